@@ -12,6 +12,11 @@
 
 namespace anonynews_rs
 {
+    // defined by the bridge in image_processing.rs
+    struct Detection;
+    struct BlurResult;
+    struct BlurOptions;
+    struct Zone;
 
     void printHelloFromCxx();
 
@@ -24,7 +29,28 @@ namespace anonynews_rs
         const std::string& pathname
     );
 
-    std::unique_ptr<std::vector<uint8_t>> blurFFMpegFrame(rust::Slice<const uint8_t> pngBuffer);
+    bool enrollIdentity(
+        rust::Str name,
+        rust::Slice<const uint8_t> image
+    );
+
+    void setMaxIdentityDistance(float maxDistance);
+
+    BlurResult blurFFMpegFrame(
+        rust::Slice<const uint8_t> pngBuffer,
+        const BlurOptions& options
+    );
+
+    void drawDebugOverlay(
+        rust::Slice<uint8_t> pixels,
+        int32_t width,
+        int32_t height,
+        int32_t stride,
+        rust::Slice<const Detection> detections,
+        rust::Slice<const uint32_t> trackIds,
+        rust::Slice<const Zone> zones,
+        float processingMs
+    );
 }
 
 #endif
\ No newline at end of file
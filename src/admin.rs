@@ -0,0 +1,258 @@
+//! A small HTTP server for whoever runs the proxy, separate from the RTMP port.
+//!
+//! - `GET /metrics`: Prometheus metrics, see [`crate::metrics`]
+//! - `GET /hls/<app>/<stream key>/index.m3u8`: the blurred stream as HLS, if
+//!   it is turned on, see [`crate::hls`]
+//! - `GET /streams/<app>/<stream key>/controls`: a live stream's operator
+//!   controls, see [`crate::controls`]
+//! - `POST /streams/<app>/<stream key>/controls`: change them, with a JSON
+//!   body like `{"curtain": "slate"}`. Responds with all of them after the
+//!   change.
+//!
+//! Changing the controls takes one of the operator tokens from
+//! [`AdminConfig`] as `Authorization: Bearer <token>`. The change is recorded
+//! as made by the operator the token belongs to, from the address it came
+//! from. Everything else is open, so still don't expose this to anyone who
+//! shouldn't be watching.
+
+use std::{io, net::SocketAddr, path::PathBuf, sync::Arc};
+
+use axum::{
+    extract::{self, ConnectInfo},
+    http::{header, HeaderMap, StatusCode},
+    response::IntoResponse,
+    routing::get,
+    Extension, Json, Router,
+};
+use sha2::{Digest, Sha256};
+use tokio::task::JoinHandle;
+use tracing::{error, info, warn};
+
+use crate::{
+    config::AdminConfig,
+    controls::{ControlChange, Controls},
+    hls,
+    metrics::Metrics,
+    remux,
+    stream_registry::{StreamName, StreamRegistry},
+};
+
+/// What the handlers get to look at. Cheap to clone.
+#[derive(Debug, Clone)]
+pub struct AdminState {
+    /// For `/metrics`
+    pub metrics: Metrics,
+    /// Where [`crate::hls`] writes to, if anywhere
+    pub hls_directory: Option<PathBuf>,
+    /// To find the live streams operators want to control
+    pub registry: StreamRegistry,
+    /// Who may change the controls
+    pub operators: Operators,
+}
+
+/// Whoever may change the controls, by their bearer tokens. Cheap to clone.
+#[derive(Debug, Clone, Default)]
+pub struct Operators {
+    /// Digests of the tokens, so comparing them gives nothing away about the
+    /// tokens themselves
+    tokens: Arc<Vec<([u8; 32], String)>>,
+}
+
+impl Operators {
+    /// Fails if an operator has an empty token
+    pub fn new(config: &AdminConfig) -> anyhow::Result<Self> {
+        let mut tokens = Vec::new();
+        for (name, token) in &config.operators {
+            if token.is_empty() {
+                anyhow::bail!("operator {} has an empty token", name);
+            }
+            tokens.push((digest(token), name.clone()));
+        }
+        Ok(Self {
+            tokens: Arc::new(tokens),
+        })
+    }
+
+    /// Whether nobody can change the controls
+    pub fn is_empty(&self) -> bool {
+        self.tokens.is_empty()
+    }
+
+    /// The name of the operator `headers` authenticate, if any
+    fn authenticate(&self, headers: &HeaderMap) -> Option<&str> {
+        let token = headers
+            .get(header::AUTHORIZATION)?
+            .to_str()
+            .ok()?
+            .strip_prefix("Bearer ")?;
+        let token = digest(token);
+        self.tokens
+            .iter()
+            .find(|(digest, _)| *digest == token)
+            .map(|(_, name)| name.as_str())
+    }
+}
+
+fn digest(token: &str) -> [u8; 32] {
+    Sha256::digest(token.as_bytes()).into()
+}
+
+/// Bind to `address` and serve in the background. Only fails if we can't bind.
+pub fn start(address: SocketAddr, state: AdminState) -> anyhow::Result<JoinHandle<()>> {
+    let app = Router::new()
+        .route("/metrics", get(metrics))
+        .route("/hls/:app/:stream_key/:file", get(hls_file))
+        .route(
+            "/streams/:app/:stream_key/controls",
+            get(controls).post(change_controls),
+        )
+        .layer(Extension(state));
+    let server = axum::Server::try_bind(&address)?
+        .serve(app.into_make_service_with_connect_info::<SocketAddr>());
+    info!("admin server listening on {}", address);
+
+    Ok(tokio::spawn(async move {
+        if let Err(e) = server.await {
+            error!("admin server stopped: {}", e);
+        }
+    }))
+}
+
+async fn metrics(Extension(state): Extension<AdminState>) -> impl IntoResponse {
+    (
+        [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+        state.metrics.render(),
+    )
+}
+
+async fn hls_file(
+    Extension(state): Extension<AdminState>,
+    extract::Path((app, stream_key, file)): extract::Path<(String, String, String)>,
+) -> Result<impl IntoResponse, StatusCode> {
+    let root = state.hls_directory.ok_or(StatusCode::NOT_FOUND)?;
+    // the file name goes straight into a path, so it has to be one of ours
+    if remux::sanitize(&file) != file || file.starts_with('.') {
+        return Err(StatusCode::NOT_FOUND);
+    }
+    let path = remux::stream_directory(&root, &StreamName::new(app, stream_key)).join(&file);
+    let content_type = hls::content_type(&path).ok_or(StatusCode::NOT_FOUND)?;
+
+    let body = match tokio::fs::read(&path).await {
+        Ok(body) => body,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Err(StatusCode::NOT_FOUND),
+        Err(e) => {
+            warn!("could not read {}: {}", path.display(), e);
+            return Err(StatusCode::INTERNAL_SERVER_ERROR);
+        }
+    };
+    // the playlist changes every segment, the segments never do
+    let cache_control = if file == hls::PLAYLIST {
+        "no-cache"
+    } else {
+        "max-age=3600"
+    };
+    Ok((
+        [
+            (header::CONTENT_TYPE, content_type),
+            (header::CACHE_CONTROL, cache_control),
+            // so a player page hosted somewhere else can load it
+            (header::ACCESS_CONTROL_ALLOW_ORIGIN, "*"),
+        ],
+        body,
+    ))
+}
+
+async fn controls(
+    Extension(state): Extension<AdminState>,
+    extract::Path((app, stream_key)): extract::Path<(String, String)>,
+) -> Result<Json<Controls>, StatusCode> {
+    let stream = state
+        .registry
+        .get(&StreamName::new(app, stream_key))
+        .ok_or(StatusCode::NOT_FOUND)?;
+    Ok(Json(stream.controls().get()))
+}
+
+async fn change_controls(
+    Extension(state): Extension<AdminState>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    extract::Path((app, stream_key)): extract::Path<(String, String)>,
+    Json(change): Json<ControlChange>,
+) -> Result<Json<Controls>, StatusCode> {
+    let name = StreamName::new(app, stream_key);
+    let operator = match state.operators.authenticate(&headers) {
+        Some(operator) => operator,
+        None => {
+            warn!(
+                "refused to change the controls of {} for {}: not an operator",
+                name, peer
+            );
+            return Err(StatusCode::UNAUTHORIZED);
+        }
+    };
+    let stream = state.registry.get(&name).ok_or(StatusCode::NOT_FOUND)?;
+    Ok(Json(stream.controls().change(
+        stream.name(),
+        operator,
+        peer,
+        change,
+    )))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn state(hls_directory: Option<PathBuf>) -> AdminState {
+        AdminState {
+            metrics: Metrics::new(),
+            hls_directory,
+            registry: StreamRegistry::default(),
+            operators: Operators::default(),
+        }
+    }
+
+    async fn get_hls_file(state: &AdminState, file: &str) -> StatusCode {
+        let path = extract::Path(("live".to_owned(), "cam".to_owned(), file.to_owned()));
+        match hls_file(Extension(state.clone()), path).await {
+            Ok(response) => response.into_response().status(),
+            Err(status) => status,
+        }
+    }
+
+    #[tokio::test]
+    async fn serves_only_our_files() {
+        let root = tempfile::tempdir().unwrap();
+        let directory = remux::stream_directory(root.path(), &StreamName::new("live", "cam"));
+        std::fs::create_dir_all(directory.join("a")).unwrap();
+        for name in ["index.m3u8", ".hidden.m3u8", "a/b.m3u8", "notes.txt"] {
+            std::fs::write(directory.join(name), b"").unwrap();
+        }
+        std::fs::write(root.path().join("live").join("secret.m3u8"), b"").unwrap();
+        let state = state(Some(root.path().to_owned()));
+
+        let cases = [
+            ("index.m3u8", StatusCode::OK),
+            ("segment_00001.ts", StatusCode::NOT_FOUND),
+            ("notes.txt", StatusCode::NOT_FOUND),
+            ("..", StatusCode::NOT_FOUND),
+            ("../secret.m3u8", StatusCode::NOT_FOUND),
+            (".hidden.m3u8", StatusCode::NOT_FOUND),
+            ("a/b.m3u8", StatusCode::NOT_FOUND),
+            ("a\\b.m3u8", StatusCode::NOT_FOUND),
+            ("", StatusCode::NOT_FOUND),
+        ];
+        for (file, expected) in cases {
+            assert_eq!(get_hls_file(&state, file).await, expected, "{}", file);
+        }
+    }
+
+    #[tokio::test]
+    async fn no_hls_without_a_directory() {
+        assert_eq!(
+            get_hls_file(&state(None), "index.m3u8").await,
+            StatusCode::NOT_FOUND
+        );
+    }
+}
@@ -0,0 +1,480 @@
+//! A record of what was anonymized, for whoever has to prove it later.
+//!
+//! Every blurred frame gets one line of JSON, whether or not any faces were
+//! found in it:
+//!
+//! ```text
+//! {"time":"2022-06-01T12:00:00.040Z","stream":"live/abc","pts_ms":40,
+//!  "detector":"res10_300x300_ssd_iter_140000_fp16","embedder":"openface_nn4.small2.v1",
+//!  "regions":[{"x":10,"y":20,"width":64,"height":64,"confidence":0.93,"action":"blurred"},
+//!   {"x":200,"y":24,"width":60,"height":60,"confidence":0.88,"action":"whitelisted","identity":"alice"}]}
+//! ```
+//!
+//! `detector` names the models that found the faces, and `embedder` the one
+//! that matched them against the whitelist (see
+//! [`crate::image_processing::enroll_identities`]). Faces from a
+//! [`FaceDetector::Scripted`] are logged with a `"scripted"` detector and no
+//! embedder.
+//!
+//! Every change an operator makes to the stream's controls (see
+//! [`crate::controls`]) gets a line too, on the first frame it applies to:
+//!
+//! ```text
+//! {"time":"2022-06-01T12:00:01.000Z","stream":"live/abc","pts_ms":1000,
+//!  "control":{"operator":"alice","peer":"10.0.0.5:53422","curtain":"slate"},
+//!  "controls":{"curtain":"slate","blur_everyone":false,"pause_detection":false,"style":null}}
+//! ```
+//!
+//! Each stream gets its own directory, like [`crate::recording`]:
+//!
+//! ```text
+//! <directory>/<app>/<stream key>/<app>_<stream key>_<start time>.jsonl
+//! ```
+
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, BufWriter, Write},
+    path::PathBuf,
+    sync::Arc,
+};
+
+use chrono::{SecondsFormat, Utc};
+use serde::Serialize;
+use tracing::{info, warn};
+
+use crate::{
+    config::AuditConfig,
+    controls::{ControlChange, Controls},
+    image_processing::{Detection, FaceDetector},
+    remux,
+    stream_registry::StreamName,
+};
+
+const EXTENSION: &str = "jsonl";
+
+#[derive(Serialize)]
+struct Entry<'a> {
+    /// When the frame was blurred
+    time: String,
+    stream: &'a str,
+    /// The frame's PTS, see [`crate::pipeline::TIME_BASE`]
+    pts_ms: Option<i64>,
+    detector: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    embedder: Option<&'static str>,
+    regions: Vec<Region<'a>>,
+    /// Set in debug mode (see [`crate::policy`]), where nothing is actually
+    /// anonymized
+    #[serde(skip_serializing_if = "is_false")]
+    debug: bool,
+}
+
+#[derive(Serialize)]
+struct ControlEntry<'a> {
+    time: String,
+    stream: &'a str,
+    /// The first frame the change applies to
+    pts_ms: Option<i64>,
+    control: &'a ControlChange,
+    /// All of them, after the change
+    controls: &'a Controls,
+}
+
+/// One face, as it is written to the audit log and embedded in the output
+/// (see [`crate::region_metadata`])
+#[derive(Serialize)]
+pub struct Region<'a> {
+    x: i32,
+    y: i32,
+    width: i32,
+    height: i32,
+    confidence: f32,
+    action: Action,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    identity: Option<&'a str>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "lowercase")]
+enum Action {
+    Blurred,
+    /// Matched an enrolled identity, so it was left alone
+    Whitelisted,
+}
+
+impl<'a> From<&'a Detection> for Region<'a> {
+    fn from(detection: &'a Detection) -> Self {
+        Self {
+            x: detection.x,
+            y: detection.y,
+            width: detection.width,
+            height: detection.height,
+            confidence: detection.confidence,
+            action: if detection.blurred {
+                Action::Blurred
+            } else {
+                Action::Whitelisted
+            },
+            identity: Some(detection.identity.as_str()).filter(|id| !id.is_empty()),
+        }
+    }
+}
+
+/// The audit log of one publishing session. Files are only created once
+/// there is something to write.
+#[derive(Debug)]
+pub struct AuditLog {
+    name: StreamName,
+    /// So it doesn't have to be formatted for every line
+    stream: String,
+    directory: PathBuf,
+    config: Arc<AuditConfig>,
+    file: Option<AuditFile>,
+    /// So a broken disk doesn't get a warning for every frame
+    healthy: bool,
+}
+
+#[derive(Debug)]
+struct AuditFile {
+    writer: BufWriter<File>,
+    bytes_written: u64,
+}
+
+impl AuditLog {
+    /// Returns `None` if auditing is turned off
+    pub fn new(name: &StreamName, config: &Arc<AuditConfig>) -> Option<Self> {
+        let directory = remux::stream_directory(config.directory.as_ref()?, name);
+        Some(Self {
+            name: name.clone(),
+            stream: name.to_string(),
+            directory,
+            config: config.clone(),
+            file: None,
+            healthy: true,
+        })
+    }
+
+    /// Log what `detector` found in the frame at `pts`. `debug` says that it
+    /// was outlined rather than blurred.
+    pub fn record(
+        &mut self,
+        pts: Option<i64>,
+        detector: &FaceDetector,
+        detections: &[Detection],
+        debug: bool,
+    ) {
+        let entry = Entry {
+            time: now(),
+            stream: &self.stream,
+            pts_ms: pts,
+            detector: detector.detector_name(),
+            embedder: detector.embedder_name(),
+            regions: detections.iter().map(Region::from).collect(),
+            debug,
+        };
+        self.append(json_line(&entry));
+    }
+
+    /// Log that `change` was made, starting with the frame at `pts`
+    pub fn record_control(
+        &mut self,
+        pts: Option<i64>,
+        change: &ControlChange,
+        controls: &Controls,
+    ) {
+        let entry = ControlEntry {
+            time: now(),
+            stream: &self.stream,
+            pts_ms: pts,
+            control: change,
+            controls,
+        };
+        self.append(json_line(&entry));
+    }
+
+    fn append(&mut self, line: Vec<u8>) {
+        match self.write(&line) {
+            Ok(()) => self.healthy = true,
+            Err(e) => {
+                if self.healthy {
+                    warn!("could not write to the audit log for {}: {}", self.name, e);
+                }
+                self.healthy = false;
+                // try a fresh file next time
+                self.file = None;
+            }
+        }
+    }
+
+    fn write(&mut self, line: &[u8]) -> io::Result<()> {
+        let full = match &self.file {
+            Some(file) => {
+                self.config.max_file_bytes > 0 && file.bytes_written >= self.config.max_file_bytes
+            }
+            None => true,
+        };
+        if full {
+            self.rotate()?;
+        }
+
+        let file = self.file.as_mut().unwrap();
+        file.writer.write_all(line)?;
+        // flushed every line, so a crash loses at most the frame it happened on
+        file.writer.flush()?;
+        file.bytes_written += line.len() as u64;
+        Ok(())
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        self.file = None;
+        fs::create_dir_all(&self.directory)?;
+        let file_name = format!(
+            "{}_{}_{}.{}",
+            remux::sanitize(&self.name.app_name),
+            remux::sanitize(&self.name.stream_key),
+            Utc::now().format("%Y%m%dT%H%M%S%.3fZ"),
+            EXTENSION,
+        );
+        let path = self.directory.join(file_name);
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        info!("writing the audit log to {}", path.display());
+        self.file = Some(AuditFile {
+            writer: BufWriter::new(file),
+            bytes_written: 0,
+        });
+
+        if let Err(e) = remux::remove_old_files(
+            &self.directory,
+            EXTENSION,
+            self.config.max_files,
+            self.config.max_age_hours,
+        ) {
+            warn!(
+                "could not clean up old audit logs in {}: {}",
+                self.directory.display(),
+                e
+            );
+        }
+        Ok(())
+    }
+}
+
+fn now() -> String {
+    Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true)
+}
+
+fn json_line(entry: &impl Serialize) -> Vec<u8> {
+    let mut line = serde_json::to_vec(entry).expect("audit entries always serialize");
+    line.push(b'\n');
+    line
+}
+
+fn is_false(value: &bool) -> bool {
+    !value
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, thread, time::Duration};
+
+    use serde_json::{json, Value};
+
+    use super::*;
+    use crate::{
+        controls::Curtain,
+        image_processing::{DETECTOR_MODEL, EMBEDDER_MODEL},
+    };
+
+    fn config(root: &tempfile::TempDir, max_file_bytes: u64, max_files: usize) -> Arc<AuditConfig> {
+        Arc::new(AuditConfig {
+            directory: Some(root.path().to_owned()),
+            max_file_bytes,
+            max_files,
+            max_age_hours: 0,
+        })
+    }
+
+    fn log(config: &Arc<AuditConfig>) -> AuditLog {
+        AuditLog::new(&StreamName::new("live", "cam"), config).unwrap()
+    }
+
+    fn detection(blurred: bool, identity: &str) -> Detection {
+        Detection {
+            x: 10,
+            y: 20,
+            width: 64,
+            height: 48,
+            confidence: 0.5,
+            blurred,
+            identity: identity.to_owned(),
+            distance: -1.0,
+        }
+    }
+
+    fn scripted() -> FaceDetector {
+        FaceDetector::Scripted(Arc::new(|_| Vec::new()))
+    }
+
+    /// Every line of every file, oldest file first, without the times
+    fn files(log: &AuditLog) -> Vec<Vec<Value>> {
+        let mut paths: Vec<_> = fs::read_dir(&log.directory)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .collect();
+        paths.sort();
+        paths
+            .iter()
+            .map(|path| {
+                fs::read_to_string(path)
+                    .unwrap()
+                    .lines()
+                    .map(|line| {
+                        let mut entry: Value = serde_json::from_str(line).unwrap();
+                        let time = entry.as_object_mut().unwrap().remove("time").unwrap();
+                        assert!(time.as_str().unwrap().ends_with('Z'), "{}", time);
+                        entry
+                    })
+                    .collect()
+            })
+            .collect()
+    }
+
+    /// The `pts_ms` of every line of every file, oldest file first
+    fn timestamps(log: &AuditLog) -> Vec<Vec<Value>> {
+        files(log)
+            .into_iter()
+            .map(|lines| {
+                lines
+                    .into_iter()
+                    .map(|entry| entry["pts_ms"].clone())
+                    .collect()
+            })
+            .collect()
+    }
+
+    /// So every file gets a name of its own
+    fn next_millisecond() {
+        thread::sleep(Duration::from_millis(2));
+    }
+
+    #[test]
+    fn entries_name_the_models_and_the_regions() {
+        let root = tempfile::tempdir().unwrap();
+        let mut log = log(&config(&root, 0, 0));
+        let detections = [detection(true, ""), detection(false, "alice")];
+        log.record(Some(40), &FaceDetector::Models, &detections, false);
+        log.record(None, &scripted(), &[], true);
+
+        let region = json!({"x": 10, "y": 20, "width": 64, "height": 48, "confidence": 0.5});
+        let mut blurred = region.clone();
+        blurred["action"] = json!("blurred");
+        let mut whitelisted = region;
+        whitelisted["action"] = json!("whitelisted");
+        whitelisted["identity"] = json!("alice");
+        assert_eq!(
+            files(&log),
+            [[
+                json!({
+                    "stream": "live/cam",
+                    "pts_ms": 40,
+                    "detector": DETECTOR_MODEL,
+                    "embedder": EMBEDDER_MODEL,
+                    "regions": [blurred, whitelisted],
+                }),
+                json!({
+                    "stream": "live/cam",
+                    "pts_ms": null,
+                    "detector": "scripted",
+                    "regions": [],
+                    "debug": true,
+                }),
+            ]]
+        );
+    }
+
+    #[test]
+    fn control_entries_say_who_made_the_change() {
+        let root = tempfile::tempdir().unwrap();
+        let mut log = log(&config(&root, 0, 0));
+        let change = ControlChange {
+            operator: "alice".to_owned(),
+            peer: Some("10.0.0.5:53422".parse().unwrap()),
+            curtain: Some(Curtain::Slate),
+            blur_everyone: None,
+            pause_detection: None,
+            style: None,
+        };
+        let controls = Controls {
+            curtain: Curtain::Slate,
+            ..Controls::default()
+        };
+        log.record_control(Some(1000), &change, &controls);
+
+        assert_eq!(
+            files(&log),
+            [[json!({
+                "stream": "live/cam",
+                "pts_ms": 1000,
+                "control": {"operator": "alice", "peer": "10.0.0.5:53422", "curtain": "slate"},
+                "controls": {
+                    "curtain": "slate",
+                    "blur_everyone": false,
+                    "pause_detection": false,
+                    "style": null,
+                },
+            })]]
+        );
+    }
+
+    #[test]
+    fn full_files_are_rotated() {
+        let root = tempfile::tempdir().unwrap();
+        let mut log = log(&config(&root, 1, 0));
+        for pts in 0..3 {
+            log.record(Some(pts), &scripted(), &[], false);
+            next_millisecond();
+        }
+
+        assert_eq!(timestamps(&log), [[json!(0)], [json!(1)], [json!(2)]]);
+    }
+
+    #[test]
+    fn only_the_newest_files_are_kept() {
+        let root = tempfile::tempdir().unwrap();
+        let mut log = log(&config(&root, 1, 2));
+        for pts in 0..4 {
+            log.record(Some(pts), &scripted(), &[], false);
+            next_millisecond();
+        }
+
+        assert_eq!(timestamps(&log), [[json!(2)], [json!(3)]]);
+    }
+
+    #[test]
+    fn write_errors_start_a_fresh_file() {
+        let root = tempfile::tempdir().unwrap();
+        let mut log = log(&config(&root, 0, 0));
+        log.record(Some(0), &scripted(), &[], false);
+        next_millisecond();
+
+        let path = fs::read_dir(&log.directory)
+            .unwrap()
+            .next()
+            .unwrap()
+            .unwrap()
+            .path();
+        // the same file, read only, as if the disk had gone away
+        let read_only = File::open(path).unwrap();
+        log.file = Some(AuditFile {
+            writer: BufWriter::new(read_only),
+            bytes_written: 0,
+        });
+        log.record(Some(1), &scripted(), &[], false);
+        assert!(!log.healthy);
+        assert!(log.file.is_none());
+
+        log.record(Some(2), &scripted(), &[], false);
+        assert!(log.healthy);
+        assert_eq!(timestamps(&log), [[json!(0)], [json!(2)]]);
+    }
+}
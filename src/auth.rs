@@ -0,0 +1,327 @@
+//! Decides who gets to publish.
+//!
+//! Besides a static allowlist of stream keys, publishers can present a signed,
+//! expiring token in the query string of either the stream key or the app:
+//!
+//! ```text
+//! rtmp://proxy:8899/live/my-show?expires=1700000000&token=<hex>
+//! rtmp://proxy:8899/live?expires=1700000000&token=<hex>/my-show
+//! ```
+//!
+//! where `expires` is a unix timestamp in seconds and `token` is the hex
+//! HMAC-SHA256, under the configured secret, of the app name, the stream key
+//! and `expires` in decimal, each preceded by its length in bytes as a
+//! big-endian `u32`. The lengths keep e.g. `a/b` + `c` and `a` + `b/c` from
+//! signing the same bytes. Either way, the query string is not part of the
+//! stream name.
+
+use std::{
+    collections::HashSet,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use thiserror::Error;
+
+use crate::{config::AuthConfig, stream_registry::StreamName};
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Why a publisher was turned away
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum AuthError {
+    /// Neither on the allowlist nor carrying a token
+    #[error("stream key is not on the allowlist and no token was given")]
+    NotAllowed,
+
+    /// `expires` or `token` is missing from the query string, or isn't a
+    /// number or hex respectively
+    #[error("publish token is malformed")]
+    MalformedToken,
+
+    /// The token was signed for a time that has passed
+    #[error("publish token has expired")]
+    Expired,
+
+    /// The token was signed for a different stream or expiry, or with a
+    /// different secret
+    #[error("publish token signature does not match")]
+    BadSignature,
+}
+
+/// Decides who may publish, following an [`AuthConfig`]
+#[derive(Debug)]
+pub struct PublishAuthorizer {
+    allowed_stream_keys: HashSet<String>,
+    token_secret: Option<Vec<u8>>,
+}
+
+impl PublishAuthorizer {
+    /// Let in whoever `config` allows
+    pub fn new(config: &AuthConfig) -> Self {
+        Self {
+            allowed_stream_keys: config.allowed_stream_keys.iter().cloned().collect(),
+            token_secret: config
+                .token_secret
+                .as_ref()
+                .map(|secret| secret.as_bytes().to_vec()),
+        }
+    }
+
+    /// With nothing configured, anyone may publish
+    pub fn is_open(&self) -> bool {
+        self.allowed_stream_keys.is_empty() && self.token_secret.is_none()
+    }
+
+    /// Check a publish request, returning the name the stream should be
+    /// published under (i.e. without any token in it)
+    pub fn authorize(&self, app_name: &str, stream_key: &str) -> Result<StreamName, AuthError> {
+        let (app_name, app_query) = split_query(app_name);
+        let (stream_key, key_query) = split_query(stream_key);
+        let name = StreamName::new(app_name, stream_key);
+
+        if self.is_open() || self.allowed_stream_keys.contains(stream_key) {
+            return Ok(name);
+        }
+
+        let secret = match &self.token_secret {
+            Some(secret) => secret,
+            None => return Err(AuthError::NotAllowed),
+        };
+        let query = match key_query.or(app_query) {
+            Some(query) => query,
+            None => return Err(AuthError::NotAllowed),
+        };
+
+        let mut expires = None;
+        let mut token = None;
+        for pair in query.split('&') {
+            match pair.split_once('=') {
+                Some(("expires", value)) => expires = Some(value),
+                Some(("token", value)) => token = Some(value),
+                _ => (),
+            }
+        }
+        let expires: u64 = expires
+            .and_then(|expires| expires.parse().ok())
+            .ok_or(AuthError::MalformedToken)?;
+        let token = token
+            .and_then(|token| hex::decode(token).ok())
+            .ok_or(AuthError::MalformedToken)?;
+
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        if expires < now {
+            return Err(AuthError::Expired);
+        }
+
+        token_mac(secret, &name, expires)
+            .verify_slice(&token)
+            .map_err(|_| AuthError::BadSignature)?;
+        Ok(name)
+    }
+}
+
+/// The stream name a player asked for, without any query string
+pub fn stream_name_without_query(app_name: &str, stream_key: &str) -> StreamName {
+    StreamName::new(split_query(app_name).0, split_query(stream_key).0)
+}
+
+/// Sign a token for `name` that is good until `expires` (unix seconds)
+pub fn sign_token(secret: &[u8], name: &StreamName, expires: u64) -> String {
+    hex::encode(token_mac(secret, name, expires).finalize().into_bytes())
+}
+
+fn token_mac(secret: &[u8], name: &StreamName, expires: u64) -> HmacSha256 {
+    let mut mac = HmacSha256::new_from_slice(secret).expect("HMAC accepts keys of any length");
+    for field in [
+        name.app_name.as_bytes(),
+        name.stream_key.as_bytes(),
+        expires.to_string().as_bytes(),
+    ] {
+        mac.update(&(field.len() as u32).to_be_bytes());
+        mac.update(field);
+    }
+    mac
+}
+
+fn split_query(s: &str) -> (&str, Option<&str>) {
+    match s.split_once('?') {
+        Some((before, query)) => (before, Some(query)),
+        None => (s, None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECRET: &str = "hunter2";
+
+    fn authorizer(allowed_stream_keys: &[&str], token_secret: Option<&str>) -> PublishAuthorizer {
+        PublishAuthorizer::new(&AuthConfig {
+            allowed_stream_keys: allowed_stream_keys.iter().map(|&key| key.into()).collect(),
+            token_secret: token_secret.map(Into::into),
+        })
+    }
+
+    fn now() -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+    }
+
+    /// A stream key carrying a token for live/`stream_key`
+    fn signed_key(stream_key: &str, expires: u64) -> String {
+        let token = sign_token(
+            SECRET.as_bytes(),
+            &StreamName::new("live", stream_key),
+            expires,
+        );
+        format!("{}?expires={}&token={}", stream_key, expires, token)
+    }
+
+    #[test]
+    fn anyone_may_publish_without_configuration() {
+        let auth = authorizer(&[], None);
+        assert!(auth.is_open());
+        assert_eq!(
+            auth.authorize("live", "cam?whatever"),
+            Ok(StreamName::new("live", "cam"))
+        );
+    }
+
+    #[test]
+    fn allowlist() {
+        let auth = authorizer(&["cam"], None);
+        assert!(!auth.is_open());
+        assert_eq!(
+            auth.authorize("live", "cam"),
+            Ok(StreamName::new("live", "cam"))
+        );
+        assert_eq!(auth.authorize("live", "other"), Err(AuthError::NotAllowed));
+        // without a secret, tokens cannot stand in for the allowlist
+        assert_eq!(
+            auth.authorize("live", &signed_key("other", now() + 60)),
+            Err(AuthError::NotAllowed)
+        );
+    }
+
+    #[test]
+    fn valid_token_in_the_stream_key() {
+        let auth = authorizer(&[], Some(SECRET));
+        assert_eq!(
+            auth.authorize("live", &signed_key("cam", now() + 60)),
+            Ok(StreamName::new("live", "cam"))
+        );
+    }
+
+    #[test]
+    fn valid_token_in_the_app() {
+        let auth = authorizer(&[], Some(SECRET));
+        let expires = now() + 60;
+        let token = sign_token(SECRET.as_bytes(), &StreamName::new("live", "cam"), expires);
+        let app_name = format!("live?expires={}&token={}", expires, token);
+        assert_eq!(
+            auth.authorize(&app_name, "cam"),
+            Ok(StreamName::new("live", "cam"))
+        );
+    }
+
+    #[test]
+    fn expired_token() {
+        let auth = authorizer(&[], Some(SECRET));
+        assert_eq!(
+            auth.authorize("live", &signed_key("cam", now() - 1)),
+            Err(AuthError::Expired)
+        );
+    }
+
+    #[test]
+    fn tampered_tokens() {
+        let auth = authorizer(&[], Some(SECRET));
+        let expires = now() + 60;
+        let token = sign_token(SECRET.as_bytes(), &StreamName::new("live", "cam"), expires);
+
+        for stream_key in [
+            // signed for another stream
+            format!("other?expires={}&token={}", expires, token),
+            // expiry pushed back
+            format!("cam?expires={}&token={}", expires + 1, token),
+            // signed with another secret
+            format!(
+                "cam?expires={}&token={}",
+                expires,
+                sign_token(b"hunter3", &StreamName::new("live", "cam"), expires)
+            ),
+        ] {
+            assert_eq!(
+                auth.authorize("live", &stream_key),
+                Err(AuthError::BadSignature),
+                "{}",
+                stream_key
+            );
+        }
+        assert_eq!(
+            auth.authorize("other", &format!("cam?expires={}&token={}", expires, token)),
+            Err(AuthError::BadSignature)
+        );
+    }
+
+    #[test]
+    fn fields_cannot_shift_between_app_and_stream_key() {
+        let expires = now() + 60;
+        assert_ne!(
+            sign_token(SECRET.as_bytes(), &StreamName::new("a/b", "c"), expires),
+            sign_token(SECRET.as_bytes(), &StreamName::new("a", "b/c"), expires)
+        );
+    }
+
+    #[test]
+    fn query_parsing() {
+        let auth = authorizer(&[], Some(SECRET));
+        let expires = now() + 60;
+        let token = sign_token(SECRET.as_bytes(), &StreamName::new("live", "cam"), expires);
+
+        // order and unknown parameters do not matter
+        assert_eq!(
+            auth.authorize(
+                "live",
+                &format!("cam?token={}&foo&bar=1&expires={}", token, expires)
+            ),
+            Ok(StreamName::new("live", "cam"))
+        );
+        assert_eq!(auth.authorize("live", "cam"), Err(AuthError::NotAllowed));
+        for stream_key in [
+            "cam?".to_owned(),
+            "cam?foo=bar".to_owned(),
+            format!("cam?token={}", token),
+            format!("cam?expires={}", expires),
+            format!("cam?expires=soon&token={}", token),
+            format!("cam?expires={}&token=not-hex", expires),
+        ] {
+            assert_eq!(
+                auth.authorize("live", &stream_key),
+                Err(AuthError::MalformedToken),
+                "{}",
+                stream_key
+            );
+        }
+    }
+
+    #[test]
+    fn names_without_query() {
+        assert_eq!(
+            stream_name_without_query("live?expires=1&token=ab", "cam?expires=1&token=ab"),
+            StreamName::new("live", "cam")
+        );
+        assert_eq!(
+            stream_name_without_query("live", "cam"),
+            StreamName::new("live", "cam")
+        );
+    }
+}
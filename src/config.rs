@@ -0,0 +1,431 @@
+//! Server configuration, loaded from a TOML file.
+//!
+//! Every field has a default, so an empty file (or no file at all) gives you
+//! an open server on port 8899.
+
+use std::{
+    collections::BTreeMap,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+
+use crate::connection_manager::ConnectionConfig;
+
+/// Everything the server can be told, i.e. the whole config file
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Where publishers and players connect over RTMP
+    pub listen_address: SocketAddr,
+    /// Where to serve metrics and HLS over HTTP, see [`crate::admin`]. Off if
+    /// left out.
+    pub admin_address: Option<SocketAddr>,
+    /// Who may change the controls of live streams through the admin server
+    pub admin: AdminConfig,
+    /// Drop connections that haven't finished the RTMP handshake by then
+    pub handshake_timeout_secs: u64,
+    /// Drop connections that haven't sent anything for this long
+    pub idle_timeout_secs: u64,
+    /// Who may publish, see [`crate::auth`]
+    pub auth: AuthConfig,
+    /// Where to push blurred streams, see [`crate::routing`]
+    pub routes: Vec<RouteConfig>,
+    /// How to anonymize each stream, see [`crate::policy`]
+    pub policies: Vec<PolicyConfig>,
+    /// Whose faces are left alone
+    pub whitelist: WhitelistConfig,
+    /// How routed streams are pushed
+    pub egress: EgressConfig,
+    /// Whether and how streams are recorded
+    pub recording: RecordingConfig,
+    /// Whether and how streams are packaged as HLS
+    pub hls: HlsConfig,
+    /// How blurred video is encoded
+    pub video: VideoConfig,
+    /// Whether and where the audit log is written
+    pub audit: AuditConfig,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        let connection = ConnectionConfig::default();
+        Self {
+            listen_address: SocketAddr::from(([0, 0, 0, 0], 8899)),
+            admin_address: None,
+            admin: AdminConfig::default(),
+            handshake_timeout_secs: connection.handshake_timeout.as_secs(),
+            idle_timeout_secs: connection.idle_timeout.as_secs(),
+            auth: AuthConfig::default(),
+            routes: Vec::new(),
+            policies: Vec::new(),
+            whitelist: WhitelistConfig::default(),
+            egress: EgressConfig::default(),
+            recording: RecordingConfig::default(),
+            hls: HlsConfig::default(),
+            video: VideoConfig::default(),
+            audit: AuditConfig::default(),
+        }
+    }
+}
+
+/// Who is allowed to publish. A publisher gets in if its stream key is on the
+/// allowlist *or* it carries a valid token. With neither configured, anyone can
+/// publish.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuthConfig {
+    /// Stream keys that may always publish
+    pub allowed_stream_keys: Vec<String>,
+    /// Shared secret for HMAC-SHA256 publish tokens, see [`crate::auth`]
+    pub token_secret: Option<String>,
+}
+
+/// Who may change a live stream's controls through the admin server, see
+/// [`crate::admin`]
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AdminConfig {
+    /// Operator names and the bearer tokens they authenticate with. Nobody can
+    /// change the controls if there are none.
+    pub operators: BTreeMap<String, String>,
+}
+
+/// Pushes the streams that match it to its destinations
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RouteConfig {
+    /// Pattern for the app name. Matches every app if left out.
+    #[serde(default = "match_anything")]
+    pub app: String,
+    /// Pattern for the stream key. Matches every stream key if left out.
+    #[serde(default = "match_anything")]
+    pub stream_key: String,
+    /// Where matching streams go, see [`crate::routing`] for what can go in
+    /// them
+    pub destinations: Vec<DestinationConfig>,
+}
+
+/// One server a route pushes to
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DestinationConfig {
+    /// `rtmp://host[:port]/app`
+    pub url: String,
+    /// Defaults to the stream key the publisher used
+    #[serde(default = "same_stream_key")]
+    pub stream_key: String,
+}
+
+/// How the streams that match it are anonymized. The first that matches a
+/// stream wins.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PolicyConfig {
+    /// Pattern for the app name, like a route's. Matches every app if left out.
+    #[serde(default = "match_anything")]
+    pub app: String,
+    /// Pattern for the stream key. Matches every stream key if left out.
+    #[serde(default = "match_anything")]
+    pub stream_key: String,
+    /// What faces and zones look like once anonymized
+    #[serde(default = "blur")]
+    pub style: BlurStyle,
+    /// Always anonymized, whether or not there is a face in them
+    #[serde(default)]
+    pub zones: Vec<ZoneConfig>,
+    /// Draw what the detector sees instead of anonymizing anything
+    #[serde(default)]
+    pub debug: bool,
+}
+
+/// What anonymized regions look like
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BlurStyle {
+    /// Gaussian blur
+    Blur,
+    /// Big blocks of the average color
+    Pixelate,
+    /// Solid black
+    Fill,
+}
+
+/// Part of the frame, in fractions of its width and height
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+pub enum ZoneConfig {
+    /// An upright rectangle
+    Rectangle {
+        /// Left edge
+        x: f32,
+        /// Top edge
+        y: f32,
+        /// Of the frame's width
+        width: f32,
+        /// Of the frame's height
+        height: f32,
+    },
+    /// Any other shape
+    Polygon {
+        /// At least three `[x, y]` corners
+        points: Vec<[f32; 2]>,
+    },
+}
+
+/// How we push to destinations
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct EgressConfig {
+    /// How much of the stream to replay after reconnecting to a destination.
+    /// Anything older, or everything if this is 0, is skipped and the
+    /// destination picks up again from the next keyframe.
+    pub catch_up_ms: u64,
+    /// How long to wait before reconnecting the first time. Doubles with
+    /// every attempt that fails.
+    pub min_reconnect_delay_ms: u64,
+    /// The longest we ever wait to reconnect
+    pub max_reconnect_delay_ms: u64,
+}
+
+impl Default for EgressConfig {
+    fn default() -> Self {
+        Self {
+            catch_up_ms: 0,
+            min_reconnect_delay_ms: 500,
+            max_reconnect_delay_ms: 30_000,
+        }
+    }
+}
+
+impl EgressConfig {
+    /// See [`Self::catch_up_ms`]
+    pub fn catch_up(&self) -> Duration {
+        Duration::from_millis(self.catch_up_ms)
+    }
+
+    /// See [`Self::min_reconnect_delay_ms`]
+    pub fn min_reconnect_delay(&self) -> Duration {
+        Duration::from_millis(self.min_reconnect_delay_ms)
+    }
+
+    /// See [`Self::max_reconnect_delay_ms`]
+    pub fn max_reconnect_delay(&self) -> Duration {
+        Duration::from_millis(self.max_reconnect_delay_ms)
+    }
+}
+
+/// Archiving what went out, see [`crate::recording`]
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RecordingConfig {
+    /// Nothing is recorded if this is left out
+    pub directory: Option<PathBuf>,
+    /// What the files are written as
+    pub format: RecordingFormat,
+    /// Start a new file at the first keyframe after this long. 0 for no limit.
+    pub max_segment_secs: u64,
+    /// Start a new file at the first keyframe after this many bytes. 0 for no limit.
+    pub max_segment_bytes: u64,
+    /// How many files to keep per stream. 0 keeps them all.
+    pub max_files: usize,
+    /// Delete a stream's files once they are this old. 0 keeps them forever.
+    /// Only checked when the stream starts a new file.
+    pub max_age_hours: u64,
+}
+
+impl Default for RecordingConfig {
+    fn default() -> Self {
+        Self {
+            directory: None,
+            format: RecordingFormat::Flv,
+            max_segment_secs: 60 * 60,
+            max_segment_bytes: 0,
+            max_files: 0,
+            max_age_hours: 0,
+        }
+    }
+}
+
+/// The container recordings are written in
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RecordingFormat {
+    /// Written as it comes in, so every codec we send goes in
+    Flv,
+    /// Fragmented, so a file that was cut off is still playable
+    Mp4,
+}
+
+impl RecordingFormat {
+    /// What the files' names end in
+    pub fn extension(self) -> &'static str {
+        match self {
+            Self::Flv => "flv",
+            Self::Mp4 => "mp4",
+        }
+    }
+}
+
+/// Packaging the output for browsers, see [`crate::hls`]
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HlsConfig {
+    /// No HLS is written if this is left out
+    pub directory: Option<PathBuf>,
+    /// What the segments are written as
+    pub segment_type: HlsSegmentType,
+    /// Segments are cut at the first keyframe after this long
+    pub segment_secs: u64,
+    /// How many segments the playlist lists. Older ones are deleted.
+    pub playlist_len: u32,
+}
+
+impl Default for HlsConfig {
+    fn default() -> Self {
+        Self {
+            directory: None,
+            segment_type: HlsSegmentType::MpegTs,
+            segment_secs: 2,
+            playlist_len: 6,
+        }
+    }
+}
+
+/// The container HLS segments are written in
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HlsSegmentType {
+    /// MPEG-TS, which plays the most places
+    MpegTs,
+    /// Fragmented MP4, with an `init.mp4` holding what every segment needs
+    Fmp4,
+}
+
+impl HlsSegmentType {
+    /// What the segments' names end in
+    pub fn extension(self) -> &'static str {
+        match self {
+            Self::MpegTs => "ts",
+            Self::Fmp4 => "m4s",
+        }
+    }
+}
+
+/// People whose faces are left alone, see
+/// [`crate::image_processing::enroll_identities`]
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct WhitelistConfig {
+    /// One photo (JPEG or PNG) per person, named after them, e.g.
+    /// `alice.jpg`. Everyone is blurred if this is left out.
+    pub directory: Option<PathBuf>,
+    /// How close a face's embedding has to be to a photo's to count as that
+    /// person. A wrong match leaves a stranger unblurred, so this errs on the
+    /// strict side.
+    pub max_distance: f32,
+}
+
+impl Default for WhitelistConfig {
+    fn default() -> Self {
+        Self {
+            directory: None,
+            max_distance: 0.6,
+        }
+    }
+}
+
+/// The record of what was blurred, see [`crate::audit`]
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuditConfig {
+    /// Nothing is logged if this is left out
+    pub directory: Option<PathBuf>,
+    /// Start a new file after this many bytes. 0 for no limit.
+    pub max_file_bytes: u64,
+    /// How many files to keep per stream. 0 keeps them all.
+    pub max_files: usize,
+    /// Delete a stream's files once they are this old. 0 keeps them forever.
+    /// Only checked when the stream starts a new file.
+    pub max_age_hours: u64,
+}
+
+impl Default for AuditConfig {
+    fn default() -> Self {
+        Self {
+            directory: None,
+            max_file_bytes: 64 * 1024 * 1024,
+            max_files: 0,
+            max_age_hours: 0,
+        }
+    }
+}
+
+/// What the blurred video gets encoded as
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct VideoConfig {
+    /// Which codec
+    pub codec: OutputCodec,
+    /// Put where faces were found in each frame into the output, see
+    /// [`crate::region_metadata`]
+    pub embed_regions: bool,
+}
+
+impl Default for VideoConfig {
+    fn default() -> Self {
+        Self {
+            codec: OutputCodec::H264,
+            embed_regions: false,
+        }
+    }
+}
+
+/// The codec blurred video is encoded with
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputCodec {
+    /// Plays everywhere, and is the only thing MP4 recordings and HLS can
+    /// package
+    H264,
+    /// Whatever the publisher sent, so HEVC and AV1 go out as HEVC and AV1
+    /// over Enhanced RTMP. Anything we can't encode falls back to H.264. MP4
+    /// recordings and HLS leave out video that isn't H.264, with a warning.
+    Same,
+}
+
+fn match_anything() -> String {
+    "*".to_owned()
+}
+
+fn blur() -> BlurStyle {
+    BlurStyle::Blur
+}
+
+fn same_stream_key() -> String {
+    "{stream_key}".to_owned()
+}
+
+impl Config {
+    /// Reads the config at `path`, or falls back to the defaults if there isn't one
+    pub fn load(path: Option<&Path>) -> anyhow::Result<Self> {
+        let path = match path {
+            Some(path) => path,
+            None => return Ok(Self::default()),
+        };
+        let contents = std::fs::read_to_string(path)
+            .with_context(|| format!("could not read config file {}", path.display()))?;
+        toml::from_str(&contents)
+            .with_context(|| format!("could not parse config file {}", path.display()))
+    }
+
+    pub(crate) fn connection_config(&self) -> ConnectionConfig {
+        ConnectionConfig {
+            handshake_timeout: Duration::from_secs(self.handshake_timeout_secs),
+            idle_timeout: Duration::from_secs(self.idle_timeout_secs),
+        }
+    }
+}
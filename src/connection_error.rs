@@ -0,0 +1,48 @@
+//! Everything that can end an RTMP connection early.
+//!
+//! None of these should take the server down. The connection task logs the
+//! error, tears down that client's pipeline, and moves on.
+
+use std::time::Duration;
+
+use rml_rtmp::{handshake::HandshakeError, sessions::ServerSessionError};
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum ConnectionError {
+    #[error("socket error: {0}")]
+    Io(#[from] std::io::Error),
+
+    #[error("client sent a malformed RTMP handshake: {0}")]
+    Handshake(#[from] HandshakeError),
+
+    #[error("client did not finish the handshake within {0:?}")]
+    HandshakeTimedOut(Duration),
+
+    #[error("client closed the connection before finishing the handshake")]
+    ClosedDuringHandshake,
+
+    #[error("client was idle for {0:?}")]
+    IdleTimeout(Duration),
+
+    #[error("RTMP session error: {0}")]
+    Session(#[from] ServerSessionError),
+
+    #[error("could not pass stream bytes to the decoder: {0}")]
+    Decoder(#[source] std::io::Error),
+}
+
+impl ConnectionError {
+    /// Whether this was caused by the client misbehaving (bad RTMP, stalling),
+    /// rather than by the network or by us
+    pub fn is_client_fault(&self) -> bool {
+        matches!(
+            self,
+            Self::Handshake(_)
+                | Self::HandshakeTimedOut(_)
+                | Self::ClosedDuringHandshake
+                | Self::Session(_)
+                | Self::IdleTimeout(_)
+        )
+    }
+}
@@ -1,15 +1,103 @@
 use rml_rtmp::{
     handshake::{Handshake, HandshakeProcessResult, PeerType},
-    sessions::{
-        ServerSession, ServerSessionConfig, ServerSessionError, ServerSessionEvent,
-        ServerSessionResult,
-    },
+    sessions::{ServerSession, ServerSessionConfig, ServerSessionEvent, ServerSessionResult},
+    time::RtmpTimestamp,
 };
-use std::{collections::VecDeque, thread};
-use tokio::{io::AsyncWriteExt, net::TcpStream};
-use tracing::{debug, info, span, Level};
+use std::{collections::VecDeque, rc::Rc, sync::Arc, time::Duration};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadHalf, WriteHalf},
+    sync::{broadcast, mpsc},
+    task::JoinHandle,
+    time::timeout,
+};
+use tracing::{debug, info, span, warn, Level};
+
+use crate::{
+    audit::AuditLog,
+    auth::{self, PublishAuthorizer},
+    config::{AuditConfig, EgressConfig, HlsConfig, RecordingConfig, VideoConfig},
+    connection_error::ConnectionError,
+    egress,
+    hls::HlsPackager,
+    image_processing::{BlurStage, FaceDetector},
+    metrics::{EgressMetrics, Metrics},
+    pipeline::Pipeline,
+    policy::Policies,
+    recording::Recorder,
+    region_metadata,
+    routing::Router,
+    shutdown::Shutdown,
+    stream_registry::{MediaKind, MediaPacket, PublishGuard, StreamName, StreamRegistry},
+};
+
+/// How many bytes we try to pull off the socket per read
+const READ_BUFFER_SIZE: usize = 4096;
+
+/// How many outbound chunks can queue up before we stop reading from the client
+const OUTBOUND_QUEUE_LEN: usize = 64;
+
+/// How long we wait for the last outbound bytes to reach a disconnecting client
+const WRITER_FLUSH_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone, Copy)]
+pub struct ConnectionConfig {
+    /// The whole handshake has to finish within this long. Clients that
+    /// trickle handshake bytes in (or never send any) get dropped.
+    pub handshake_timeout: Duration,
+    /// Drop the client if it sends nothing (or reads nothing we send) for this long
+    pub idle_timeout: Duration,
+}
+
+impl Default for ConnectionConfig {
+    fn default() -> Self {
+        Self {
+            handshake_timeout: Duration::from_secs(10),
+            idle_timeout: Duration::from_secs(30),
+        }
+    }
+}
+
+/// Everything connections share with each other. Cheap to clone.
+#[derive(Debug, Clone)]
+pub struct ServerContext {
+    pub connection_config: ConnectionConfig,
+    pub registry: StreamRegistry,
+    pub authorizer: Arc<PublishAuthorizer>,
+    pub router: Arc<Router>,
+    pub policies: Arc<Policies>,
+    pub detector: FaceDetector,
+    pub egress_config: EgressConfig,
+    pub recording: Arc<RecordingConfig>,
+    pub hls: Arc<HlsConfig>,
+    pub video: VideoConfig,
+    pub audit: Arc<AuditConfig>,
+    pub metrics: Metrics,
+}
+
+/// A client that is pushing video through the pipeline
+#[derive(Debug)]
+struct Publishing {
+    /// Declared first so the pipeline (and with it the encoder) finishes
+    /// before the stream is taken out of the registry
+    pipeline: Pipeline,
+    guard: PublishGuard,
+    /// Keeps every destination listed in the metrics while we are live
+    _egress: Vec<Arc<EgressMetrics>>,
+    /// Declared after `guard`, since the recording and HLS only finish once
+    /// the stream has ended
+    _recorder: Option<Recorder>,
+    _hls: Option<HlsPackager>,
+}
 
-use crate::image_processing;
+/// A client that is watching one of the anonymized streams
+#[derive(Debug)]
+struct Playback {
+    stream_id: u32,
+    packets: broadcast::Receiver<MediaPacket>,
+    /// Set when the player joins or falls behind, since P-frames are useless
+    /// without the keyframe before them
+    waiting_for_keyframe: bool,
+}
 
 enum SessionResultAction {
     SendBytes(Vec<u8>),
@@ -18,111 +106,101 @@ enum SessionResultAction {
     CloseConnection,
 }
 
-pub struct ConnectionManager {
-    socket: TcpStream,
+/// Serves one RTMP client over any byte stream: a `TcpStream`, a TLS stream,
+/// or a `tokio::io::duplex` pipe in tests
+pub struct ConnectionManager<S> {
+    config: ConnectionConfig,
+    context: ServerContext,
+    reader: ReadHalf<S>,
+    /// Feeds the socket writer task. `None` once we have started closing.
+    outbound: Option<mpsc::Sender<Vec<u8>>>,
+    writer_task: Option<JoinHandle<std::io::Result<()>>>,
     session: ServerSession,
+    /// What the session chunks outgoing messages at, for the ones we have
+    /// to serialize ourselves
+    chunk_size: u32,
     server_session_results: VecDeque<ServerSessionResult>,
-    frame_decoder: crate::decoding_frames::FrameExtractor,
+    /// Handed to the pipeline once the client starts publishing
+    abort: Shutdown,
+    /// Dropping this blocks until the pipeline threads have drained and the
+    /// recording and HLS are finished
+    publishing: Option<Publishing>,
+    playing: Option<Playback>,
 }
 
-impl std::fmt::Debug for ConnectionManager {
+impl<S> std::fmt::Debug for ConnectionManager<S> {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("ConnectionManager")
-            .field("socket", &self.socket)
+            .field("config", &self.config)
             .field("server_session_results", &self.server_session_results)
-            .field("frame_decoder", &self.frame_decoder)
+            .field("publishing", &self.publishing)
+            .field("playing", &self.playing)
             .finish()
     }
 }
 
-impl ConnectionManager {
-    pub async fn connect(mut socket: TcpStream) -> anyhow::Result<Self> {
-        let remaining_bytes;
-        {
+impl<S> ConnectionManager<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    /// `abort` is handed to the pipeline threads, see
+    /// [`crate::pipeline::PipelineBuilder::abort_on`]
+    pub async fn connect(
+        socket: S,
+        context: ServerContext,
+        abort: Shutdown,
+    ) -> Result<Self, ConnectionError> {
+        let config = context.connection_config;
+        let (mut reader, mut writer) = tokio::io::split(socket);
+
+        let remaining_bytes = {
             // We are a server trying to receive frames
             let span = span!(Level::TRACE, "rtmp_handshake");
             let _span_raii = span.enter();
 
-            let mut handshake_manager = Handshake::new(PeerType::Server);
-
-            // Drive the handshake process
-            remaining_bytes = loop {
-                let response_bytes: Vec<u8>;
-                let remaining_bytes: Option<Vec<u8>>;
-
-                // they use yucky data representation
-                socket.readable().await?;
-                let vec = sock_read(&mut socket)?;
-
-                // Their data repr is bad
-                match handshake_manager.process_bytes(&vec) {
-                    Ok(HandshakeProcessResult::InProgress { response_bytes: r }) => {
-                        info!("read {} bytes, handshake in progress!", vec.len());
-                        response_bytes = r;
-                        remaining_bytes = None;
-                    }
-                    Ok(HandshakeProcessResult::Completed {
-                        response_bytes: r,
-                        remaining_bytes: leftover,
-                    }) => {
-                        info!("read {} bytes, handshake completed!", vec.len());
-                        response_bytes = r;
-                        remaining_bytes = Some(leftover);
-                    }
-                    Err(_) => {
-                        todo!("error handling in handshake conducting")
-                    }
+            match timeout(
+                config.handshake_timeout,
+                perform_handshake(&mut reader, &mut writer),
+            )
+            .await
+            {
+                Ok(res) => res?,
+                Err(_) => {
+                    warn!(
+                        "client did not finish the handshake within {:?}",
+                        config.handshake_timeout
+                    );
+                    return Err(ConnectionError::HandshakeTimedOut(config.handshake_timeout));
                 }
-
-                socket.write_all(&response_bytes).await?;
-
-                if let Some(remaining_bytes) = remaining_bytes {
-                    break remaining_bytes;
-                }
-            };
-        }
+            }
+        };
 
         {
             let _span = span!(Level::TRACE, "streaming_from_client").entered();
 
-            let (mut session, packets_to_send) = ServerSession::new(ServerSessionConfig::new())?;
+            let session_config = ServerSessionConfig::new();
+            let chunk_size = session_config.chunk_size;
+            let (mut session, packets_to_send) = ServerSession::new(session_config)?;
             let packets_to_send2 = session.handle_input(&remaining_bytes)?;
 
-            let (frame_decoder, frame_splitter_output) =
-                crate::decoding_frames::FrameExtractor::new();
-            let frame_blurrer_output = image_processing::start_blur_thread(frame_splitter_output);
-
-            thread::Builder::new()
-                .name("write blurred frames to filesystem".to_owned())
-                .spawn(move || {
-                    let _span = span!(Level::TRACE, "writing_frames_to_fs").entered();
-                    let mut frame_counter = 0;
-
-                    match std::fs::create_dir("./temp") {
-                        Err(e) if e.kind() != std::io::ErrorKind::AlreadyExists => Err(e).unwrap(),
-                        _ => (),
-                    };
-
-                    loop {
-                        let next_frame = frame_blurrer_output.recv().expect("frame blurring thread died");
-                        let ppm = image_processing::frame_to_ppm_format(next_frame);
-
-                        std::fs::write(format!("./temp/blurred_frame_{}.ppm", frame_counter), &ppm)
-                            .unwrap();
-                        frame_counter += 1;
-                    }
-                })
-                .expect("failed to spawn thread");
+            let (outbound, writer_task) = spawn_socket_writer(writer);
 
             Ok(Self {
-                socket,
+                config,
+                context,
+                reader,
+                outbound: Some(outbound),
+                writer_task: Some(writer_task),
                 session,
+                chunk_size,
                 server_session_results: {
                     let mut deque = VecDeque::from(packets_to_send);
                     deque.extend(packets_to_send2);
                     deque
                 },
-                frame_decoder,
+                abort,
+                publishing: None,
+                playing: None,
             })
         }
     }
@@ -130,13 +208,16 @@ impl ConnectionManager {
     fn process_server_session_event(
         &mut self,
         e: ServerSessionEvent,
-    ) -> Result<SessionResultAction, ServerSessionError> {
+    ) -> Result<SessionResultAction, ConnectionError> {
         Ok(match e {
             ServerSessionEvent::ConnectionRequested {
                 request_id,
                 app_name,
             } => {
-                debug!("\tsomeone wants to connect to app {}", app_name,);
+                debug!(
+                    "\tsomeone wants to connect to app {}",
+                    auth::stream_name_without_query(&app_name, "").app_name
+                );
                 SessionResultAction::HandleMoreSessionResults(
                     self.session.accept_request(request_id)?,
                 )
@@ -147,51 +228,127 @@ impl ConnectionManager {
                 stream_key,
                 mode,
             } => {
-                debug!(
-                    "\tsomeone wants to publish ({:?}) on {}/{}",
-                    mode, app_name, stream_key
-                );
-                SessionResultAction::HandleMoreSessionResults(
-                    self.session.accept_request(request_id)?,
-                )
+                // the raw names can carry a publish token, which stays out of the logs
+                let requested = auth::stream_name_without_query(&app_name, &stream_key);
+                debug!("\tsomeone wants to publish ({:?}) on {}", mode, requested);
+                let name = match self.context.authorizer.authorize(&app_name, &stream_key) {
+                    Ok(name) => name,
+                    Err(e) => {
+                        warn!("rejecting publish on {}: {}", requested, e);
+                        return Ok(SessionResultAction::HandleMoreSessionResults(
+                            self.session.reject_request(
+                                request_id,
+                                "NetStream.Publish.BadName",
+                                &format!("not allowed to publish: {}", e),
+                            )?,
+                        ));
+                    }
+                };
+                if self.publishing.is_some() {
+                    warn!("client tried to publish {} while already publishing", name);
+                    SessionResultAction::HandleMoreSessionResults(self.session.reject_request(
+                        request_id,
+                        "NetStream.Publish.BadConnection",
+                        "this connection is already publishing a stream",
+                    )?)
+                } else if let Some(guard) = self.context.registry.start_publishing(name.clone()) {
+                    let policy = self.context.policies.for_stream(&name);
+                    if policy.debug {
+                        warn!(
+                            "{} is in debug mode, faces will be outlined instead of blurred",
+                            name
+                        );
+                    }
+                    let pipeline = Pipeline::builder(guard.stream().clone())
+                        .video(self.context.video)
+                        .stage(BlurStage::new(
+                            &policy,
+                            self.context.detector.clone(),
+                            guard.stream().controls().clone(),
+                            AuditLog::new(&name, &self.context.audit),
+                        ))
+                        .abort_on(self.abort.clone())
+                        .start();
+                    let egress = self
+                        .context
+                        .router
+                        .destinations(&name)
+                        .into_iter()
+                        .map(|destination| {
+                            info!("pushing {} to {}", name, destination);
+                            egress::spawn(
+                                guard.stream(),
+                                destination,
+                                self.context.egress_config,
+                                &self.context.metrics,
+                                self.abort.clone(),
+                            )
+                        })
+                        .collect();
+                    let recorder = Recorder::start(guard.stream(), &self.context.recording);
+                    let hls = HlsPackager::start(guard.stream(), &self.context.hls);
+                    self.publishing = Some(Publishing {
+                        pipeline,
+                        guard,
+                        _egress: egress,
+                        _recorder: recorder,
+                        _hls: hls,
+                    });
+                    SessionResultAction::HandleMoreSessionResults(
+                        self.session.accept_request(request_id)?,
+                    )
+                } else {
+                    warn!("rejecting publish on {}, it is already live", name);
+                    SessionResultAction::HandleMoreSessionResults(self.session.reject_request(
+                        request_id,
+                        "NetStream.Publish.BadName",
+                        "someone is already publishing on this stream key",
+                    )?)
+                }
             }
             ServerSessionEvent::AudioDataReceived {
-                app_name,
-                stream_key,
-                data,
-                timestamp,
-            } => SessionResultAction::NoAction,
-            ServerSessionEvent::VideoDataReceived {
-                app_name,
-                stream_key,
-                data,
-                timestamp,
+                data, timestamp, ..
             } => {
-                self.frame_decoder.send_bytes(timestamp.value, &data);
+                // audio has no faces in it, so it goes straight to the output
+                if let Some(publishing) = &self.publishing {
+                    publishing.guard.stream().send(MediaPacket {
+                        kind: MediaKind::Audio,
+                        timestamp: timestamp.value,
+                        data,
+                    });
+                }
                 SessionResultAction::NoAction
             }
-            ServerSessionEvent::ClientChunkSizeChanged { new_chunk_size } => todo!(),
-            ServerSessionEvent::ReleaseStreamRequested {
-                request_id,
-                app_name,
-                stream_key,
+            ServerSessionEvent::VideoDataReceived {
+                data, timestamp, ..
             } => {
+                if let Some(publishing) = &mut self.publishing {
+                    publishing
+                        .pipeline
+                        .send_video_bytes(timestamp.value, &data)
+                        .map_err(ConnectionError::Decoder)?;
+                }
+                SessionResultAction::NoAction
+            }
+            ServerSessionEvent::ClientChunkSizeChanged { new_chunk_size } => {
+                // the session already uses the new size when deserializing,
+                // so this is purely informational
+                debug!("\tclient changed its chunk size to {}", new_chunk_size);
+                SessionResultAction::NoAction
+            }
+            ServerSessionEvent::ReleaseStreamRequested { .. } => {
                 debug!("\t'Release stream requested'?");
                 SessionResultAction::NoAction
             }
-            ServerSessionEvent::PublishStreamFinished {
-                app_name,
-                stream_key,
-            } => {
+            ServerSessionEvent::PublishStreamFinished { .. } => {
                 debug!("\tthey finished publishing a stream");
                 SessionResultAction::CloseConnection
             }
-            ServerSessionEvent::StreamMetadataChanged {
-                app_name,
-                stream_key,
-                metadata,
-            } => {
+            ServerSessionEvent::StreamMetadataChanged { metadata, .. } => {
                 debug!("\tthey changed the stream metadata: {:?}", metadata);
+                if let Some(publishing) = &self.publishing {
+                    publishing.guard.stream().set_metadata(metadata);
+                }
                 SessionResultAction::NoAction
             }
             c @ ServerSessionEvent::UnhandleableAmf0Command { .. } => {
@@ -202,43 +359,147 @@ impl ConnectionManager {
                 request_id,
                 app_name,
                 stream_key,
-                start_at,
-                duration,
-                reset,
                 stream_id,
+                ..
             } => {
                 debug!("\tthey are requesting to play a stream");
-                SessionResultAction::NoAction
+                SessionResultAction::HandleMoreSessionResults(self.start_playback(
+                    request_id,
+                    auth::stream_name_without_query(&app_name, &stream_key),
+                    stream_id,
+                )?)
             }
-            ServerSessionEvent::PlayStreamFinished {
-                app_name,
-                stream_key,
-            } => {
+            ServerSessionEvent::PlayStreamFinished { .. } => {
                 debug!("\tthey finished playing a stream");
                 SessionResultAction::CloseConnection
             }
-            ServerSessionEvent::AcknowledgementReceived { bytes_received } => {
+            ServerSessionEvent::AcknowledgementReceived { .. } => {
                 debug!("\tthey acknowledged they received some bytes");
                 SessionResultAction::NoAction
             }
-            ServerSessionEvent::PingResponseReceived { timestamp } => {
+            ServerSessionEvent::PingResponseReceived { .. } => {
                 debug!("\treceived a ping response");
                 SessionResultAction::NoAction
             }
         })
     }
 
+    /// Accept a play request and queue up the cached headers, so the player
+    /// can start decoding from the next keyframe
+    fn start_playback(
+        &mut self,
+        request_id: u32,
+        name: StreamName,
+        stream_id: u32,
+    ) -> Result<Vec<ServerSessionResult>, ConnectionError> {
+        let stream = match self.context.registry.get(&name) {
+            Some(stream) if self.playing.is_none() => stream,
+            Some(_) => {
+                warn!("client tried to play {} while already playing", name);
+                return Ok(self.session.reject_request(
+                    request_id,
+                    "NetStream.Play.Failed",
+                    "this connection is already playing a stream",
+                )?);
+            }
+            None => {
+                info!("client asked to play {}, which is not live", name);
+                return Ok(self.session.reject_request(
+                    request_id,
+                    "NetStream.Play.StreamNotFound",
+                    "nobody is publishing on this stream key",
+                )?);
+            }
+        };
+
+        info!("client started playing {}", name);
+        let mut results = self.session.accept_request(request_id)?;
+        let (headers, packets) = stream.subscribe();
+
+        if let Some(metadata) = headers.metadata {
+            let packet = self.session.send_metadata(stream_id, Rc::new(metadata))?;
+            results.push(ServerSessionResult::OutboundResponse(packet));
+        }
+        for header in [headers.video_sequence_header, headers.audio_sequence_header]
+            .into_iter()
+            .flatten()
+        {
+            let packet = self.packet_for_player(stream_id, header)?;
+            results.push(ServerSessionResult::OutboundResponse(packet));
+        }
+
+        self.playing = Some(Playback {
+            stream_id,
+            packets,
+            waiting_for_keyframe: true,
+        });
+        Ok(results)
+    }
+
+    fn packet_for_player(
+        &mut self,
+        stream_id: u32,
+        packet: MediaPacket,
+    ) -> Result<rml_rtmp::chunk_io::Packet, ConnectionError> {
+        let timestamp = RtmpTimestamp::new(packet.timestamp);
+        // anything but keyframes and headers can be skipped if the player is behind
+        let can_be_dropped = !packet.is_keyframe() && !packet.is_sequence_header();
+        Ok(match packet.kind {
+            MediaKind::Video => {
+                self.session
+                    .send_video_data(stream_id, packet.data, timestamp, can_be_dropped)?
+            }
+            MediaKind::Audio => {
+                self.session
+                    .send_audio_data(stream_id, packet.data, timestamp, can_be_dropped)?
+            }
+            MediaKind::Data => rml_rtmp::chunk_io::Packet {
+                bytes: region_metadata::rtmp_data_message(
+                    stream_id,
+                    packet.timestamp,
+                    &packet.data,
+                    self.chunk_size,
+                ),
+                can_be_dropped: true,
+            },
+        })
+    }
+
+    /// Forward one packet of the stream being played. Returns the bytes to
+    /// send, which are empty if we're skipping ahead to a keyframe.
+    fn forward_to_player(&mut self, packet: MediaPacket) -> Result<Vec<u8>, ConnectionError> {
+        let playback = match &mut self.playing {
+            Some(playback) => playback,
+            None => return Ok(Vec::new()),
+        };
+        if playback.waiting_for_keyframe {
+            match packet.kind {
+                MediaKind::Video if packet.is_keyframe() => playback.waiting_for_keyframe = false,
+                MediaKind::Video => return Ok(Vec::new()),
+                // regions of frames the player won't see
+                MediaKind::Data => return Ok(Vec::new()),
+                MediaKind::Audio => (),
+            }
+        }
+        let stream_id = playback.stream_id;
+        Ok(self.packet_for_player(stream_id, packet)?.bytes)
+    }
+
     fn process_server_session_result(
         &mut self,
         ssr: ServerSessionResult,
-    ) -> Result<SessionResultAction, ServerSessionError> {
+    ) -> Result<SessionResultAction, ConnectionError> {
         match ssr {
             ServerSessionResult::RaisedEvent(e) => self.process_server_session_event(e),
             ServerSessionResult::OutboundResponse(packet) => {
                 Ok(SessionResultAction::SendBytes(packet.bytes))
             }
-            ServerSessionResult::UnhandleableMessageReceived(_) => {
-                debug!("yuck! we got an unhandleable message :(");
+            ServerSessionResult::UnhandleableMessageReceived(payload) => {
+                warn!(
+                    "yuck! we got an unhandleable message :( (type id {}, {} bytes)",
+                    payload.type_id,
+                    payload.data.len()
+                );
                 Ok(SessionResultAction::NoAction)
             }
         }
@@ -246,7 +507,7 @@ impl ConnectionManager {
 
     #[allow(unused)]
     #[tracing::instrument(level = "info")]
-    pub fn process_message_buffer(&mut self) -> anyhow::Result<(Vec<u8>, bool)> {
+    pub fn process_message_buffer(&mut self) -> Result<(Vec<u8>, bool), ConnectionError> {
         let mut connection_should_close = false;
         let mut bytes_to_send: Vec<u8> = Vec::new();
         let mut more_session_results: Vec<ServerSessionResult> = Vec::new();
@@ -258,7 +519,7 @@ impl ConnectionManager {
             let x = std::mem::take(&mut self.server_session_results);
             x.into_iter()
                 .map(|ssr| self.process_server_session_result(ssr))
-                .try_for_each(|el| -> Result<(), ServerSessionError> {
+                .try_for_each(|el| -> Result<(), ConnectionError> {
                     match el? {
                         SessionResultAction::SendBytes(b) => {
                             bytes_to_send.extend(b);
@@ -281,33 +542,586 @@ impl ConnectionManager {
         Ok((bytes_to_send, connection_should_close))
     }
 
-    #[tracing::instrument]
-    pub async fn handle_connection(&mut self) -> anyhow::Result<()> {
+    /// Serve the client until it goes away or `force_close` fires
+    #[tracing::instrument(skip(force_close))]
+    pub async fn handle_connection(
+        &mut self,
+        force_close: &Shutdown,
+    ) -> Result<(), ConnectionError> {
+        let res = self.serve(force_close).await;
+        self.close_writer().await;
+        res
+    }
+
+    async fn serve(&mut self, force_close: &Shutdown) -> Result<(), ConnectionError> {
+        let idle_timeout = self.config.idle_timeout;
+        let mut read_buf: Vec<u8> = Vec::with_capacity(READ_BUFFER_SIZE);
         loop {
             let (bytes_to_send, should_close_connection) = self.process_message_buffer()?;
-            self.socket.write_all(&bytes_to_send).await?;
-            self.socket.readable().await?;
-            let read_bytes = sock_read(&mut self.socket)?;
-            self.server_session_results
-                .extend(self.session.handle_input(&read_bytes)?);
+            self.send(bytes_to_send).await?;
 
             if should_close_connection {
                 info!("closing connection");
                 return Ok(());
             }
+
+            // players only send the odd acknowledgement, so they can't be held to the idle timeout
+            let read_deadline = match self.playing {
+                Some(_) => None,
+                None => Some(idle_timeout),
+            };
+
+            read_buf.clear();
+            let bytes_read = tokio::select! {
+                res = read_with_deadline(&mut self.reader, &mut read_buf, read_deadline) => {
+                    match res {
+                        Ok(res) => res?,
+                        Err(_) => {
+                            warn!("client sent nothing for {:?}", idle_timeout);
+                            return Err(ConnectionError::IdleTimeout(idle_timeout));
+                        }
+                    }
+                }
+                packet = next_playback_packet(&mut self.playing) => {
+                    match packet {
+                        Ok(packet) => {
+                            let bytes = self.forward_to_player(packet)?;
+                            self.send(bytes).await?;
+                        }
+                        Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                            warn!("player fell {} packets behind, skipping to the next keyframe", skipped);
+                            if let Some(playback) = &mut self.playing {
+                                playback.waiting_for_keyframe = true;
+                            }
+                        }
+                        Err(broadcast::error::RecvError::Closed) => {
+                            info!("the stream being played has ended");
+                            return Ok(());
+                        }
+                    }
+                    continue;
+                }
+                _ = force_close.cancelled() => {
+                    info!("server is shutting down, closing connection");
+                    return Ok(());
+                }
+            };
+
+            if bytes_read == 0 {
+                info!("client closed the connection");
+                return Ok(());
+            }
+
+            self.server_session_results
+                .extend(self.session.handle_input(&read_buf)?);
+        }
+    }
+
+    /// Queue bytes for the writer task. Only blocks if the client stops reading.
+    async fn send(&mut self, bytes: Vec<u8>) -> Result<(), ConnectionError> {
+        if bytes.is_empty() {
+            return Ok(());
+        }
+        let outbound = match &self.outbound {
+            Some(outbound) => outbound,
+            None => return Err(std::io::Error::from(std::io::ErrorKind::BrokenPipe).into()),
+        };
+
+        match timeout(self.config.idle_timeout, outbound.send(bytes)).await {
+            Ok(Ok(())) => Ok(()),
+            // the writer task only stops early if writing to the socket failed
+            Ok(Err(_)) => Err(std::io::Error::from(std::io::ErrorKind::BrokenPipe).into()),
+            Err(_) => {
+                warn!(
+                    "client has not read anything we sent for {:?}",
+                    self.config.idle_timeout
+                );
+                Err(ConnectionError::IdleTimeout(self.config.idle_timeout))
+            }
         }
     }
+
+    /// Let the writer task send whatever is still queued, then shut the socket
+    async fn close_writer(&mut self) {
+        drop(self.outbound.take());
+        if let Some(writer_task) = self.writer_task.take() {
+            match timeout(WRITER_FLUSH_TIMEOUT, writer_task).await {
+                Ok(Ok(Ok(()))) => (),
+                Ok(Ok(Err(e))) => debug!("could not flush the socket: {}", e),
+                Ok(Err(e)) => warn!("socket writer task failed: {}", e),
+                Err(_) => debug!(
+                    "gave up flushing the socket after {:?}",
+                    WRITER_FLUSH_TIMEOUT
+                ),
+            }
+        }
+    }
+}
+
+async fn read_with_deadline<S: AsyncRead>(
+    reader: &mut ReadHalf<S>,
+    buf: &mut Vec<u8>,
+    deadline: Option<Duration>,
+) -> Result<std::io::Result<usize>, tokio::time::error::Elapsed> {
+    match deadline {
+        Some(deadline) => timeout(deadline, reader.read_buf(buf)).await,
+        None => Ok(reader.read_buf(buf).await),
+    }
+}
+
+/// Never resolves if nothing is being played
+async fn next_playback_packet(
+    playing: &mut Option<Playback>,
+) -> Result<MediaPacket, broadcast::error::RecvError> {
+    match playing {
+        Some(playback) => playback.packets.recv().await,
+        None => std::future::pending().await,
+    }
 }
 
-#[tracing::instrument]
-fn sock_read(socket: &mut TcpStream) -> std::io::Result<Vec<u8>> {
-    let mut buf: Vec<u8> = Vec::new();
+/// Drive the server side of the RTMP handshake. Returns the bytes the client
+/// sent after the handshake, which belong to the session.
+async fn perform_handshake<S: AsyncRead + AsyncWrite>(
+    reader: &mut ReadHalf<S>,
+    writer: &mut WriteHalf<S>,
+) -> Result<Vec<u8>, ConnectionError> {
+    let mut handshake_manager = Handshake::new(PeerType::Server);
+    let mut vec: Vec<u8> = Vec::with_capacity(READ_BUFFER_SIZE);
+
     loop {
-        match socket.try_read_buf(&mut buf) {
-            Ok(0) => return Ok(buf),
-            Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => return Ok(buf),
-            Ok(_) => {}
-            Err(e) => return Err(e),
+        let response_bytes: Vec<u8>;
+        let remaining_bytes: Option<Vec<u8>>;
+
+        vec.clear();
+        if reader.read_buf(&mut vec).await? == 0 {
+            warn!("client hung up in the middle of the handshake");
+            return Err(ConnectionError::ClosedDuringHandshake);
+        }
+
+        // Their data repr is bad
+        match handshake_manager.process_bytes(&vec) {
+            Ok(HandshakeProcessResult::InProgress { response_bytes: r }) => {
+                info!("read {} bytes, handshake in progress!", vec.len());
+                response_bytes = r;
+                remaining_bytes = None;
+            }
+            Ok(HandshakeProcessResult::Completed {
+                response_bytes: r,
+                remaining_bytes: leftover,
+            }) => {
+                info!("read {} bytes, handshake completed!", vec.len());
+                response_bytes = r;
+                remaining_bytes = Some(leftover);
+            }
+            Err(e) => {
+                warn!("handshake failed after reading {} bytes: {}", vec.len(), e);
+                return Err(e.into());
+            }
+        }
+
+        writer.write_all(&response_bytes).await?;
+
+        if let Some(remaining_bytes) = remaining_bytes {
+            return Ok(remaining_bytes);
+        }
+    }
+}
+
+/// Writes to the socket on its own task, so a client that is slow to read
+/// doesn't stop us from reading what it sends
+fn spawn_socket_writer<S: AsyncWrite + Send + 'static>(
+    mut writer: WriteHalf<S>,
+) -> (mpsc::Sender<Vec<u8>>, JoinHandle<std::io::Result<()>>) {
+    let (tx, mut rx) = mpsc::channel::<Vec<u8>>(OUTBOUND_QUEUE_LEN);
+    let task = tokio::spawn(async move {
+        while let Some(bytes) = rx.recv().await {
+            writer.write_all(&bytes).await?;
+        }
+        writer.shutdown().await
+    });
+    (tx, task)
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+    use rml_rtmp::sessions::{
+        ClientSession, ClientSessionConfig, ClientSessionEvent, ClientSessionResult,
+        PublishRequestType,
+    };
+    use tokio::io::{duplex, DuplexStream};
+
+    use super::*;
+    use crate::config::Config;
+
+    /// How much either end of a test connection can have in flight
+    const PIPE_CAPACITY: usize = 64 * 1024;
+
+    /// How long the server gets to answer before a test fails
+    const REPLY_TIMEOUT: Duration = Duration::from_secs(10);
+
+    /// An AVC sequence header, a keyframe and a P-frame, as far as the
+    /// connection can tell from their headers
+    const SEQUENCE_HEADER: &[u8] = &[0x17, 0, 0, 0, 0, 1, 2, 3];
+    const KEYFRAME: &[u8] = &[0x17, 1, 0, 0, 0, 4, 5, 6];
+    const INTER_FRAME: &[u8] = &[0x27, 1, 0, 0, 0, 7, 8, 9];
+
+    /// What the server would share between connections with `config`. Faces
+    /// come from a detector that never finds any, so no models are needed.
+    fn context(config: &str) -> ServerContext {
+        let config: Config = toml::from_str(config).unwrap();
+        ServerContext {
+            connection_config: config.connection_config(),
+            registry: StreamRegistry::new(),
+            authorizer: Arc::new(PublishAuthorizer::new(&config.auth)),
+            router: Arc::new(Router::new(&config.routes).unwrap()),
+            policies: Arc::new(Policies::new(&config.policies).unwrap()),
+            detector: FaceDetector::Scripted(Arc::new(|_| Vec::new())),
+            egress_config: config.egress,
+            recording: Arc::new(config.recording.clone()),
+            hls: Arc::new(config.hls.clone()),
+            video: config.video,
+            audit: Arc::new(config.audit.clone()),
+            metrics: Metrics::new(),
+        }
+    }
+
+    /// Serve one end of a pipe the way the server serves a socket, and hand
+    /// back the other end
+    fn serve(context: ServerContext) -> (DuplexStream, JoinHandle<Result<(), ConnectionError>>) {
+        let (client, server) = duplex(PIPE_CAPACITY);
+        let task = tokio::spawn(async move {
+            let mut conn = ConnectionManager::connect(server, context, Shutdown::new()).await?;
+            let res = conn.handle_connection(&Shutdown::new()).await;
+            // dropping the connection joins its pipeline threads
+            tokio::task::spawn_blocking(move || drop(conn))
+                .await
+                .unwrap();
+            res
+        });
+        (client, task)
+    }
+
+    fn video(timestamp: u32, data: &'static [u8]) -> MediaPacket {
+        MediaPacket {
+            kind: MediaKind::Video,
+            timestamp,
+            data: Bytes::from_static(data),
+        }
+    }
+
+    /// The other side of a connection, as an encoder or a player would drive it
+    struct Client {
+        socket: DuplexStream,
+        session: ClientSession,
+        events: VecDeque<ClientSessionEvent>,
+    }
+
+    impl Client {
+        /// Shake hands and connect to `app_name`
+        async fn connect(mut socket: DuplexStream, app_name: &str) -> Self {
+            let mut handshake = Handshake::new(PeerType::Client);
+            socket
+                .write_all(&handshake.generate_outbound_p0_and_p1().unwrap())
+                .await
+                .unwrap();
+            let mut buf = vec![0; READ_BUFFER_SIZE];
+            let remaining_bytes = loop {
+                let read = socket.read(&mut buf).await.unwrap();
+                assert_ne!(read, 0, "server hung up during the handshake");
+                match handshake.process_bytes(&buf[..read]).unwrap() {
+                    HandshakeProcessResult::InProgress { response_bytes } => {
+                        socket.write_all(&response_bytes).await.unwrap();
+                    }
+                    HandshakeProcessResult::Completed {
+                        response_bytes,
+                        remaining_bytes,
+                    } => {
+                        socket.write_all(&response_bytes).await.unwrap();
+                        break remaining_bytes;
+                    }
+                }
+            };
+
+            let (session, results) = ClientSession::new(ClientSessionConfig::new()).unwrap();
+            let mut client = Self {
+                socket,
+                session,
+                events: VecDeque::new(),
+            };
+            client.handle(results).await;
+            let results = client.session.handle_input(&remaining_bytes).unwrap();
+            client.handle(results).await;
+
+            let request = client
+                .session
+                .request_connection(app_name.to_owned())
+                .unwrap();
+            client.handle(vec![request]).await;
+            let event = client.next_event().await;
+            assert!(
+                matches!(event, ClientSessionEvent::ConnectionRequestAccepted),
+                "{:?}",
+                event
+            );
+            client
+        }
+
+        /// Send what the session wants sent, and keep what it raised
+        async fn handle(&mut self, results: Vec<ClientSessionResult>) {
+            for result in results {
+                match result {
+                    ClientSessionResult::OutboundResponse(packet) => {
+                        self.socket.write_all(&packet.bytes).await.unwrap()
+                    }
+                    ClientSessionResult::RaisedEvent(event) => self.events.push_back(event),
+                    ClientSessionResult::UnhandleableMessageReceived(_) => (),
+                }
+            }
+        }
+
+        /// The next thing the server told us that a test could care about
+        async fn next_event(&mut self) -> ClientSessionEvent {
+            let mut buf = vec![0; READ_BUFFER_SIZE];
+            loop {
+                match self.events.pop_front() {
+                    Some(
+                        ClientSessionEvent::AcknowledgementReceived { .. }
+                        | ClientSessionEvent::PingResponseReceived { .. }
+                        | ClientSessionEvent::UnhandleableAmf0Command { .. },
+                    ) => continue,
+                    Some(event) => return event,
+                    None => (),
+                }
+                let read = timeout(REPLY_TIMEOUT, self.socket.read(&mut buf))
+                    .await
+                    .expect("the server went quiet")
+                    .unwrap();
+                assert_ne!(read, 0, "the server closed the connection");
+                let results = self.session.handle_input(&buf[..read]).unwrap();
+                self.handle(results).await;
+            }
+        }
+
+        async fn publish(&mut self, stream_key: &str) -> ClientSessionEvent {
+            let request = self
+                .session
+                .request_publishing(stream_key.to_owned(), PublishRequestType::Live)
+                .unwrap();
+            self.handle(vec![request]).await;
+            self.next_event().await
+        }
+
+        async fn stop_publishing(&mut self) {
+            let results = self.session.stop_publishing().unwrap();
+            self.handle(results).await;
+        }
+
+        async fn play(&mut self, stream_key: &str) -> ClientSessionEvent {
+            let request = self
+                .session
+                .request_playback(stream_key.to_owned())
+                .unwrap();
+            self.handle(vec![request]).await;
+            self.next_event().await
+        }
+
+        /// The next video message, skipping everything else
+        async fn next_video(&mut self) -> Bytes {
+            loop {
+                if let ClientSessionEvent::VideoDataReceived { data, .. } = self.next_event().await
+                {
+                    return data;
+                }
+            }
+        }
+
+        /// Wait for the server to hang up
+        async fn closed(mut self) {
+            let mut buf = vec![0; READ_BUFFER_SIZE];
+            while timeout(REPLY_TIMEOUT, self.socket.read(&mut buf))
+                .await
+                .expect("the server kept the connection open")
+                .unwrap()
+                != 0
+            {}
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn publishing_starts_a_pipeline_until_the_publisher_stops() {
+        let context = context("");
+        let registry = context.registry.clone();
+        let name = StreamName::new("live", "cam");
+        let (socket, server) = serve(context);
+
+        let mut publisher = Client::connect(socket, "live").await;
+        let event = publisher.publish("cam").await;
+        assert!(
+            matches!(event, ClientSessionEvent::PublishRequestAccepted),
+            "{:?}",
+            event
+        );
+        assert!(registry.get(&name).is_some());
+
+        publisher.stop_publishing().await;
+        publisher.closed().await;
+        server.await.unwrap().unwrap();
+        assert!(registry.get(&name).is_none());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn rejects_publishers_that_are_not_allowed() {
+        let context = context(
+            r#"
+            [auth]
+            allowed_stream_keys = ["allowed"]
+            "#,
+        );
+        let registry = context.registry.clone();
+        let (socket, _server) = serve(context);
+
+        let mut publisher = Client::connect(socket, "live").await;
+        match publisher.publish("not-allowed").await {
+            ClientSessionEvent::UnhandleableOnStatusCode { code } => {
+                assert_eq!(code, "NetStream.Publish.BadName")
+            }
+            event => panic!("publishing was not rejected: {:?}", event),
+        }
+        assert!(registry
+            .get(&StreamName::new("live", "not-allowed"))
+            .is_none());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn rejects_a_second_publisher_on_a_live_stream() {
+        let context = context("");
+        let (socket, _first_server) = serve(context.clone());
+        let mut first = Client::connect(socket, "live").await;
+        first.publish("cam").await;
+
+        let (socket, _second_server) = serve(context);
+        let mut second = Client::connect(socket, "live").await;
+        match second.publish("cam").await {
+            ClientSessionEvent::UnhandleableOnStatusCode { code } => {
+                assert_eq!(code, "NetStream.Publish.BadName")
+            }
+            event => panic!("publishing was not rejected: {:?}", event),
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn plays_the_live_output_from_the_next_keyframe() {
+        let context = context("");
+        let (socket, publisher_server) = serve(context.clone());
+        let mut publisher = Client::connect(socket, "live").await;
+        publisher.publish("cam").await;
+
+        // sent before the player joins, so it can only come from the cache
+        let stream = context
+            .registry
+            .get(&StreamName::new("live", "cam"))
+            .unwrap();
+        stream.send(video(0, SEQUENCE_HEADER));
+
+        let (socket, player_server) = serve(context);
+        let mut player = Client::connect(socket, "live").await;
+        let event = player.play("cam").await;
+        assert!(
+            matches!(event, ClientSessionEvent::PlaybackRequestAccepted),
+            "{:?}",
+            event
+        );
+        assert_eq!(player.next_video().await, SEQUENCE_HEADER);
+
+        // useless without the keyframe before it
+        stream.send(video(40, INTER_FRAME));
+        stream.send(video(80, KEYFRAME));
+        stream.send(video(120, INTER_FRAME));
+        assert_eq!(player.next_video().await, KEYFRAME);
+        assert_eq!(player.next_video().await, INTER_FRAME);
+
+        // the player is let go once the stream ends
+        drop(stream);
+        publisher.stop_publishing().await;
+        publisher.closed().await;
+        publisher_server.await.unwrap().unwrap();
+        player.closed().await;
+        player_server.await.unwrap().unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn rejects_playing_a_stream_that_is_not_live() {
+        let (socket, _server) = serve(context(""));
+        let mut player = Client::connect(socket, "live").await;
+        match player.play("nobody").await {
+            ClientSessionEvent::UnhandleableOnStatusCode { code } => {
+                assert_eq!(code, "NetStream.Play.StreamNotFound")
+            }
+            event => panic!("playing was not rejected: {:?}", event),
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn drops_clients_that_never_finish_the_handshake() {
+        let (socket, server) = serve(context("handshake_timeout_secs = 1"));
+        match server.await.unwrap() {
+            Err(ConnectionError::HandshakeTimedOut(_)) => (),
+            res => panic!("the handshake did not time out: {:?}", res),
+        }
+        drop(socket);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn drops_publishers_that_go_quiet() {
+        let (socket, server) = serve(context("idle_timeout_secs = 1"));
+        let mut publisher = Client::connect(socket, "live").await;
+        publisher.publish("cam").await;
+
+        // still connected, just not sending anything
+        match timeout(REPLY_TIMEOUT, server).await.unwrap().unwrap() {
+            Err(ConnectionError::IdleTimeout(_)) => (),
+            res => panic!("the publisher was not dropped: {:?}", res),
+        }
+        drop(publisher);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn drops_players_that_stop_reading() {
+        let context = context("idle_timeout_secs = 1");
+        let (socket, _publisher_server) = serve(context.clone());
+        let mut publisher = Client::connect(socket, "live").await;
+        publisher.publish("cam").await;
+        let stream = context
+            .registry
+            .get(&StreamName::new("live", "cam"))
+            .unwrap();
+
+        let (socket, player_server) = serve(context);
+        let mut player = Client::connect(socket, "live").await;
+        player.play("cam").await;
+
+        // far more than the pipe and the outbound queue can hold, all of it
+        // keyframes so none of it is skipped
+        let mut frame = vec![0; 16 * 1024];
+        frame[..2].copy_from_slice(&KEYFRAME[..2]);
+        let frame = Bytes::from(frame);
+        for i in 0..200 {
+            stream.send(MediaPacket {
+                kind: MediaKind::Video,
+                timestamp: i * 40,
+                data: frame.clone(),
+            });
+        }
+
+        match timeout(REPLY_TIMEOUT, player_server)
+            .await
+            .unwrap()
+            .unwrap()
+        {
+            Err(ConnectionError::IdleTimeout(_)) => (),
+            res => panic!("the player was not dropped: {:?}", res),
         }
+        drop(player);
     }
 }
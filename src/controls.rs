@@ -0,0 +1,248 @@
+//! Switches an operator can flip on a live stream from the admin server (see
+//! [`crate::admin`]), for when something goes wrong on air.
+//!
+//! The blur thread checks them before every frame, so a change shows up in
+//! the next frame it blurs. Each change is also written to the audit log (see
+//! [`crate::audit`]) along with the operator who made it and where from.
+//!
+//! Controls belong to one publishing session, and start over with the next.
+
+use std::{
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+};
+
+use serde::{Deserialize, Deserializer, Serialize};
+use tracing::info;
+
+use crate::{config::BlurStyle, stream_registry::StreamName};
+
+/// Hides the whole picture, faces or not
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Curtain {
+    /// The picture goes out, anonymized as usual
+    Off,
+    /// A plain grey frame
+    Slate,
+    /// The whole frame blurred
+    Blur,
+}
+
+/// What the operators of a stream have switched on
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct Controls {
+    /// Over the whole picture
+    pub curtain: Curtain,
+    /// Blur faces even if they match an enrolled identity, see
+    /// [`crate::image_processing::enroll_identities`]
+    pub blur_everyone: bool,
+    /// Stop looking for faces. Zones are still anonymized.
+    pub pause_detection: bool,
+    /// Overrides the stream's policy, see [`crate::policy`]
+    pub style: Option<BlurStyle>,
+}
+
+impl Default for Controls {
+    fn default() -> Self {
+        Self {
+            curtain: Curtain::Off,
+            blur_everyone: false,
+            pause_detection: false,
+            style: None,
+        }
+    }
+}
+
+/// What an operator asked for. Anything left out stays as it was.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ControlChange {
+    /// Whoever made the change, as the admin server authenticated them. Not
+    /// up to the caller, see [`OperatorControls::change`].
+    #[serde(skip_deserializing)]
+    pub operator: String,
+    /// Where they made it from
+    #[serde(skip_deserializing, skip_serializing_if = "Option::is_none")]
+    pub peer: Option<SocketAddr>,
+    /// See [`Controls::curtain`]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub curtain: Option<Curtain>,
+    /// See [`Controls::blur_everyone`]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub blur_everyone: Option<bool>,
+    /// See [`Controls::pause_detection`]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pause_detection: Option<bool>,
+    /// `null` goes back to the policy's style
+    #[serde(
+        default,
+        deserialize_with = "explicit",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub style: Option<Option<BlurStyle>>,
+}
+
+/// Tells a `null` apart from a field that was left out
+fn explicit<'de, D, T>(deserializer: D) -> Result<Option<Option<T>>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Deserialize<'de>,
+{
+    Option::deserialize(deserializer).map(Some)
+}
+
+/// The controls of one stream, shared between the admin server and the blur
+/// thread. Cheap to clone.
+#[derive(Debug, Clone, Default)]
+pub struct OperatorControls {
+    inner: Arc<Mutex<Inner>>,
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    controls: Controls,
+    /// Changes the blur thread hasn't seen yet
+    unseen: Vec<ControlChange>,
+}
+
+impl OperatorControls {
+    /// Everything switched off
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// What is switched on right now
+    pub fn get(&self) -> Controls {
+        self.inner.lock().unwrap().controls
+    }
+
+    /// Returns the controls with `change` applied. It is recorded as made by
+    /// `operator` from `peer`, whatever it says.
+    pub fn change(
+        &self,
+        name: &StreamName,
+        operator: &str,
+        peer: SocketAddr,
+        mut change: ControlChange,
+    ) -> Controls {
+        change.operator = operator.to_string();
+        change.peer = Some(peer);
+        let mut inner = self.inner.lock().unwrap();
+        let controls = &mut inner.controls;
+        if let Some(curtain) = change.curtain {
+            controls.curtain = curtain;
+        }
+        if let Some(blur_everyone) = change.blur_everyone {
+            controls.blur_everyone = blur_everyone;
+        }
+        if let Some(pause_detection) = change.pause_detection {
+            controls.pause_detection = pause_detection;
+        }
+        if let Some(style) = change.style {
+            controls.style = style;
+        }
+        let controls = *controls;
+        info!(
+            "{} ({}) changed the controls of {}: {:?}",
+            operator, peer, name, controls
+        );
+        inner.unseen.push(change);
+        controls
+    }
+
+    /// The current controls, and every change made since the last call
+    pub fn take_changes(&self) -> (Controls, Vec<ControlChange>) {
+        let mut inner = self.inner.lock().unwrap();
+        (inner.controls, std::mem::take(&mut inner.unseen))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn peer() -> SocketAddr {
+        SocketAddr::from(([10, 0, 0, 5], 53422))
+    }
+
+    #[test]
+    fn changes_leave_out_what_they_dont_mention() {
+        let cases = [
+            (r#"{}"#, None, None, "nothing"),
+            (
+                r#"{"curtain": "slate"}"#,
+                Some(Curtain::Slate),
+                None,
+                "curtain",
+            ),
+            (
+                r#"{"style": "fill"}"#,
+                None,
+                Some(Some(BlurStyle::Fill)),
+                "a style",
+            ),
+            (r#"{"style": null}"#, None, Some(None), "the policy's style"),
+        ];
+
+        for (body, curtain, style, message) in cases {
+            let change: ControlChange = serde_json::from_str(body).unwrap();
+            assert_eq!(change.curtain, curtain, "{}", message);
+            assert_eq!(change.blur_everyone, None, "{}", message);
+            assert_eq!(change.style, style, "{}", message);
+        }
+    }
+
+    #[test]
+    fn callers_cant_say_who_they_are() {
+        for body in [
+            r#"{"operator": "alice", "curtain": "slate"}"#,
+            r#"{"peer": "127.0.0.1:1", "curtain": "slate"}"#,
+        ] {
+            assert!(
+                serde_json::from_str::<ControlChange>(body).is_err(),
+                "{}",
+                body
+            );
+        }
+    }
+
+    #[test]
+    fn styles_can_be_overridden_and_cleared() {
+        let name = StreamName::new("live", "abc");
+        let controls = OperatorControls::new();
+        let change = |body: &str| {
+            controls.change(&name, "alice", peer(), serde_json::from_str(body).unwrap())
+        };
+
+        assert_eq!(change(r#"{"style": "fill"}"#).style, Some(BlurStyle::Fill));
+        assert_eq!(
+            change(r#"{"curtain": "blur"}"#).style,
+            Some(BlurStyle::Fill),
+            "left out"
+        );
+        assert_eq!(change(r#"{"style": null}"#).style, None);
+        assert_eq!(controls.get().curtain, Curtain::Blur);
+    }
+
+    #[test]
+    fn changes_are_recorded_as_the_authenticated_operator() {
+        let name = StreamName::new("live", "abc");
+        let controls = OperatorControls::new();
+        controls.change(
+            &name,
+            "alice",
+            peer(),
+            serde_json::from_str(r#"{"blur_everyone": true}"#).unwrap(),
+        );
+
+        let (current, changes) = controls.take_changes();
+        assert!(current.blur_everyone);
+        assert_eq!(changes.len(), 1);
+        assert_eq!(
+            serde_json::to_string(&changes[0]).unwrap(),
+            r#"{"operator":"alice","peer":"10.0.0.5:53422","blur_everyone":true}"#
+        );
+        assert!(controls.take_changes().1.is_empty(), "already seen");
+    }
+}
@@ -3,13 +3,23 @@
 //! a std::sync::mpsc::Receiver into an ffmpeg input
 //! Once you have the ffmpeg input, you can subsequently extract frames from it
 //! and blur them or whatever you want
+//!
+//! The same goes in the other direction: once frames are encoded, ffmpeg muxes
+//! them into an output that writes into a [`CustomFFMpegWrite`] implementor
+//!
+//! Files and in-memory buffers can be read through here too (see
+//! [`SeekableReader`]), for formats that ffmpeg needs to seek around in
 
 use std::{
-    mem::MaybeUninit,
+    fs::File,
+    io::{self, Cursor, Read, Seek, SeekFrom},
+    mem::{ManuallyDrop, MaybeUninit},
+    ops::{Deref, DerefMut},
     sync::mpsc::{Receiver, RecvError, TryRecvError},
 };
 
 use arrayvec::ArrayVec;
+use bytes::Bytes;
 use ffmpeg::sys as ffmpeg_c;
 use ffmpeg_next as ffmpeg;
 
@@ -17,11 +27,24 @@ use ffmpeg_next as ffmpeg;
 
 /// A trait for something that `ffmpeg` can read bytes from
 pub trait CustomFFMpegRead {
-    /// Read from self, writing the bytes read into the buffer
+    /// Whether ffmpeg gets to call [`CustomFFMpegRead::seek`]. Live sources
+    /// can't seek, but some formats (e.g. MP4 with the moov atom at the end)
+    /// can't be demuxed without it.
+    const SEEKABLE: bool = false;
+
+    /// Read from self, writing the bytes read into the buffer. The buffer is
+    /// ffmpeg's, and its contents are uninitialized.
     fn read(&mut self, buf: &mut [MaybeUninit<u8>]) -> Result<u32, ffmpeg::Error>;
 
-    /// Guess what format this video file is in
-    fn get_format_info(&mut self) -> Option<&mut ffmpeg_c::AVInputFormat> {
+    /// Move to `pos`, returning the new offset from the start
+    fn seek(&mut self, _pos: SeekFrom) -> Result<u64, ffmpeg::Error> {
+        Err(ffmpeg::Error::Other {
+            errno: libc::ESPIPE,
+        })
+    }
+
+    /// The total size in bytes, if known. Lets ffmpeg seek relative to the end.
+    fn size(&mut self) -> Option<u64> {
         None
     }
 }
@@ -34,13 +57,14 @@ pub trait CustomFFMpegWrite {
 
 // --- concrete impl defns
 
-/// A wrapper around a `Receiver<ArrayVec<u8, CHUNK_SIZE>>` 
+/// A wrapper around a `Receiver<ArrayVec<u8, CHUNK_SIZE>>`
 /// that `ffmpeg` can read bytes from in order to decode audio
 pub struct MPSCReader<const CHUNK_SIZE: usize> {
     recv: Receiver<ArrayVec<u8, CHUNK_SIZE>>,
 }
 
 impl<const CHUNK_SIZE: usize> MPSCReader<CHUNK_SIZE> {
+    /// Reads until every sender is gone
     pub fn new(recv: Receiver<ArrayVec<u8, CHUNK_SIZE>>) -> Self {
         Self { recv }
     }
@@ -77,6 +101,108 @@ impl<const CHUNK_SIZE: usize> CustomFFMpegRead for MPSCReader<CHUNK_SIZE> {
     }
 }
 
+/// A [`CustomFFMpegRead`] for anything that can [`Read`] and [`Seek`]
+pub struct SeekableReader<R> {
+    inner: R,
+}
+
+impl<R: Read + Seek> SeekableReader<R> {
+    /// Reads from the start of `inner`
+    pub fn new(inner: R) -> Self {
+        Self { inner }
+    }
+
+    /// Gives back the source, wherever ffmpeg left it
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+}
+
+impl<R: Read + Seek> CustomFFMpegRead for SeekableReader<R> {
+    const SEEKABLE: bool = true;
+
+    fn read(&mut self, buf: &mut [MaybeUninit<u8>]) -> Result<u32, ffmpeg::Error> {
+        read_into(&mut self.inner, buf)
+    }
+
+    fn seek(&mut self, pos: SeekFrom) -> Result<u64, ffmpeg::Error> {
+        self.inner.seek(pos).map_err(io_error_to_ffmpeg)
+    }
+
+    fn size(&mut self) -> Option<u64> {
+        // `Seek::stream_len` isn't stable, so do what it does
+        let position = self.inner.stream_position().ok()?;
+        let size = self.inner.seek(SeekFrom::End(0)).ok()?;
+        self.inner.seek(SeekFrom::Start(position)).ok()?;
+        Some(size)
+    }
+}
+
+impl CustomFFMpegRead for File {
+    const SEEKABLE: bool = true;
+
+    fn read(&mut self, buf: &mut [MaybeUninit<u8>]) -> Result<u32, ffmpeg::Error> {
+        read_into(self, buf)
+    }
+
+    fn seek(&mut self, pos: SeekFrom) -> Result<u64, ffmpeg::Error> {
+        Seek::seek(self, pos).map_err(io_error_to_ffmpeg)
+    }
+
+    fn size(&mut self) -> Option<u64> {
+        self.metadata().ok().map(|metadata| metadata.len())
+    }
+}
+
+/// A whole file that is already in memory
+impl CustomFFMpegRead for Cursor<Bytes> {
+    const SEEKABLE: bool = true;
+
+    fn read(&mut self, buf: &mut [MaybeUninit<u8>]) -> Result<u32, ffmpeg::Error> {
+        read_into(self, buf)
+    }
+
+    fn seek(&mut self, pos: SeekFrom) -> Result<u64, ffmpeg::Error> {
+        Seek::seek(self, pos).map_err(io_error_to_ffmpeg)
+    }
+
+    fn size(&mut self) -> Option<u64> {
+        Some(self.get_ref().len() as u64)
+    }
+}
+
+/// Whatever is being piped into us
+impl CustomFFMpegRead for io::Stdin {
+    fn read(&mut self, buf: &mut [MaybeUninit<u8>]) -> Result<u32, ffmpeg::Error> {
+        read_into(self, buf)
+    }
+}
+
+fn read_into<R: Read>(reader: &mut R, buf: &mut [MaybeUninit<u8>]) -> Result<u32, ffmpeg::Error> {
+    // ffmpeg's buffers come from av_malloc, which leaves them uninitialized,
+    // and `Read` takes a `&mut [u8]`, which must not point at uninitialized
+    // memory even if the implementation only ever writes to it
+    for byte in buf.iter_mut() {
+        byte.write(0);
+    }
+    // SAFETY: every byte was just initialized
+    let buf = unsafe { std::slice::from_raw_parts_mut(buf.as_mut_ptr() as *mut u8, buf.len()) };
+    loop {
+        match reader.read(buf) {
+            Ok(0) => return Err(ffmpeg::Error::Eof),
+            Ok(bytes_read) => return Ok(bytes_read as u32),
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(io_error_to_ffmpeg(e)),
+        }
+    }
+}
+
+fn io_error_to_ffmpeg(e: io::Error) -> ffmpeg::Error {
+    ffmpeg::Error::Other {
+        errno: e.raw_os_error().unwrap_or(libc::EIO),
+    }
+}
+
 // --- unsafe code below
 
 /// This function tells ffmpeg how to read from a CustomFFMpegRead implementor
@@ -104,41 +230,201 @@ unsafe extern "C" fn custom_ffmpeg_read<T: CustomFFMpegRead>(
     }
 }
 
-/// Use this function to help ffmpeg read from custom rust sources
+/// This function tells ffmpeg how to seek in a CustomFFMpegRead implementor.
+/// `whence` is one of `SEEK_SET`, `SEEK_CUR` or `SEEK_END`, possibly with
+/// `AVSEEK_FORCE` set, or `AVSEEK_SIZE` to ask for the size without seeking.
+///
+/// # Contract
+///
+/// Same as [`custom_ffmpeg_read`]: the opaque pointer must be a valid, unique,
+/// non-null pointer to a `T`
+unsafe extern "C" fn custom_ffmpeg_seek<T: CustomFFMpegRead>(
+    opaque: *mut libc::c_void,
+    offset: i64,
+    whence: i32,
+) -> i64 {
+    let it = &mut *(opaque as *mut T);
+
+    if whence & ffmpeg_c::AVSEEK_SIZE as i32 != 0 {
+        return match it.size() {
+            Some(size) => size as i64,
+            None => ffmpeg_c::AVERROR(libc::ENOSYS) as i64,
+        };
+    }
+    let pos = match whence & !(ffmpeg_c::AVSEEK_FORCE as i32) {
+        libc::SEEK_SET if offset >= 0 => SeekFrom::Start(offset as u64),
+        libc::SEEK_CUR => SeekFrom::Current(offset),
+        libc::SEEK_END => SeekFrom::End(offset),
+        _ => return ffmpeg_c::AVERROR(libc::EINVAL) as i64,
+    };
+
+    match it.seek(pos) {
+        Ok(position) => position as i64,
+        Err(e) => libc::c_int::from(e) as i64,
+    }
+}
+
+/// This function tells ffmpeg how to write into a CustomFFMpegWrite implementor
+///
+/// # Contract
+///
+/// Same as [`custom_ffmpeg_read`]: the opaque pointer must be a valid, unique,
+/// non-null pointer to a `T`
+unsafe extern "C" fn custom_ffmpeg_write<T: CustomFFMpegWrite>(
+    opaque: *mut libc::c_void,
+    buf: *mut u8,
+    buf_size: i32,
+) -> i32 {
+    let it = &mut *(opaque as *mut T);
+
+    let buf_safer = std::slice::from_raw_parts(buf as *const u8, buf_size as usize);
+    let result = it.write(buf_safer);
+
+    match result {
+        Ok(bytes_written) => bytes_written as i32,
+        Err(e) => libc::c_int::from(e),
+    }
+}
+
+/// An ffmpeg input that reads through a [`CustomFFMpegRead`] implementor.
+///
+/// `avformat_close_input` leaves custom AVIO contexts alone, so this owns the
+/// AVIO context and the reader and frees them after the input is closed.
+pub struct CustomInput<T> {
+    input: ManuallyDrop<ffmpeg::format::context::Input>,
+    avio_context: *mut ffmpeg_c::AVIOContext,
+    /// ffmpeg holds a pointer to this, so it has to outlive `input` and `avio_context`
+    _reader: Box<T>,
+}
+
+// SAFETY: the raw AVIO context pointer is only touched through `input` or in
+// `drop`, both of which require ownership of (or a unique reference to) `self`
+unsafe impl<T: Send> Send for CustomInput<T> {}
+
+impl<T> Deref for CustomInput<T> {
+    type Target = ffmpeg::format::context::Input;
+
+    fn deref(&self) -> &Self::Target {
+        &self.input
+    }
+}
+
+impl<T> DerefMut for CustomInput<T> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.input
+    }
+}
+
+impl<T> Drop for CustomInput<T> {
+    fn drop(&mut self) {
+        unsafe {
+            ManuallyDrop::drop(&mut self.input);
+            free_avio_context(&mut self.avio_context);
+        }
+    }
+}
+
+/// Frees an AVIO context allocated with `avio_alloc_context` along with its buffer
+///
+/// # Contract
+///
+/// Nothing may use the context afterwards. ffmpeg may have swapped out the
+/// buffer we originally gave it, so we free whatever it currently points at.
+unsafe fn free_avio_context(avio_context: &mut *mut ffmpeg_c::AVIOContext) {
+    if avio_context.is_null() {
+        return;
+    }
+    ffmpeg_c::av_freep(&mut (**avio_context).buffer as *mut *mut u8 as *mut libc::c_void);
+    ffmpeg_c::avio_context_free(avio_context);
+}
+
+/// Use this function to help ffmpeg read from custom rust sources.
+///
+/// The format is probed from the first bytes that come through, so this
+/// blocks until enough of them have. Use [`read_from_custom_input_as`] if you
+/// already know what is coming.
 pub fn read_from_custom_input<T: CustomFFMpegRead>(
     custom_ffmpegio_reader: T,
-) -> Result<ffmpeg::format::context::Input, ffmpeg::Error> {
+) -> Result<CustomInput<T>, ffmpeg::Error> {
+    open_custom_input(custom_ffmpegio_reader, None)
+}
+
+/// Like [`read_from_custom_input`], but skip probing and demux as
+/// `format_name`, an ffmpeg demuxer name such as `"flv"` or `"mpegts"`
+pub fn read_from_custom_input_as<T: CustomFFMpegRead>(
+    custom_ffmpegio_reader: T,
+    format_name: &str,
+) -> Result<CustomInput<T>, ffmpeg::Error> {
+    open_custom_input(custom_ffmpegio_reader, Some(format_name))
+}
+
+fn open_custom_input<T: CustomFFMpegRead>(
+    custom_ffmpegio_reader: T,
+    format_name: Option<&str>,
+) -> Result<CustomInput<T>, ffmpeg::Error> {
+    let format_name = format_name
+        .map(std::ffi::CString::new)
+        .transpose()
+        .map_err(|_| ffmpeg::Error::DemuxerNotFound)?;
     let mut custom_ffmpegio_reader = Box::new(custom_ffmpegio_reader);
     unsafe {
         // step 1: init AVFormatContext
         let mut avformat_context = ffmpeg_c::avformat_alloc_context();
+        let mut avio_context;
         {
             let buf_size: i32 = 8192;
             let buf = ffmpeg_c::av_malloc(buf_size as usize) as *mut u8;
 
             // tell the av format context to use our custom IO functions
-            (*avformat_context).pb = ffmpeg_c::avio_alloc_context(
+            avio_context = ffmpeg_c::avio_alloc_context(
                 buf,
                 buf_size,
                 0, // 0 for read, 1 for write,
                 &mut *custom_ffmpegio_reader as *mut T as *mut libc::c_void,
                 Some(custom_ffmpeg_read::<T>),
                 None,
-                None,
+                if T::SEEKABLE {
+                    Some(custom_ffmpeg_seek::<T>)
+                } else {
+                    None
+                },
             );
+            (*avformat_context).pb = avio_context;
 
             (*avformat_context).flags |= ffmpeg_c::AVFMT_FLAG_CUSTOM_IO;
+        }
 
-            // FIXME: assume we are reading FLV
-            (*avformat_context).iformat = custom_ffmpegio_reader
-                .get_format_info()
-                .map(|mutref| mutref as *mut _)
-                .unwrap_or_else(|| {
-                    ffmpeg_c::av_find_input_format(b"flv\0" as *const u8 as *const i8)
-                });
+        // step 2: figure out what we are reading. Whether `iformat` is const
+        // depends on the ffmpeg version, so borrow its type for ours.
+        let mut input_format = (*avformat_context).iformat;
+        let found = match &format_name {
+            Some(format_name) => {
+                input_format = ffmpeg_c::av_find_input_format(format_name.as_ptr());
+                match input_format.is_null() {
+                    true => Err(ffmpeg::Error::DemuxerNotFound),
+                    false => Ok(()),
+                }
+            }
+            None => match ffmpeg_c::av_probe_input_buffer(
+                avio_context,
+                &mut input_format,
+                b"\0" as *const u8 as *const i8, // empty filename
+                std::ptr::null_mut(),
+                0,
+                0, // ffmpeg's default probe size
+            ) {
+                0.. => Ok(()),
+                errno => Err(ffmpeg::Error::from(errno)),
+            },
+        };
+        if let Err(e) = found {
+            ffmpeg_c::avformat_free_context(avformat_context);
+            free_avio_context(&mut avio_context);
+            return Err(e);
         }
+        (*avformat_context).iformat = input_format;
 
-        // open the input
+        // step 3: open the input
         match ffmpeg_c::avformat_open_input(
             &mut avformat_context,
             b"\0" as *const u8 as *const i8, // empty filename
@@ -147,18 +433,166 @@ pub fn read_from_custom_input<T: CustomFFMpegRead>(
         ) {
             0 => {
                 match ffmpeg_c::avformat_find_stream_info(avformat_context, std::ptr::null_mut()) {
-                    0.. => {
-                        // FIXME: nobody ever frees the custom ffmpeg io reader??
-                        Box::leak(custom_ffmpegio_reader);
-                        Ok(ffmpeg::format::context::Input::wrap(avformat_context))
-                    }
+                    0.. => Ok(CustomInput {
+                        input: ManuallyDrop::new(ffmpeg::format::context::Input::wrap(
+                            avformat_context,
+                        )),
+                        avio_context,
+                        _reader: custom_ffmpegio_reader,
+                    }),
                     errno => {
                         ffmpeg_c::avformat_close_input(&mut avformat_context);
+                        free_avio_context(&mut avio_context);
                         Err(ffmpeg::Error::from(errno))
                     }
                 }
             }
-            errno => Err(ffmpeg::Error::from(errno)),
+            errno => {
+                // avformat_open_input frees the format context on failure, but not our AVIO context
+                free_avio_context(&mut avio_context);
+                Err(ffmpeg::Error::from(errno))
+            }
         }
     }
 }
+
+/// An ffmpeg output that writes through a [`CustomFFMpegWrite`] implementor.
+///
+/// Like [`CustomInput`], this owns the AVIO context and the writer so they
+/// are freed along with the output.
+pub struct CustomOutput<T> {
+    output: ManuallyDrop<ffmpeg::format::context::Output>,
+    avio_context: *mut ffmpeg_c::AVIOContext,
+    /// ffmpeg holds a pointer to this, so it has to outlive `output` and `avio_context`
+    _writer: Box<T>,
+}
+
+// SAFETY: see the impl for `CustomInput`
+unsafe impl<T: Send> Send for CustomOutput<T> {}
+
+impl<T> Deref for CustomOutput<T> {
+    type Target = ffmpeg::format::context::Output;
+
+    fn deref(&self) -> &Self::Target {
+        &self.output
+    }
+}
+
+impl<T> DerefMut for CustomOutput<T> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.output
+    }
+}
+
+impl<T> Drop for CustomOutput<T> {
+    fn drop(&mut self) {
+        unsafe {
+            ManuallyDrop::drop(&mut self.output);
+            free_avio_context(&mut self.avio_context);
+        }
+    }
+}
+
+/// Use this function to help ffmpeg mux into custom rust sinks.
+///
+/// `format_name` is an ffmpeg muxer name, e.g. `"flv"`. Every packet is
+/// flushed to the writer as soon as it is muxed, since we are usually feeding
+/// something live.
+pub fn write_to_custom_output<T: CustomFFMpegWrite>(
+    custom_ffmpegio_writer: T,
+    format_name: &str,
+) -> Result<CustomOutput<T>, ffmpeg::Error> {
+    let format_name =
+        std::ffi::CString::new(format_name).map_err(|_| ffmpeg::Error::MuxerNotFound)?;
+    let mut custom_ffmpegio_writer = Box::new(custom_ffmpegio_writer);
+    unsafe {
+        let mut avformat_context = std::ptr::null_mut();
+        match ffmpeg_c::avformat_alloc_output_context2(
+            &mut avformat_context,
+            std::ptr::null_mut(),
+            format_name.as_ptr(),
+            std::ptr::null(),
+        ) {
+            0.. => (),
+            errno => return Err(ffmpeg::Error::from(errno)),
+        }
+
+        let buf_size: i32 = 8192;
+        let buf = ffmpeg_c::av_malloc(buf_size as usize) as *mut u8;
+
+        let avio_context = ffmpeg_c::avio_alloc_context(
+            buf,
+            buf_size,
+            1, // 0 for read, 1 for write,
+            &mut *custom_ffmpegio_writer as *mut T as *mut libc::c_void,
+            None,
+            Some(custom_ffmpeg_write::<T>),
+            None,
+        );
+        (*avformat_context).pb = avio_context;
+        (*avformat_context).flags |= ffmpeg_c::AVFMT_FLAG_CUSTOM_IO;
+        (*avformat_context).flags |= ffmpeg_c::AVFMT_FLAG_FLUSH_PACKETS;
+
+        Ok(CustomOutput {
+            output: ManuallyDrop::new(ffmpeg::format::context::Output::wrap(avformat_context)),
+            avio_context,
+            _writer: custom_ffmpegio_writer,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::process::Command;
+
+    use super::*;
+
+    /// A second of `testsrc` as MP4, which puts the moov atom (the index)
+    /// after the media data unless told otherwise
+    fn mp4_with_moov_at_the_end() -> Vec<u8> {
+        let path = std::env::temp_dir().join(format!("moov-at-end-{}.mp4", std::process::id()));
+        let status = Command::new("ffmpeg")
+            .args(["-hide_banner", "-loglevel", "error", "-y"])
+            .args([
+                "-f",
+                "lavfi",
+                "-i",
+                "testsrc=size=160x120:rate=10:duration=1",
+            ])
+            .args(["-c:v", "mpeg4", "-f", "mp4"])
+            .arg(&path)
+            .status()
+            .expect("could not run ffmpeg, is it on the PATH?");
+        assert!(status.success());
+        let mp4 = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        mp4
+    }
+
+    fn find(haystack: &[u8], needle: &[u8]) -> usize {
+        haystack
+            .windows(needle.len())
+            .position(|window| window == needle)
+            .unwrap()
+    }
+
+    #[test]
+    fn reads_mp4_with_moov_at_the_end_from_memory() {
+        let mp4 = mp4_with_moov_at_the_end();
+        assert!(find(&mp4, b"mdat") < find(&mp4, b"moov"));
+
+        // the demuxer has to ask for the size and seek to the end for the
+        // index before it can read a single packet
+        let mut input = read_from_custom_input(Cursor::new(Bytes::from(mp4))).unwrap();
+        let video = input
+            .streams()
+            .best(ffmpeg::media::Type::Video)
+            .unwrap()
+            .index();
+        let packets = input
+            .packets()
+            .filter(|(stream, _)| stream.index() == video)
+            .count();
+        assert_eq!(packets, 10);
+    }
+}
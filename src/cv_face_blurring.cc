@@ -1,6 +1,10 @@
 #include <iostream>
 #include "anonynews_rs/include/cv_face_blurring.h"
+#include "anonynews_rs/src/image_processing.rs.h"
 #include <cassert>
+#include <algorithm>
+#include <iomanip>
+#include <sstream>
 
 namespace anonynews_rs
 {
@@ -41,7 +45,15 @@ namespace anonynews_rs
         cv::imwrite(filename, *img);
     }
 
-    std::vector<cv::Rect2i> findFaces(cv::Mat image)
+    struct FaceRegion
+    {
+        cv::Rect2i region;
+        float confidence;
+
+        FaceRegion(cv::Rect2i r, float c) : region(r), confidence(c) {}
+    };
+
+    std::vector<FaceRegion> findFaces(cv::Mat image)
     {
         const double CONFIDENCE_THRESHOLD = 0.2;
 
@@ -60,7 +72,7 @@ namespace anonynews_rs
                                 CV_32F,
                                 detection.ptr<float>());
 
-        std::vector<cv::Rect2i> regions;
+        std::vector<FaceRegion> regions;
         for (int i = 0; i < detectionMatrix.rows; i++)
         {
             auto confidence = detectionMatrix.at<float>(i, 2);
@@ -77,9 +89,9 @@ namespace anonynews_rs
 
                 if (valid)
                 {
-                    regions.push_back(cv::Rect2i(
-                        cv::Point2i(x1, y1),
-                        cv::Point2i(x2, y2)));
+                    regions.push_back(FaceRegion(
+                        cv::Rect2i(cv::Point2i(x1, y1), cv::Point2i(x2, y2)),
+                        confidence));
                 }
             }
         }
@@ -91,8 +103,9 @@ namespace anonynews_rs
     {
         cv::Mat faceVec;
         cv::Rect2i region;
+        float confidence;
 
-        EmbeddingResults(cv::Mat fv, cv::Rect2i r) : faceVec(std::move(fv)), region(r) {}
+        EmbeddingResults(cv::Mat fv, cv::Rect2i r, float c) : faceVec(std::move(fv)), region(r), confidence(c) {}
     };
 
     std::vector<EmbeddingResults> getEmbeddings(const cv::Mat &origImage)
@@ -114,8 +127,9 @@ namespace anonynews_rs
 
         std::vector<EmbeddingResults> embeddingResults;
 
-        for (auto &region : faceRegions)
+        for (auto &faceRegion : faceRegions)
         {
+            auto &region = faceRegion.region;
             if (region.width < MINIMUM_SIZE || region.height < MINIMUM_SIZE)
             {
                 continue;
@@ -124,8 +138,8 @@ namespace anonynews_rs
             cv::Mat faceBlob = cv::dnn::blobFromImage(face, 1.0 / 255.0, cv::Size2i(96, 96), cv::Scalar(0, 0, 0), true, false);
             faceEmbedderNet.setInput(faceBlob);
 
-            cv::Mat faceVec;
-            faceEmbedderNet.forward(faceVec);
+            // the net reuses its output between runs, and enrolled faces are kept
+            cv::Mat faceVec = faceEmbedderNet.forward().clone();
 
             cv::Rect2i rescaledRegion(
                 region.x * width_scaling,
@@ -134,57 +148,292 @@ namespace anonynews_rs
                 region.height * width_scaling);
 
             embeddingResults.push_back(EmbeddingResults(
-                faceVec, rescaledRegion));
+                faceVec, rescaledRegion, faceRegion.confidence));
         }
 
         return embeddingResults;
     }
 
-    std::vector<cv::Rect2i> findRegionsToBlur(std::vector<EmbeddingResults> embeddingResults, std::vector<cv::Mat> dontBlurTheseFaces)
+    struct EnrolledIdentity
     {
-        std::vector<cv::Rect2i> ret;
+        std::string name;
+        cv::Mat faceVec;
+
+        EnrolledIdentity(std::string n, cv::Mat fv) : name(std::move(n)), faceVec(std::move(fv)) {}
+    };
+
+    // Only written to before any frames are blurred, see enroll_identities
+    static std::vector<EnrolledIdentity> enrolledIdentities;
+    static float maxIdentityDistance = 0;
+
+    bool enrollIdentity(rust::Str name, rust::Slice<const uint8_t> image)
+    {
+        auto photo = cv::imdecode(cv::_InputArray(image.data(), image.size()), cv::IMREAD_COLOR);
+        if (photo.empty())
+        {
+            return false;
+        }
+
+        // it is meant to be a photo of one person, so anyone else in it is in the background
+        auto embeddings = getEmbeddings(photo);
+        auto largest = std::max_element(
+            embeddings.begin(), embeddings.end(),
+            [](const EmbeddingResults &a, const EmbeddingResults &b)
+            { return a.region.area() < b.region.area(); });
+        if (largest == embeddings.end())
+        {
+            return false;
+        }
+        enrolledIdentities.emplace_back(std::string(name), largest->faceVec);
+        return true;
+    }
+
+    void setMaxIdentityDistance(float maxDistance)
+    {
+        maxIdentityDistance = maxDistance;
+    }
+
+    // Decides what happens to every face that was found. Faces that don't
+    // match anyone in dontBlurTheseFaces get blurred, and so does everyone if
+    // blurEveryone is set.
+    rust::Vec<Detection> findRegionsToBlur(std::vector<EmbeddingResults> embeddingResults, const std::vector<EnrolledIdentity> &dontBlurTheseFaces, bool blurEveryone)
+    {
+        rust::Vec<Detection> ret;
 
         for (auto &er : embeddingResults)
         {
-            ret.push_back(er.region);
+            Detection detection;
+            detection.x = er.region.x;
+            detection.y = er.region.y;
+            detection.width = er.region.width;
+            detection.height = er.region.height;
+            detection.confidence = er.confidence;
+            detection.distance = -1;
+
+            const EnrolledIdentity *closest = nullptr;
+            for (auto &identity : dontBlurTheseFaces)
+            {
+                float distance = cv::norm(er.faceVec, identity.faceVec, cv::NORM_L2);
+                if (closest == nullptr || distance < detection.distance)
+                {
+                    closest = &identity;
+                    detection.distance = distance;
+                }
+            }
+            bool whitelisted = closest != nullptr && detection.distance <= maxIdentityDistance;
+            if (whitelisted)
+            {
+                detection.identity = rust::String(closest->name);
+            }
+            detection.blurred = blurEveryone || !whitelisted;
+            ret.push_back(std::move(detection));
         }
 
         return ret;
     }
 
-    cv::Mat blurRegions(cv::Mat imageToBlur, std::vector<cv::Rect2i> regionsToBlur)
+    // Anonymizes region of image in the given style. Only the pixels under
+    // mask are touched, or all of them if there is no mask.
+    void anonymize(cv::Mat image, cv::Rect2i region, BlurStyle style, const cv::Mat &mask = cv::Mat())
     {
         const auto GAUSSIAN_KERNEL = cv::Size(80, 80);
+        const int PIXEL_SIZE = 16;
+
+        region &= cv::Rect2i(0, 0, image.cols, image.rows);
+        if (region.empty())
+        {
+            return;
+        }
+        cv::Mat target = image(region);
+
+        cv::Mat styled;
+        switch (style)
+        {
+        case BlurStyle::Pixelate:
+        {
+            cv::Mat small;
+            cv::resize(target, small,
+                       cv::Size(std::max(1, region.width / PIXEL_SIZE), std::max(1, region.height / PIXEL_SIZE)),
+                       0, 0, cv::INTER_AREA);
+            cv::resize(small, styled, target.size(), 0, 0, cv::INTER_NEAREST);
+            break;
+        }
+        case BlurStyle::Fill:
+            styled = cv::Mat::zeros(target.size(), target.type());
+            break;
+        case BlurStyle::Blur:
+        default:
+            cv::blur(target, styled, GAUSSIAN_KERNEL);
+            break;
+        }
+
+        styled.copyTo(target, mask);
+    }
+
+    // Zones are in fractions of the frame's size, so they follow resolution changes
+    void anonymizeZone(cv::Mat image, const Zone &zone, BlurStyle style)
+    {
+        std::vector<cv::Point2i> corners;
+        for (auto &point : zone.points)
+        {
+            corners.emplace_back(cvRound(point.x * image.cols), cvRound(point.y * image.rows));
+        }
+        if (corners.size() < 3)
+        {
+            return;
+        }
+
+        auto region = cv::boundingRect(corners) & cv::Rect2i(0, 0, image.cols, image.rows);
+        if (region.empty())
+        {
+            return;
+        }
+        for (auto &corner : corners)
+        {
+            corner -= region.tl();
+        }
+        cv::Mat mask = cv::Mat::zeros(region.size(), CV_8UC1);
+        cv::fillPoly(mask, std::vector<std::vector<cv::Point2i>>{corners}, cv::Scalar(255));
+
+        anonymize(image, region, style, mask);
+    }
 
-        for (auto &rec : regionsToBlur)
+    cv::Mat blurRegions(cv::Mat imageToBlur, const rust::Vec<Detection> &detections, const rust::Vec<Zone> &zones, BlurStyle style)
+    {
+        for (auto &zone : zones)
+        {
+            anonymizeZone(imageToBlur, zone, style);
+        }
+
+        for (auto &detection : detections)
         {
+            if (!detection.blurred)
+            {
+                continue;
+            }
             // FIXME: blur the margin as well
-            cv::blur(imageToBlur(rec), imageToBlur(rec), GAUSSIAN_KERNEL);
+            cv::Rect2i rec(detection.x, detection.y, detection.width, detection.height);
+            anonymize(imageToBlur, rec, style);
         }
 
         return imageToBlur;
     }
 
-    cv::Mat blur(cv::Mat toBlur)
+    cv::Mat blur(cv::Mat toBlur, rust::Vec<Detection> &detections, const BlurOptions &options)
     {
-        auto embeddings = getEmbeddings(toBlur);
-        // TODO: load whitelist somehow
-        auto regionsToBlur = findRegionsToBlur(std::move(embeddings), std::vector<cv::Mat>());
-        return blurRegions(toBlur, std::move(regionsToBlur));
+        if (options.detect_faces && options.use_models)
+        {
+            auto embeddings = getEmbeddings(toBlur);
+            detections = findRegionsToBlur(std::move(embeddings), enrolledIdentities, options.blur_everyone);
+        }
+        else if (options.detect_faces)
+        {
+            // a scripted detector already decided where the faces are
+            for (auto &face : options.given_faces)
+            {
+                Detection detection = face;
+                detection.blurred = detection.blurred || options.blur_everyone;
+                detections.push_back(std::move(detection));
+            }
+        }
+        if (!options.anonymize)
+        {
+            return toBlur;
+        }
+        return blurRegions(toBlur, detections, options.zones, options.style);
     }
 
-    std::unique_ptr<std::vector<uint8_t>> blurFFMpegFrame(rust::Slice<const uint8_t> pngBuffer)
+    BlurResult blurFFMpegFrame(rust::Slice<const uint8_t> pngBuffer, const BlurOptions &options)
     {
         auto cvMat = cvMatrixFromPNGBuffer(pngBuffer);
-        auto blurredMat = blur(std::move(cvMat));
+        rust::Vec<Detection> detections;
+        auto blurredMat = blur(std::move(cvMat), detections, options);
         cv::cvtColor(blurredMat, blurredMat, cv::COLOR_BGR2RGB);
 
-        std::unique_ptr<std::vector<uint8_t>> ret = std::make_unique<std::vector<uint8_t>>();
+        std::unique_ptr<std::vector<uint8_t>> pixels = std::make_unique<std::vector<uint8_t>>();
 
         cv::Mat flat = blurredMat.reshape(1, blurredMat.total() * blurredMat.channels());
-        *ret = blurredMat.isContinuous() ? flat : flat.clone();
+        *pixels = blurredMat.isContinuous() ? flat : flat.clone();
 
-        return ret;
+        return BlurResult{std::move(pixels), std::move(detections)};
+    }
+
+    // Puts text on a dark background, so it can be read on any picture
+    void drawLabel(cv::Mat frame, const std::string &text, cv::Point2i origin, cv::Scalar color)
+    {
+        const auto FONT = cv::FONT_HERSHEY_SIMPLEX;
+        const double FONT_SCALE = 0.5;
+        const int THICKNESS = 1;
+
+        int baseline = 0;
+        auto size = cv::getTextSize(text, FONT, FONT_SCALE, THICKNESS, &baseline);
+        // keep it on screen
+        origin.x = std::max(0, std::min(origin.x, frame.cols - size.width));
+        origin.y = std::max(size.height, std::min(origin.y, frame.rows - baseline));
+
+        cv::rectangle(frame,
+                      cv::Rect2i(origin.x, origin.y - size.height, size.width, size.height + baseline),
+                      cv::Scalar(0, 0, 0), cv::FILLED);
+        cv::putText(frame, text, origin, FONT, FONT_SCALE, color, THICKNESS, cv::LINE_AA);
+    }
+
+    void drawDebugOverlay(
+        rust::Slice<uint8_t> pixels,
+        int32_t width,
+        int32_t height,
+        int32_t stride,
+        rust::Slice<const Detection> detections,
+        rust::Slice<const uint32_t> trackIds,
+        rust::Slice<const Zone> zones,
+        float processingMs)
+    {
+        // the frame is RGB rather than OpenCV's usual BGR, so these are too
+        const cv::Scalar BLURRED(255, 64, 64);
+        const cv::Scalar WHITELISTED(64, 255, 64);
+        const cv::Scalar ZONE(255, 255, 0);
+        const cv::Scalar CAPTION(255, 255, 255);
+
+        // draws straight into the frame without copying it
+        cv::Mat frame(height, width, CV_8UC3, pixels.data(), stride);
+
+        for (auto &zone : zones)
+        {
+            std::vector<cv::Point2i> corners;
+            for (auto &point : zone.points)
+            {
+                corners.emplace_back(cvRound(point.x * width), cvRound(point.y * height));
+            }
+            cv::polylines(frame, corners, true, ZONE, 2);
+        }
+
+        for (size_t i = 0; i < detections.size(); i++)
+        {
+            auto &detection = detections[i];
+            auto color = detection.blurred ? BLURRED : WHITELISTED;
+            cv::rectangle(frame, cv::Rect2i(detection.x, detection.y, detection.width, detection.height), color, 2);
+
+            std::ostringstream label;
+            label << std::fixed << std::setprecision(2);
+            if (i < trackIds.size())
+            {
+                label << "#" << trackIds[i] << " ";
+            }
+            label << detection.confidence;
+            if (detection.identity.size() > 0)
+            {
+                label << " " << std::string(detection.identity);
+            }
+            if (detection.distance >= 0)
+            {
+                label << " d=" << detection.distance;
+            }
+            drawLabel(frame, label.str(), cv::Point2i(detection.x, detection.y - 4), color);
+        }
+
+        std::ostringstream caption;
+        caption << std::fixed << std::setprecision(1)
+                << detections.size() << " faces, " << processingMs << " ms";
+        drawLabel(frame, caption.str(), cv::Point2i(4, 16), CAPTION);
     }
 }
 
@@ -1,113 +1,343 @@
+//! The front of a [`Pipeline`](crate::pipeline::Pipeline): turns RTMP video
+//! messages into RGB24 frames, with their timestamps in
+//! [`pipeline::TIME_BASE`].
+
 use std::{
-    sync::mpsc::{channel, Receiver},
-    thread,
+    io,
+    sync::{
+        mpsc::{channel, Receiver, Sender},
+        Arc, Mutex,
+    },
+    thread::{self, JoinHandle},
 };
 
+use arrayvec::ArrayVec;
 use bytes::Bytes;
+use ffmpeg::sys as ffmpeg_c;
 use ffmpeg::{
     codec::{self, decoder},
-    frame,
-    software::scaling,
+    frame, media,
     util::format,
+    Packet, Rational, Rescale,
 };
 use ffmpeg_next as ffmpeg;
+use tracing::{debug, info, warn};
 
 use crate::{
-    custom_ffmpeg_io::{read_from_custom_input, MPSCReader},
+    custom_ffmpeg_io::{read_from_custom_input_as, MPSCReader},
     flv_file::{BufferedSenderWriter, FLVWriterWrapper},
+    image_processing::Detection,
+    pipeline::{self, Frames},
+    rescaling::Rescaler,
+    shutdown::Shutdown,
+    video_tags::{VideoCodec, VideoPacketType, VideoTag},
 };
 
+/// Decodes the video of a stream, fed to it an RTMP video message at a time,
+/// into frames on a thread of its own
 #[derive(Debug)]
 pub struct FrameExtractor {
-    /// Put stream bytes into here. They will get passed to ffmpeg.
-    rtmp_stream_input: FLVWriterWrapper<BufferedSenderWriter<1024>>,
+    /// `None` until the first video message tells us which kind of input we need
+    input: Option<DecoderInput>,
+    /// Handed to the decode thread once it is started
+    frame_tx: Option<Sender<(frame::Video, Vec<Detection>)>>,
+    cancel: Shutdown,
+    /// Set once we know what the publisher is sending
+    ingest_codec: Arc<Mutex<Option<VideoCodec>>>,
+    decode_thread: Option<JoinHandle<()>>,
+}
+
+/// Where stream bytes go to get passed to ffmpeg
+#[derive(Debug)]
+enum DecoderInput {
+    /// Plain AVC, which ffmpeg's flv demuxer understands
+    Flv(Box<FLVWriterWrapper<BufferedSenderWriter<1024>>>),
+    /// Enhanced RTMP, which it doesn't in ffmpeg 5, so the tags are taken apart
+    /// by [`crate::video_tags`] and the decoder is fed directly
+    Enhanced(Sender<(u32, VideoTag)>),
 }
 
 impl FrameExtractor {
-    pub fn new() -> (Self, Receiver<frame::Video>) {
-        let (flv_tx, flv_rx) = channel();
+    /// `cancel` makes the decode thread stop at the next packet without
+    /// flushing the decoder. Closing the input (see [`FrameExtractor::finish`])
+    /// is the graceful way of stopping it.
+    ///
+    /// `ingest_codec` gets filled in with whatever the publisher turns out to
+    /// be sending.
+    pub fn new(cancel: Shutdown, ingest_codec: Arc<Mutex<Option<VideoCodec>>>) -> (Self, Frames) {
         let (frame_tx, frame_rx) = channel();
-        let mut rtmp_stream_input = FLVWriterWrapper::new(BufferedSenderWriter::new(flv_tx));
-        rtmp_stream_input.write_header().unwrap();
-
-        // This thread reads from the rtmp_stream_input and dumps the frames out on the frame_sink
-        thread::spawn(move || {
-            let custom_io = MPSCReader::new(flv_rx);
-            // let custom_io = FileReader::new("hi.flv");
-
-            if let Ok(mut ictx) = read_from_custom_input(custom_io) {
-                println!(
-                    "{:?}",
-                    ictx.streams()
-                        .inspect(|x| println!("{:?}", x.metadata()))
-                        .count()
-                );
-                let input = ictx
-                    .streams()
-                    .next()
-                    // .best(ffmpeg::media::Type::Video)
-                    .expect("pleases");
-
-                let video_stream_index = input.index();
-                let ctx = codec::Context::from_parameters(input.parameters()).unwrap();
-                let mut decoder = ctx.decoder().video().unwrap();
-
-                // Convert the frame to RGB, same width, height
-                let mut scaler = scaling::Context::get(
-                    decoder.format(),
-                    decoder.width(),
-                    decoder.height(),
-                    format::Pixel::RGB24,
-                    decoder.width(),
-                    decoder.height(),
-                    scaling::Flags::BILINEAR,
-                )
-                .unwrap();
-
-                println!(
-                    "width {:?} vs height {:?}",
-                    decoder.width(),
-                    decoder.height()
-                );
+        (
+            Self {
+                input: None,
+                frame_tx: Some(frame_tx),
+                cancel,
+                ingest_codec,
+                decode_thread: None,
+            },
+            frame_rx,
+        )
+    }
+
+    /// Close the input and wait for the decoder to drain the frames it has
+    /// buffered. Downstream stages see their receiver disconnect once this returns.
+    pub fn finish(mut self) {
+        match self.input.take() {
+            Some(DecoderInput::Flv(mut flv)) => {
+                if let Err(e) = flv.flush_inner() {
+                    debug!("could not flush the last bytes to the decoder: {}", e);
+                }
+            }
+            Some(DecoderInput::Enhanced(_)) | None => (),
+        }
+        if let Some(decode_thread) = self.decode_thread.take() {
+            if decode_thread.join().is_err() {
+                warn!("frame decoding thread panicked");
+            }
+        }
+    }
 
-                let mut process_decoded_frames =
-                    |decoder: &mut decoder::Video| -> Result<(), ffmpeg::Error> {
-                        let mut decoded = frame::Video::empty();
-                        while let Ok(()) = decoder.receive_frame(&mut decoded) {
-                            let mut rgb_frame = frame::Video::empty();
-                            scaler.run(&decoded, &mut rgb_frame)?;
-
-                            // if the reciever stops listening, its completely fine for
-                            // this thread to die
-                            frame_tx.send(rgb_frame).unwrap();
-                        }
-
-                        Ok(())
-                    };
-
-                for (stream, packet) in ictx.packets() {
-                    if stream.index() == video_stream_index {
-                        decoder.send_packet(&packet).expect("ffmpeg could not decode one of the packets from the stream");
-                        process_decoded_frames(&mut decoder).unwrap();
+    /// Fails with `BrokenPipe` if the decode thread has gone away, or
+    /// `InvalidData` if the stream is in a codec we can't decode
+    pub fn send_bytes(&mut self, timestamp: u32, bytes: &Bytes) -> io::Result<()> {
+        if self.input.is_none() {
+            self.start(bytes)?;
+        }
+        match self.input.as_mut().unwrap() {
+            DecoderInput::Flv(flv) => flv.write_video_bytes(timestamp, bytes),
+            DecoderInput::Enhanced(tags) => {
+                let tag = match VideoTag::parse(bytes) {
+                    Some(tag) => tag,
+                    None => {
+                        debug!("skipping a video message we don't understand");
+                        return Ok(());
                     }
+                };
+                tags.send((timestamp, tag))
+                    .map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe))
+            }
+        }
+    }
+
+    /// Spawn the decode thread that suits the first video message
+    fn start(&mut self, first_message: &Bytes) -> io::Result<()> {
+        let tag = VideoTag::parse(first_message).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                "the stream's video is not in a codec we can decode",
+            )
+        })?;
+        info!("publisher is sending {:?} video", tag.codec);
+        *self.ingest_codec.lock().unwrap() = Some(tag.codec);
+
+        let frame_sink = FrameSink::new(self.frame_tx.take().unwrap());
+        let cancel = self.cancel.clone();
+        let (input, decode_thread) = if tag.enhanced {
+            let (tag_tx, tag_rx) = channel();
+            let decode_thread = thread::Builder::new()
+                .name("frame decoding thread".to_owned())
+                .spawn(move || decode_enhanced(tag_rx, frame_sink, cancel))
+                .expect("failed to spawn thread");
+            (DecoderInput::Enhanced(tag_tx), decode_thread)
+        } else {
+            let (flv_tx, flv_rx) = channel();
+            let mut flv = FLVWriterWrapper::new(BufferedSenderWriter::new(flv_tx));
+            flv.write_header()?;
+            let decode_thread = thread::Builder::new()
+                .name("frame decoding thread".to_owned())
+                .spawn(move || decode_flv(flv_rx, frame_sink, cancel))
+                .expect("failed to spawn thread");
+            (DecoderInput::Flv(Box::new(flv)), decode_thread)
+        };
+        self.input = Some(input);
+        self.decode_thread = Some(decode_thread);
+        Ok(())
+    }
+}
+
+/// Converts decoded frames to RGB and passes them down the pipeline, with
+/// nothing anonymized in them yet
+struct FrameSink {
+    frame_tx: Sender<(frame::Video, Vec<Detection>)>,
+    /// Convert the frame to RGB, same width, height
+    to_rgb: Rescaler,
+}
+
+impl FrameSink {
+    fn new(frame_tx: Sender<(frame::Video, Vec<Detection>)>) -> Self {
+        Self {
+            frame_tx,
+            to_rgb: Rescaler::new(format::Pixel::RGB24, "decoded frames"),
+        }
+    }
+
+    /// `time_base` is what the decoder's timestamps are in.
+    ///
+    /// Fails with [`ffmpeg::Error::Exit`] if the receiver stopped listening,
+    /// in which case there is no point decoding more
+    fn drain(
+        &mut self,
+        decoder: &mut decoder::Video,
+        time_base: Rational,
+    ) -> Result<(), ffmpeg::Error> {
+        let mut decoded = frame::Video::empty();
+        while let Ok(()) = decoder.receive_frame(&mut decoded) {
+            let mut rgb_frame = match self.to_rgb.run(&decoded) {
+                Ok(rgb_frame) => rgb_frame,
+                Err(e) => {
+                    warn!("could not convert a decoded frame to RGB: {}", e);
+                    continue;
                 }
+            };
+            match rgb_frame.pts() {
+                Some(pts) => rgb_frame.set_pts(Some(pts.rescale(time_base, pipeline::TIME_BASE))),
+                None => debug!("decoded a frame without a timestamp"),
+            }
 
-                decoder.send_eof().unwrap();
-                process_decoded_frames(&mut decoder).unwrap();
+            if self.frame_tx.send((rgb_frame, Vec::new())).is_err() {
+                return Err(ffmpeg::Error::Exit);
             }
-        });
+        }
+        Ok(())
+    }
+}
 
-        (
-            Self {
-                rtmp_stream_input,
-            },
-            frame_rx,
-        )
+/// Reads plain FLV out of `flv_rx` and dumps the frames out on the frame sink
+fn decode_flv(flv_rx: Receiver<ArrayVec<u8, 1024>>, mut frames: FrameSink, cancel: Shutdown) {
+    let mut ictx = match read_from_custom_input_as(MPSCReader::new(flv_rx), "flv") {
+        Ok(ictx) => ictx,
+        Err(e) => {
+            debug!("could not read the stream: {}", e);
+            return;
+        }
+    };
+    // audio might come first, so don't just take the first stream
+    let (video_stream_index, time_base, parameters) = match ictx.streams().best(media::Type::Video)
+    {
+        Some(stream) => (stream.index(), stream.time_base(), stream.parameters()),
+        None => {
+            warn!("the stream has no video we can decode");
+            return;
+        }
+    };
+    let mut decoder =
+        match codec::Context::from_parameters(parameters).and_then(|ctx| ctx.decoder().video()) {
+            Ok(decoder) => decoder,
+            Err(e) => {
+                warn!("could not open the decoder: {}", e);
+                return;
+            }
+        };
+
+    for (stream, packet) in ictx.packets() {
+        if cancel.is_cancelled() {
+            debug!("decode thread cancelled");
+            return;
+        }
+        if stream.index() == video_stream_index {
+            if let Err(e) = decoder.send_packet(&packet) {
+                warn!(
+                    "ffmpeg could not decode one of the packets from the stream: {}",
+                    e
+                );
+                continue;
+            }
+            if frames.drain(&mut decoder, time_base).is_err() {
+                return;
+            }
+        }
+    }
+
+    // the input was closed, so drain whatever the decoder is holding on to
+    if decoder.send_eof().is_ok() {
+        let _ = frames.drain(&mut decoder, time_base);
+    }
+}
+
+/// Decodes Enhanced RTMP tags until the sender hangs up
+fn decode_enhanced(tags: Receiver<(u32, VideoTag)>, mut frames: FrameSink, cancel: Shutdown) {
+    // opened by each sequence start, since that carries the decoder configuration
+    let mut decoder: Option<decoder::Video> = None;
+
+    for (timestamp, tag) in tags.iter() {
+        if cancel.is_cancelled() {
+            debug!("decode thread cancelled");
+            return;
+        }
+        match tag.packet_type {
+            VideoPacketType::SequenceStart => {
+                if let Some(mut old_decoder) = decoder.take() {
+                    if old_decoder.send_eof().is_ok()
+                        && frames.drain(&mut old_decoder, pipeline::TIME_BASE).is_err()
+                    {
+                        return;
+                    }
+                }
+                match open_decoder(tag.codec, &tag.payload) {
+                    Ok(new_decoder) => decoder = Some(new_decoder),
+                    Err(e) => warn!("could not open a {:?} decoder: {}", tag.codec, e),
+                }
+            }
+            VideoPacketType::CodedFrames => {
+                let decoder = match &mut decoder {
+                    Some(decoder) => decoder,
+                    None => continue,
+                };
+                let mut packet = Packet::copy(&tag.payload);
+                packet.set_dts(Some(timestamp as i64));
+                packet.set_pts(Some(timestamp as i64 + tag.composition_time as i64));
+                if let Err(e) = decoder.send_packet(&packet) {
+                    warn!(
+                        "ffmpeg could not decode one of the packets from the stream: {}",
+                        e
+                    );
+                    continue;
+                }
+                if frames.drain(decoder, pipeline::TIME_BASE).is_err() {
+                    return;
+                }
+            }
+            VideoPacketType::SequenceEnd | VideoPacketType::Other => (),
+        }
+    }
+
+    // the input was closed, so drain whatever the decoder is holding on to
+    if let Some(mut decoder) = decoder {
+        if decoder.send_eof().is_ok() {
+            let _ = frames.drain(&mut decoder, pipeline::TIME_BASE);
+        }
+    }
+}
+
+/// Open a decoder for `codec` with the configuration record (`avcC`, `hvcC`,
+/// `av1C` or `vpcC`) from a sequence start as its extradata
+fn open_decoder(codec: VideoCodec, config: &[u8]) -> Result<decoder::Video, ffmpeg::Error> {
+    let decoder_codec = match codec {
+        VideoCodec::Avc => decoder::find(codec::Id::H264),
+        VideoCodec::Hevc => decoder::find(codec::Id::HEVC),
+        // ffmpeg's own AV1 decoder needs hardware, so prefer dav1d
+        VideoCodec::Av1 => {
+            decoder::find_by_name("libdav1d").or_else(|| decoder::find(codec::Id::AV1))
+        }
+        VideoCodec::Vp9 => decoder::find(codec::Id::VP9),
     }
+    .ok_or(ffmpeg::Error::DecoderNotFound)?;
 
-    pub fn send_bytes(&mut self, timestamp: u32, bytes: &Bytes) {
-        self.rtmp_stream_input
-            .write_video_bytes(timestamp, bytes)
-            .unwrap();
+    let mut context = codec::Context::new();
+    unsafe {
+        let raw = context.as_mut_ptr();
+        // ffmpeg frees the extradata along with the context, and wants it padded
+        let extradata =
+            ffmpeg_c::av_mallocz(config.len() + ffmpeg_c::AV_INPUT_BUFFER_PADDING_SIZE as usize)
+                as *mut u8;
+        if extradata.is_null() {
+            return Err(ffmpeg::Error::Other {
+                errno: libc::ENOMEM,
+            });
+        }
+        std::ptr::copy_nonoverlapping(config.as_ptr(), extradata, config.len());
+        (*raw).extradata = extradata;
+        (*raw).extradata_size = config.len() as libc::c_int;
+        (*raw).pkt_timebase = pipeline::TIME_BASE.into();
     }
+    context.decoder().open_as(decoder_codec)?.video()
 }
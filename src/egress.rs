@@ -0,0 +1,757 @@
+//! Pushes a blurred stream on to another RTMP server, e.g. a platform's
+//! ingest. We act as an ordinary RTMP client that publishes the stream.
+
+use std::{
+    collections::VecDeque,
+    future::Future,
+    sync::{atomic::Ordering, Arc, Weak},
+    time::{Duration, Instant},
+};
+
+use rml_rtmp::{
+    chunk_io::Packet,
+    handshake::{Handshake, HandshakeError, HandshakeProcessResult, PeerType},
+    sessions::{
+        ClientSession, ClientSessionConfig, ClientSessionError, ClientSessionEvent,
+        ClientSessionResult, PublishRequestType,
+    },
+    time::RtmpTimestamp,
+};
+use thiserror::Error;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{
+        tcp::{OwnedReadHalf, OwnedWriteHalf},
+        TcpStream,
+    },
+    sync::{broadcast, mpsc},
+    task::JoinHandle,
+    time::timeout,
+};
+use tracing::{debug, info, warn, Instrument};
+
+use crate::{
+    config::EgressConfig,
+    metrics::{EgressMetrics, EgressState, Metrics},
+    region_metadata,
+    routing::Destination,
+    shutdown::Shutdown,
+    stream_registry::{CachedHeaders, LiveStream, MediaKind, MediaPacket},
+};
+
+/// A connection that lasted this long resets the reconnect backoff
+const STABLE_CONNECTION: Duration = Duration::from_secs(30);
+
+/// How long the destination gets to accept our connection and publish request
+const SETUP_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Give up on a destination that hasn't taken any bytes for this long
+const WRITE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How many messages can wait for a slow destination before we start skipping
+/// ahead to keyframes. A few seconds' worth at typical frame rates.
+const SEND_QUEUE_LEN: usize = 256;
+
+const READ_BUFFER_SIZE: usize = 4096;
+
+/// The message stream we publish on. rml_rtmp doesn't tell us which one the
+/// destination created, but every server we know of hands out 1 for the first
+/// `createStream` on a connection.
+const PUBLISH_STREAM_ID: u32 = 1;
+
+/// Why a connection to a destination ended, before it is retried
+#[derive(Debug, Error)]
+pub enum EgressError {
+    /// Connecting, reading or writing failed
+    #[error("io error: {0}")]
+    Io(#[from] std::io::Error),
+
+    /// The destination doesn't speak RTMP, or not to us
+    #[error("handshake failed: {0}")]
+    Handshake(#[from] HandshakeError),
+
+    /// The destination sent something rml_rtmp couldn't make sense of
+    #[error("rtmp session error: {0}")]
+    Session(#[from] ClientSessionError),
+
+    /// The destination didn't let us publish in time
+    #[error("destination did not accept the stream within {0:?}")]
+    SetupTimedOut(Duration),
+
+    /// The destination said no, e.g. to a wrong stream key
+    #[error("destination rejected the stream: {0}")]
+    Rejected(String),
+
+    /// The destination hung up
+    #[error("destination closed the connection")]
+    Closed,
+}
+
+/// Push `stream` to `destination` until the stream ends or `abort` fires.
+///
+/// Every destination gets its own task, connection and send queue, so one
+/// that is slow or gone can't hold up the encoder or any other destination.
+/// If the connection drops, we keep up with the stream while reconnecting
+/// with exponential backoff. Only a weak reference to the stream is kept, so
+/// this never keeps a stream alive after its publisher is gone.
+///
+/// Returns the destination's metrics, which are reported for as long as
+/// either they or the task are around.
+pub fn spawn(
+    stream: &Arc<LiveStream>,
+    destination: Destination,
+    config: EgressConfig,
+    metrics: &Metrics,
+    abort: Shutdown,
+) -> Arc<EgressMetrics> {
+    let metrics = metrics.register_egress(stream.name(), &destination);
+    let span = tracing::info_span!("egress", stream = %stream.name(), destination = %destination);
+    // subscribe straight away, so nothing is missed while we connect
+    let (_, packets) = stream.subscribe();
+    let stream = Arc::downgrade(stream);
+    let task_metrics = metrics.clone();
+    tokio::spawn(
+        async move {
+            let mut egress = Egress {
+                stream,
+                packets,
+                destination,
+                metrics: task_metrics,
+                backlog: CatchUpBuffer::new(config.catch_up()),
+            };
+            egress.run(config, &abort).await;
+            info!("finished pushing the stream");
+            egress.metrics.set_state(EgressState::Finished);
+        }
+        .instrument(span),
+    );
+    metrics
+}
+
+/// One stream being pushed to one destination
+struct Egress {
+    stream: Weak<LiveStream>,
+    packets: broadcast::Receiver<MediaPacket>,
+    destination: Destination,
+    metrics: Arc<EgressMetrics>,
+    /// What arrived while we were not connected
+    backlog: CatchUpBuffer,
+}
+
+impl Egress {
+    /// Connect, send until something goes wrong, back off, repeat
+    async fn run(&mut self, config: EgressConfig, abort: &Shutdown) {
+        let mut delay = config.min_reconnect_delay();
+        loop {
+            self.metrics.set_state(EgressState::Connecting);
+            let destination = self.destination.clone();
+            let connecting = timeout(SETUP_TIMEOUT, EgressConnection::publish(&destination));
+            let connection = match self.keep_up_until(connecting, abort).await {
+                Some(Ok(Ok(connection))) => Some(connection),
+                Some(Ok(Err(e))) => {
+                    warn!("could not connect to the destination: {}", e);
+                    None
+                }
+                Some(Err(_)) => {
+                    warn!("{}", EgressError::SetupTimedOut(SETUP_TIMEOUT));
+                    None
+                }
+                None => return,
+            };
+
+            if let Some(connection) = connection {
+                info!("destination accepted the stream");
+                self.metrics.set_state(EgressState::Live);
+                let connected_at = Instant::now();
+                match self.send_to(connection, abort).await {
+                    Ok(()) => return,
+                    Err(e) => warn!("lost the destination: {}", e),
+                }
+                // only start over from a short delay if the last attempt got somewhere
+                if connected_at.elapsed() >= STABLE_CONNECTION {
+                    delay = config.min_reconnect_delay();
+                }
+            }
+
+            self.metrics.set_state(EgressState::Reconnecting);
+            self.metrics.reconnects.fetch_add(1, Ordering::Relaxed);
+            info!("reconnecting in {:?}", delay);
+            if self
+                .keep_up_until(tokio::time::sleep(delay), abort)
+                .await
+                .is_none()
+            {
+                return;
+            }
+            delay = (delay * 2).min(config.max_reconnect_delay());
+        }
+    }
+
+    /// Wait for `fut` while buffering (or dropping) the stream's packets.
+    /// Returns `None` if the stream ended or `abort` fired first.
+    async fn keep_up_until<T>(
+        &mut self,
+        fut: impl Future<Output = T>,
+        abort: &Shutdown,
+    ) -> Option<T> {
+        tokio::pin!(fut);
+        loop {
+            tokio::select! {
+                res = &mut fut => return Some(res),
+                packet = self.packets.recv() => match packet {
+                    Ok(packet) => self.backlog.push(packet, &self.metrics),
+                    Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                        self.metrics.packets_dropped.fetch_add(skipped, Ordering::Relaxed);
+                        self.backlog.clear(&self.metrics);
+                    }
+                    Err(broadcast::error::RecvError::Closed) => return None,
+                },
+                _ = abort.cancelled() => return None,
+            }
+        }
+    }
+
+    /// Catch the destination up, then forward the stream to it until it goes
+    /// away (an error) or the stream ends (`Ok`)
+    async fn send_to(
+        &mut self,
+        mut connection: EgressConnection,
+        abort: &Shutdown,
+    ) -> Result<(), EgressError> {
+        let metrics = self.metrics.clone();
+
+        // the headers may have changed since we were last connected
+        let headers = match self.stream.upgrade() {
+            Some(stream) => stream.headers(),
+            None => CachedHeaders::default(),
+        };
+        if let Some(metadata) = headers.metadata {
+            let result = connection.session.publish_metadata(&metadata)?;
+            connection.handle_results(vec![result]).await?;
+        }
+        for header in [headers.video_sequence_header, headers.audio_sequence_header]
+            .into_iter()
+            .flatten()
+        {
+            connection.send_packet(header, &metrics).await?;
+        }
+
+        // the backlog always starts at a keyframe
+        let mut waiting_for_keyframe = true;
+        if !self.backlog.is_empty() {
+            info!("replaying {} packets from the outage", self.backlog.len());
+            waiting_for_keyframe = false;
+            for packet in self.backlog.take() {
+                if !connection.send_packet(packet, &metrics).await? {
+                    waiting_for_keyframe = true;
+                    break;
+                }
+            }
+        }
+
+        loop {
+            tokio::select! {
+                packet = self.packets.recv() => match packet {
+                    Ok(packet) => {
+                        // cue points describe frames that are being skipped too
+                        if waiting_for_keyframe && packet.kind != MediaKind::Audio {
+                            if !packet.is_keyframe() {
+                                metrics.packets_dropped.fetch_add(1, Ordering::Relaxed);
+                                continue;
+                            }
+                            waiting_for_keyframe = false;
+                        }
+                        if !connection.send_packet(packet, &metrics).await? {
+                            if !waiting_for_keyframe {
+                                warn!("destination is not keeping up, skipping to the next keyframe");
+                            }
+                            waiting_for_keyframe = true;
+                        }
+                    }
+                    Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                        warn!("fell {} packets behind the stream, skipping to the next keyframe", skipped);
+                        metrics.packets_dropped.fetch_add(skipped, Ordering::Relaxed);
+                        waiting_for_keyframe = true;
+                    }
+                    Err(broadcast::error::RecvError::Closed) => break,
+                },
+                res = connection.read() => {
+                    res?;
+                    for event in connection.handle_input().await? {
+                        debug!("destination sent {:?}", event);
+                    }
+                }
+                _ = abort.cancelled() => {
+                    debug!("egress cancelled");
+                    return Ok(());
+                }
+            }
+        }
+
+        let results = connection.session.stop_publishing()?;
+        connection.handle_results(results).await?;
+        if let Err(e) = connection.finish().await {
+            debug!("could not flush the last packets: {}", e);
+        }
+        Ok(())
+    }
+}
+
+/// Packets that arrived while the destination was unreachable, trimmed to the
+/// last `window` worth and always starting at a keyframe, so that they can be
+/// replayed as-is once we reconnect
+struct CatchUpBuffer {
+    window: Duration,
+    packets: VecDeque<MediaPacket>,
+}
+
+impl CatchUpBuffer {
+    fn new(window: Duration) -> Self {
+        Self {
+            window,
+            packets: VecDeque::new(),
+        }
+    }
+
+    fn push(&mut self, packet: MediaPacket, metrics: &EgressMetrics) {
+        if self.window.is_zero() || (self.packets.is_empty() && !packet.is_keyframe()) {
+            metrics.packets_dropped.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+        self.packets.push_back(packet);
+
+        let window = self.window.as_millis() as u32;
+        while self.span() > window {
+            // drop a whole keyframe interval, since what's left has to start at one
+            self.packets.pop_front();
+            metrics.packets_dropped.fetch_add(1, Ordering::Relaxed);
+            while matches!(self.packets.front(), Some(packet) if !packet.is_keyframe()) {
+                self.packets.pop_front();
+                metrics.packets_dropped.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+
+    /// Milliseconds between the first and last packet
+    fn span(&self) -> u32 {
+        match (self.packets.front(), self.packets.back()) {
+            (Some(first), Some(last)) => last.timestamp.wrapping_sub(first.timestamp),
+            _ => 0,
+        }
+    }
+
+    fn clear(&mut self, metrics: &EgressMetrics) {
+        metrics
+            .packets_dropped
+            .fetch_add(self.packets.len() as u64, Ordering::Relaxed);
+        self.packets.clear();
+    }
+
+    fn take(&mut self) -> VecDeque<MediaPacket> {
+        std::mem::take(&mut self.packets)
+    }
+
+    fn len(&self) -> usize {
+        self.packets.len()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.packets.is_empty()
+    }
+}
+
+/// The client side of an RTMP connection to a destination
+struct EgressConnection {
+    reader: OwnedReadHalf,
+    /// Feeds the socket writer task
+    outbound: mpsc::Sender<Vec<u8>>,
+    writer_task: JoinHandle<std::io::Result<()>>,
+    session: ClientSession,
+    /// What the session chunks outgoing messages at, for the ones we have
+    /// to serialize ourselves
+    chunk_size: u32,
+    read_buf: Vec<u8>,
+}
+
+impl EgressConnection {
+    /// Connect to `destination` and wait for it to accept our publish request
+    async fn publish(destination: &Destination) -> Result<Self, EgressError> {
+        let socket = TcpStream::connect((destination.host.as_str(), destination.port)).await?;
+        socket.set_nodelay(true)?;
+        let (mut reader, mut writer) = socket.into_split();
+        let remaining_bytes = perform_handshake(&mut reader, &mut writer).await?;
+
+        let mut config = ClientSessionConfig::new();
+        config.tc_url = Some(destination.url.clone());
+        let chunk_size = config.chunk_size;
+        let (session, results) = ClientSession::new(config)?;
+        let (outbound, writer_task) = spawn_socket_writer(writer);
+        let mut connection = Self {
+            reader,
+            outbound,
+            writer_task,
+            session,
+            chunk_size,
+            read_buf: Vec::with_capacity(READ_BUFFER_SIZE),
+        };
+        connection.handle_results(results).await?;
+        let results = connection.session.handle_input(&remaining_bytes)?;
+        connection.handle_results(results).await?;
+
+        let result = connection
+            .session
+            .request_connection(destination.app.clone())?;
+        connection.handle_results(vec![result]).await?;
+        connection
+            .wait_for(|event| matches!(event, ClientSessionEvent::ConnectionRequestAccepted))
+            .await?;
+
+        let result = connection
+            .session
+            .request_publishing(destination.stream_key.clone(), PublishRequestType::Live)?;
+        connection.handle_results(vec![result]).await?;
+        connection
+            .wait_for(|event| matches!(event, ClientSessionEvent::PublishRequestAccepted))
+            .await?;
+
+        Ok(connection)
+    }
+
+    /// Read until the destination raises an event that satisfies `accepted`,
+    /// or turns us down
+    async fn wait_for(
+        &mut self,
+        accepted: impl Fn(&ClientSessionEvent) -> bool,
+    ) -> Result<(), EgressError> {
+        loop {
+            self.read().await?;
+            for event in self.handle_input().await? {
+                if accepted(&event) {
+                    return Ok(());
+                }
+                match event {
+                    ClientSessionEvent::ConnectionRequestRejected { description } => {
+                        return Err(EgressError::Rejected(description))
+                    }
+                    // failed publishes show up as a status code the session doesn't know about
+                    ClientSessionEvent::UnhandleableOnStatusCode { code } => {
+                        return Err(EgressError::Rejected(code))
+                    }
+                    event => debug!("destination sent {:?} while setting up", event),
+                }
+            }
+        }
+    }
+
+    /// Read whatever the destination sent next. Cancel safe.
+    async fn read(&mut self) -> Result<(), EgressError> {
+        self.read_buf.clear();
+        if self.reader.read_buf(&mut self.read_buf).await? == 0 {
+            return Err(EgressError::Closed);
+        }
+        Ok(())
+    }
+
+    /// Answer what [`Self::read`] got, returning the events it raised
+    async fn handle_input(&mut self) -> Result<Vec<ClientSessionEvent>, EgressError> {
+        let results = self.session.handle_input(&self.read_buf)?;
+        self.handle_results(results).await
+    }
+
+    /// Queue any outbound packets, returning the events. Protocol messages are
+    /// never dropped, so this waits for room in the send queue.
+    async fn handle_results(
+        &mut self,
+        results: Vec<ClientSessionResult>,
+    ) -> Result<Vec<ClientSessionEvent>, EgressError> {
+        let mut events = Vec::new();
+        for result in results {
+            match result {
+                ClientSessionResult::OutboundResponse(packet) => {
+                    if self.outbound.send(packet.bytes).await.is_err() {
+                        return Err(writer_error(&mut self.writer_task).await);
+                    }
+                }
+                ClientSessionResult::RaisedEvent(event) => events.push(event),
+                ClientSessionResult::UnhandleableMessageReceived(payload) => debug!(
+                    "destination sent an unhandleable message (type id {})",
+                    payload.type_id
+                ),
+            }
+        }
+        Ok(events)
+    }
+
+    /// Queue a media packet. Returns `false` if the send queue was full and
+    /// the packet had to be dropped. Sequence headers are never dropped.
+    async fn send_packet(
+        &mut self,
+        packet: MediaPacket,
+        metrics: &EgressMetrics,
+    ) -> Result<bool, EgressError> {
+        // The session compresses each chunk header against the one before it,
+        // so once a packet is serialized it has to be sent. Reserve room first.
+        let permit = if packet.is_sequence_header() {
+            self.outbound.reserve().await.map_err(|_| ())
+        } else {
+            match self.outbound.try_reserve() {
+                Ok(permit) => Ok(permit),
+                Err(mpsc::error::TrySendError::Full(())) => {
+                    metrics.packets_dropped.fetch_add(1, Ordering::Relaxed);
+                    return Ok(false);
+                }
+                Err(mpsc::error::TrySendError::Closed(())) => Err(()),
+            }
+        };
+        let permit = match permit {
+            Ok(permit) => permit,
+            Err(()) => return Err(writer_error(&mut self.writer_task).await),
+        };
+
+        let timestamp = RtmpTimestamp::new(packet.timestamp);
+        let can_be_dropped = !packet.is_keyframe() && !packet.is_sequence_header();
+        let result = match packet.kind {
+            MediaKind::Video => {
+                self.session
+                    .publish_video_data(packet.data, timestamp, can_be_dropped)?
+            }
+            MediaKind::Audio => {
+                self.session
+                    .publish_audio_data(packet.data, timestamp, can_be_dropped)?
+            }
+            MediaKind::Data => ClientSessionResult::OutboundResponse(Packet {
+                bytes: region_metadata::rtmp_data_message(
+                    PUBLISH_STREAM_ID,
+                    packet.timestamp,
+                    &packet.data,
+                    self.chunk_size,
+                ),
+                can_be_dropped: true,
+            }),
+        };
+        match result {
+            ClientSessionResult::OutboundResponse(packet) => {
+                metrics.packets_sent.fetch_add(1, Ordering::Relaxed);
+                metrics
+                    .bytes_sent
+                    .fetch_add(packet.bytes.len() as u64, Ordering::Relaxed);
+                permit.send(packet.bytes);
+            }
+            // publishing media only ever produces the packet to send
+            other => debug!("publishing a packet gave us {:?}", other),
+        }
+        Ok(true)
+    }
+
+    /// Let the writer task send whatever is still queued, then shut the socket
+    async fn finish(self) -> Result<(), EgressError> {
+        drop(self.outbound);
+        match timeout(WRITE_TIMEOUT, self.writer_task).await {
+            Ok(Ok(res)) => Ok(res?),
+            Ok(Err(_)) => Err(EgressError::Closed),
+            Err(_) => Err(std::io::Error::from(std::io::ErrorKind::TimedOut).into()),
+        }
+    }
+}
+
+/// Why the writer task stopped taking bytes
+async fn writer_error(writer_task: &mut JoinHandle<std::io::Result<()>>) -> EgressError {
+    match writer_task.await {
+        Ok(Err(e)) => e.into(),
+        _ => EgressError::Closed,
+    }
+}
+
+/// Writes to the socket on its own task, so that reading the destination's
+/// acknowledgements never waits on a full socket
+fn spawn_socket_writer(
+    mut writer: OwnedWriteHalf,
+) -> (mpsc::Sender<Vec<u8>>, JoinHandle<std::io::Result<()>>) {
+    let (tx, mut rx) = mpsc::channel::<Vec<u8>>(SEND_QUEUE_LEN);
+    let task = tokio::spawn(async move {
+        while let Some(bytes) = rx.recv().await {
+            match timeout(WRITE_TIMEOUT, writer.write_all(&bytes)).await {
+                Ok(res) => res?,
+                Err(_) => {
+                    warn!(
+                        "destination has not taken any bytes for {:?}",
+                        WRITE_TIMEOUT
+                    );
+                    return Err(std::io::ErrorKind::TimedOut.into());
+                }
+            }
+        }
+        writer.shutdown().await
+    });
+    (tx, task)
+}
+
+/// Drive the client side of the RTMP handshake. Returns the bytes the server
+/// sent after the handshake, which belong to the session.
+async fn perform_handshake(
+    reader: &mut OwnedReadHalf,
+    writer: &mut OwnedWriteHalf,
+) -> Result<Vec<u8>, EgressError> {
+    let mut handshake = Handshake::new(PeerType::Client);
+    writer
+        .write_all(&handshake.generate_outbound_p0_and_p1()?)
+        .await?;
+
+    let mut buf = Vec::with_capacity(READ_BUFFER_SIZE);
+    loop {
+        buf.clear();
+        if reader.read_buf(&mut buf).await? == 0 {
+            return Err(EgressError::Closed);
+        }
+        match handshake.process_bytes(&buf)? {
+            HandshakeProcessResult::InProgress { response_bytes } => {
+                writer.write_all(&response_bytes).await?;
+            }
+            HandshakeProcessResult::Completed {
+                response_bytes,
+                remaining_bytes,
+            } => {
+                writer.write_all(&response_bytes).await?;
+                return Ok(remaining_bytes);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+
+    use super::*;
+    use crate::stream_registry::{StreamName, StreamRegistry};
+
+    fn destination() -> Destination {
+        Destination {
+            url: "rtmp://example.com/live".to_owned(),
+            host: "example.com".to_owned(),
+            port: 1935,
+            app: "live".to_owned(),
+            stream_key: "cam".to_owned(),
+        }
+    }
+
+    fn metrics() -> Arc<EgressMetrics> {
+        Metrics::new().register_egress(&StreamName::new("live", "cam"), &destination())
+    }
+
+    fn dropped(metrics: &EgressMetrics) -> u64 {
+        metrics.packets_dropped.load(Ordering::Relaxed)
+    }
+
+    fn video(timestamp: u32, keyframe: bool) -> MediaPacket {
+        MediaPacket {
+            kind: MediaKind::Video,
+            timestamp,
+            data: Bytes::from_static(if keyframe { &[0x17, 1] } else { &[0x27, 1] }),
+        }
+    }
+
+    fn timestamps(backlog: &mut CatchUpBuffer) -> Vec<u32> {
+        backlog
+            .take()
+            .into_iter()
+            .map(|packet| packet.timestamp)
+            .collect()
+    }
+
+    #[test]
+    fn catching_up_starts_at_a_keyframe() {
+        let metrics = metrics();
+        let mut backlog = CatchUpBuffer::new(Duration::from_secs(10));
+        backlog.push(video(0, false), &metrics);
+        backlog.push(video(40, false), &metrics);
+        assert!(backlog.is_empty());
+        assert_eq!(dropped(&metrics), 2);
+
+        backlog.push(video(80, true), &metrics);
+        backlog.push(video(120, false), &metrics);
+        assert_eq!(timestamps(&mut backlog), [80, 120]);
+        assert_eq!(dropped(&metrics), 2);
+    }
+
+    #[test]
+    fn catching_up_drops_whole_keyframe_intervals() {
+        let metrics = metrics();
+        let mut backlog = CatchUpBuffer::new(Duration::from_millis(100));
+        // a keyframe every 50ms
+        for timestamp in (0..=100).step_by(25) {
+            backlog.push(video(timestamp, timestamp % 50 == 0), &metrics);
+        }
+        assert_eq!(backlog.len(), 5, "100ms fits");
+        assert_eq!(dropped(&metrics), 0);
+
+        backlog.push(video(125, false), &metrics);
+        assert_eq!(timestamps(&mut backlog), [50, 75, 100, 125]);
+        assert_eq!(dropped(&metrics), 2);
+    }
+
+    #[test]
+    fn catching_up_drops_a_keyframe_interval_longer_than_the_window() {
+        let metrics = metrics();
+        let mut backlog = CatchUpBuffer::new(Duration::from_millis(100));
+        for timestamp in (0..=150).step_by(25) {
+            backlog.push(video(timestamp, timestamp == 0), &metrics);
+        }
+        assert!(backlog.is_empty(), "nothing left starts at a keyframe");
+        assert_eq!(dropped(&metrics), 7);
+    }
+
+    #[test]
+    fn no_window_drops_everything() {
+        let metrics = metrics();
+        let mut backlog = CatchUpBuffer::new(Duration::ZERO);
+        backlog.push(video(0, true), &metrics);
+        backlog.push(video(40, false), &metrics);
+        assert!(backlog.is_empty());
+        assert_eq!(dropped(&metrics), 2);
+    }
+
+    #[test]
+    fn clearing_counts_what_was_dropped() {
+        let metrics = metrics();
+        let mut backlog = CatchUpBuffer::new(Duration::from_secs(10));
+        backlog.push(video(0, true), &metrics);
+        backlog.push(video(40, false), &metrics);
+        backlog.clear(&metrics);
+        assert!(backlog.is_empty());
+        assert_eq!(dropped(&metrics), 2);
+    }
+
+    #[tokio::test]
+    async fn falling_behind_the_stream_clears_the_backlog() {
+        let registry = StreamRegistry::new();
+        let guard = registry
+            .start_publishing(StreamName::new("live", "cam"))
+            .unwrap();
+        let (_, packets) = guard.stream().subscribe();
+        let metrics = metrics();
+        let mut egress = Egress {
+            stream: Arc::downgrade(guard.stream()),
+            packets,
+            destination: destination(),
+            metrics: metrics.clone(),
+            backlog: CatchUpBuffer::new(Duration::from_secs(3600)),
+        };
+        egress.backlog.push(video(0, true), &metrics);
+        egress.backlog.push(video(40, false), &metrics);
+
+        // more than the stream buffers for a subscriber, none of it keyframes
+        let sent = 5000;
+        for i in 0..sent {
+            guard.stream().send(video(80 + i * 40, false));
+        }
+        let abort = Shutdown::new();
+        egress
+            .keep_up_until(tokio::time::sleep(Duration::from_millis(100)), &abort)
+            .await
+            .unwrap();
+
+        assert!(egress.backlog.is_empty());
+        assert_eq!(dropped(&metrics), 2 + sent as u64);
+    }
+}
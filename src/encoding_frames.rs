@@ -0,0 +1,529 @@
+//! Turns blurred frames back into video and hands the result to a [`LiveStream`]
+//! as RTMP-ready video packets.
+//!
+//! For H.264, rather than building FLV video tags ourselves, we let ffmpeg's
+//! `flv` muxer do it (it knows how to turn x264's output into an AVC sequence
+//! header and length-prefixed NALUs), and split what it writes back into tags.
+//! HEVC and AV1 need Enhanced RTMP tags, which that muxer can't write in
+//! ffmpeg 5, so those are built with [`crate::video_tags`].
+//!
+//! If asked to, the regions each frame had blurred go out with it, see
+//! [`crate::region_metadata`].
+
+use std::{
+    ptr, slice,
+    sync::{mpsc::Receiver, Arc, Mutex},
+    thread::{self, JoinHandle},
+};
+
+use ffmpeg::sys as ffmpeg_c;
+use ffmpeg::{codec, encoder, format, frame, util::format::Pixel, Dictionary, Packet, Rational};
+use ffmpeg_next as ffmpeg;
+use rml_rtmp::sessions::StreamMetadata;
+use tracing::{debug, info, span, warn, Level};
+
+use crate::{
+    config::{OutputCodec, VideoConfig},
+    custom_ffmpeg_io::{write_to_custom_output, CustomFFMpegWrite, CustomOutput},
+    flv_file::{FLVTagReader, FLVTagType},
+    image_processing::Detection,
+    pipeline, region_metadata,
+    rescaling::Rescaler,
+    shutdown::Shutdown,
+    stream_registry::{LiveStream, MediaKind, MediaPacket},
+    video_tags::{self, VideoCodec, VideoPacketType, VideoTag},
+};
+
+/// Same as the frames', so their timestamps carry straight over
+const ENCODER_TIME_BASE: Rational = pipeline::TIME_BASE;
+
+/// What rate control assumes if the publisher doesn't say. Without one it
+/// would go by the time base, i.e. 1000 fps.
+const DEFAULT_FRAME_RATE: Rational = Rational(30, 1);
+
+/// Receives whatever the `flv` muxer writes and forwards the video tags
+struct LiveStreamWriter {
+    tags: FLVTagReader,
+    stream: Arc<LiveStream>,
+}
+
+impl CustomFFMpegWrite for LiveStreamWriter {
+    fn write(&mut self, buf: &[u8]) -> Result<u32, ffmpeg::Error> {
+        self.tags.push_bytes(buf);
+        loop {
+            match self.tags.next_tag() {
+                Ok(Some(tag)) if tag.tag_type == FLVTagType::Video => {
+                    self.stream.send(MediaPacket {
+                        kind: MediaKind::Video,
+                        timestamp: tag.timestamp,
+                        data: tag.data,
+                    });
+                }
+                // the muxer's onMetaData knows less than the publisher's, so drop it
+                Ok(Some(_)) => (),
+                Ok(None) => break,
+                Err(e) => {
+                    warn!("could not parse what the flv muxer wrote: {}", e);
+                    return Err(ffmpeg::Error::InvalidData);
+                }
+            }
+        }
+        Ok(buf.len() as u32)
+    }
+}
+
+/// An opened encoder and where its packets go
+struct FrameEncoder {
+    encoder: encoder::video::Encoder,
+    output: EncodedOutput,
+    /// RGB from the blurrer -> YUV for the encoder. If the publisher changes
+    /// resolution, this scales back to the size we started with, so nothing
+    /// downstream has to cope with a change.
+    scaler: Rescaler,
+    stream: Arc<LiveStream>,
+    /// Send an `onCuePoint` ahead of every frame that had faces in it
+    cue_points: bool,
+    /// Have the encoder put the regions into the frame as SEI
+    sei: bool,
+}
+
+enum EncodedOutput {
+    /// H.264 goes through the `flv` muxer into a [`LiveStreamWriter`]
+    Muxer {
+        output: CustomOutput<LiveStreamWriter>,
+        output_time_base: Rational,
+    },
+    /// Everything else is turned into Enhanced RTMP tags by hand
+    Enhanced { codec: VideoCodec },
+}
+
+impl FrameEncoder {
+    /// With `embed_regions`, regions go out as cue points, and as SEI too if
+    /// the output is H.264. `frame_rate` is only for rate control, the frames
+    /// keep their own timestamps.
+    fn new(
+        stream: Arc<LiveStream>,
+        codec: VideoCodec,
+        width: u32,
+        height: u32,
+        frame_rate: Rational,
+        embed_regions: bool,
+    ) -> Result<Self, ffmpeg::Error> {
+        let sei = embed_regions && codec == VideoCodec::Avc;
+        let (encoder, output) = match codec {
+            VideoCodec::Avc => open_h264(stream.clone(), width, height, frame_rate, sei)?,
+            VideoCodec::Hevc | VideoCodec::Av1 | VideoCodec::Vp9 => {
+                let encoder = open_enhanced(codec, width, height, frame_rate)?;
+                let sequence_start = VideoTag {
+                    codec,
+                    enhanced: true,
+                    keyframe: true,
+                    packet_type: VideoPacketType::SequenceStart,
+                    composition_time: 0,
+                    payload: decoder_configuration(codec, extradata(&encoder))
+                        .ok_or(ffmpeg::Error::InvalidData)?,
+                };
+                stream.send(MediaPacket {
+                    kind: MediaKind::Video,
+                    timestamp: 0,
+                    data: sequence_start.to_enhanced_bytes(),
+                });
+                (encoder, EncodedOutput::Enhanced { codec })
+            }
+        };
+
+        let scaler = Rescaler::with_size(Pixel::YUV420P, width, height, "blurred frames");
+
+        Ok(Self {
+            encoder,
+            output,
+            scaler,
+            stream,
+            cue_points: embed_regions,
+            sei,
+        })
+    }
+
+    /// `rgb_frame` keeps its PTS. `detections` are the faces found in it.
+    fn encode(
+        &mut self,
+        rgb_frame: &frame::Video,
+        detections: &[Detection],
+    ) -> Result<(), ffmpeg::Error> {
+        let mut yuv_frame = self.scaler.run(rgb_frame)?;
+
+        if !detections.is_empty() {
+            let pts = yuv_frame.pts().unwrap_or(0);
+            if self.cue_points {
+                // same timestamp as the frame's video tag, since none of
+                // the encoders reorder frames (see `open_h264` and
+                // `open_enhanced`), so every frame's DTS is its PTS
+                self.stream.send(MediaPacket {
+                    kind: MediaKind::Data,
+                    timestamp: pts.max(0) as u32,
+                    data: region_metadata::cue_point(pts, detections),
+                });
+            }
+            if self.sei {
+                attach_sei(
+                    &mut yuv_frame,
+                    &region_metadata::sei_payload(pts, detections),
+                )?;
+            }
+        }
+
+        self.encoder.send_frame(&yuv_frame)?;
+        self.write_encoded_packets()
+    }
+
+    /// Drain the encoder and write the trailer
+    fn finish(mut self) -> Result<(), ffmpeg::Error> {
+        self.encoder.send_eof()?;
+        self.write_encoded_packets()?;
+        match &mut self.output {
+            EncodedOutput::Muxer { output, .. } => output.write_trailer(),
+            EncodedOutput::Enhanced { .. } => Ok(()),
+        }
+    }
+
+    fn write_encoded_packets(&mut self) -> Result<(), ffmpeg::Error> {
+        let mut packet = Packet::empty();
+        while self.encoder.receive_packet(&mut packet).is_ok() {
+            match &mut self.output {
+                EncodedOutput::Muxer {
+                    output,
+                    output_time_base,
+                } => {
+                    packet.set_stream(0);
+                    packet.rescale_ts(ENCODER_TIME_BASE, *output_time_base);
+                    packet.write_interleaved(output)?;
+                }
+                EncodedOutput::Enhanced { codec } => {
+                    send_enhanced_packet(&self.stream, *codec, &packet);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// `sei` makes x264 write the SEI side data of each frame into the stream
+fn open_h264(
+    stream: Arc<LiveStream>,
+    width: u32,
+    height: u32,
+    frame_rate: Rational,
+    sei: bool,
+) -> Result<(encoder::video::Encoder, EncodedOutput), ffmpeg::Error> {
+    let codec = encoder::find(codec::Id::H264).ok_or(ffmpeg::Error::EncoderNotFound)?;
+    let mut output = write_to_custom_output(
+        LiveStreamWriter {
+            tags: FLVTagReader::new(),
+            stream,
+        },
+        "flv",
+    )?;
+    let global_header = output
+        .format()
+        .flags()
+        .contains(format::Flags::GLOBAL_HEADER);
+
+    let mut ost = output.add_stream(codec)?;
+    let encoder = configure_video_encoder(
+        width,
+        height,
+        ENCODER_TIME_BASE,
+        Some(frame_rate),
+        global_header,
+    )?;
+    let mut options = Dictionary::new();
+    options.set("preset", "veryfast");
+    options.set("tune", "zerolatency");
+    if sei {
+        options.set("udu_sei", "1");
+    }
+    let encoder = encoder.open_as_with(codec, options)?;
+    ost.set_parameters(&encoder);
+
+    output.write_header()?;
+    let output_time_base = output.stream(0).unwrap().time_base();
+    Ok((
+        encoder,
+        EncodedOutput::Muxer {
+            output,
+            output_time_base,
+        },
+    ))
+}
+
+/// Opens an encoder for one of the codecs that need Enhanced RTMP, tuned for
+/// latency like x264 is, so none of them reorder frames either
+fn open_enhanced(
+    codec: VideoCodec,
+    width: u32,
+    height: u32,
+    frame_rate: Rational,
+) -> Result<encoder::video::Encoder, ffmpeg::Error> {
+    let mut options = Dictionary::new();
+    let encoder_codec = match codec {
+        VideoCodec::Hevc => {
+            options.set("preset", "veryfast");
+            options.set("tune", "zerolatency");
+            encoder::find_by_name("libx265")
+        }
+        VideoCodec::Av1 => match encoder::find_by_name("libsvtav1") {
+            Some(svt) => {
+                options.set("preset", "10");
+                // low delay, its default random access structure reorders frames
+                options.set("svtav1-params", "pred-struct=1");
+                Some(svt)
+            }
+            None => {
+                options.set("usage", "realtime");
+                options.set("cpu-used", "8");
+                encoder::find_by_name("libaom-av1")
+            }
+        },
+        VideoCodec::Avc | VideoCodec::Vp9 => None,
+    }
+    .ok_or(ffmpeg::Error::EncoderNotFound)?;
+
+    // the configuration record is built from the global header, so ask for one
+    let encoder =
+        configure_video_encoder(width, height, ENCODER_TIME_BASE, Some(frame_rate), true)?;
+    encoder.open_as_with(encoder_codec, options)
+}
+
+/// The encoder's global header, i.e. Annex B parameter sets for x265 and a
+/// sequence header OBU for the AV1 encoders
+fn extradata(encoder: &encoder::video::Encoder) -> &[u8] {
+    unsafe {
+        let raw = encoder.as_ptr();
+        if (*raw).extradata.is_null() || (*raw).extradata_size <= 0 {
+            return &[];
+        }
+        slice::from_raw_parts((*raw).extradata, (*raw).extradata_size as usize)
+    }
+}
+
+fn decoder_configuration(codec: VideoCodec, extradata: &[u8]) -> Option<bytes::Bytes> {
+    match codec {
+        VideoCodec::Hevc => video_tags::hevc_decoder_configuration(extradata),
+        VideoCodec::Av1 => video_tags::av1_decoder_configuration(extradata),
+        VideoCodec::Avc | VideoCodec::Vp9 => None,
+    }
+}
+
+/// Hand `payload` to the encoder as user data unregistered SEI for `frame`
+fn attach_sei(frame: &mut frame::Video, payload: &[u8]) -> Result<(), ffmpeg::Error> {
+    unsafe {
+        let side_data = ffmpeg_c::av_frame_new_side_data(
+            frame.as_mut_ptr(),
+            ffmpeg_c::AVFrameSideDataType::AV_FRAME_DATA_SEI_UNREGISTERED,
+            payload.len() as _,
+        );
+        if side_data.is_null() {
+            return Err(ffmpeg::Error::Other {
+                errno: libc::ENOMEM,
+            });
+        }
+        ptr::copy_nonoverlapping(payload.as_ptr(), (*side_data).data, payload.len());
+    }
+    Ok(())
+}
+
+/// Wrap an encoded packet in an Enhanced RTMP tag and send it out
+fn send_enhanced_packet(stream: &LiveStream, codec: VideoCodec, packet: &Packet) {
+    let data = match packet.data() {
+        Some(data) => data,
+        None => return,
+    };
+    let payload = match codec {
+        VideoCodec::Av1 => video_tags::strip_av1_temporal_delimiters(data),
+        _ => video_tags::annex_b_to_length_prefixed(data),
+    };
+    // the encoder's time base is already milliseconds
+    let dts = packet.dts().or_else(|| packet.pts()).unwrap_or(0);
+    let pts = packet.pts().unwrap_or(dts);
+    let tag = VideoTag {
+        codec,
+        enhanced: true,
+        keyframe: packet.is_key(),
+        packet_type: VideoPacketType::CodedFrames,
+        composition_time: (pts - dts) as i32,
+        payload,
+    };
+    stream.send(MediaPacket {
+        kind: MediaKind::Video,
+        timestamp: dts.max(0) as u32,
+        data: tag.to_enhanced_bytes(),
+    });
+}
+
+/// Which codec to encode in, given what the publisher sent
+fn output_codec(output: OutputCodec, ingest: Option<VideoCodec>) -> VideoCodec {
+    match (output, ingest) {
+        (OutputCodec::Same, Some(codec @ (VideoCodec::Hevc | VideoCodec::Av1))) => codec,
+        (OutputCodec::Same, Some(VideoCodec::Vp9)) => {
+            warn!("we can't encode VP9 yet, falling back to H.264");
+            VideoCodec::Avc
+        }
+        _ => VideoCodec::Avc,
+    }
+}
+
+/// The frame rate the publisher's `onMetaData` gives, to the nearest
+/// thousandth of a frame per second
+fn frame_rate(metadata: Option<&StreamMetadata>) -> Option<Rational> {
+    let fps = metadata?.video_frame_rate?;
+    if !(fps > 0.0 && fps <= 1000.0) {
+        return None;
+    }
+    Some(Rational::new((fps * 1000.0).round() as i32, 1000))
+}
+
+/// Everything about a video encoder except the codec and the options it is
+/// opened with, which depend on what it is for and whether anyone is waiting
+/// for the output.
+///
+/// `frame_rate` is what rate control goes by if known; otherwise encoders
+/// take it to be one frame per tick of `time_base`. `global_header` is for
+/// muxers that want the SPS/PPS up front (see [`format::Flags::GLOBAL_HEADER`])
+/// rather than in the stream.
+pub fn configure_video_encoder(
+    width: u32,
+    height: u32,
+    time_base: Rational,
+    frame_rate: Option<Rational>,
+    global_header: bool,
+) -> Result<encoder::video::Video, ffmpeg::Error> {
+    let mut encoder = codec::context::Context::new().encoder().video()?;
+    encoder.set_width(width);
+    encoder.set_height(height);
+    encoder.set_format(Pixel::YUV420P);
+    encoder.set_time_base(time_base);
+    encoder.set_frame_rate(frame_rate);
+    if global_header {
+        encoder.set_flags(codec::Flags::GLOBAL_HEADER);
+    }
+    Ok(encoder)
+}
+
+/// Encodes frames until the sender hangs up or `abort` fires, then flushes
+/// the encoder so the last frames still make it out.
+///
+/// `ingest_codec` is only looked at when [`OutputCodec::Same`] is asked for.
+pub fn start_encode_thread(
+    frame_receiver: Receiver<(frame::Video, Vec<Detection>)>,
+    stream: Arc<LiveStream>,
+    video: VideoConfig,
+    ingest_codec: Arc<Mutex<Option<VideoCodec>>>,
+    abort: Shutdown,
+) -> JoinHandle<()> {
+    thread::Builder::new()
+        .name("frame encoding thread".to_owned())
+        .spawn(move || {
+            let _span = span!(Level::TRACE, "encoding_frames", stream = %stream.name()).entered();
+
+            // we don't know the frame size until we've seen a frame
+            let mut frame_encoder: Option<FrameEncoder> = None;
+            let mut last_pts: Option<i64> = None;
+
+            for (mut frame, detections) in frame_receiver.iter() {
+                if abort.is_cancelled() {
+                    debug!("encode thread cancelled");
+                    return;
+                }
+
+                if frame_encoder.is_none() {
+                    // the first frame can only come after the first video message
+                    let codec = output_codec(video.codec, *ingest_codec.lock().unwrap());
+                    // onMetaData comes before the media, if it comes at all
+                    let frame_rate =
+                        frame_rate(stream.headers().metadata.as_ref()).unwrap_or_else(|| {
+                            debug!("the publisher didn't say what its frame rate is");
+                            DEFAULT_FRAME_RATE
+                        });
+                    match FrameEncoder::new(
+                        stream.clone(),
+                        codec,
+                        frame.width(),
+                        frame.height(),
+                        frame_rate,
+                        video.embed_regions,
+                    ) {
+                        Ok(new_encoder) => {
+                            info!(
+                                "encoding {}x{} {:?} output at {} fps",
+                                frame.width(),
+                                frame.height(),
+                                codec,
+                                f64::from(frame_rate)
+                            );
+                            frame_encoder = Some(new_encoder);
+                        }
+                        Err(e) => {
+                            warn!("could not set up the encoder: {}", e);
+                            return;
+                        }
+                    }
+                }
+                let frame_encoder = frame_encoder.as_mut().unwrap();
+
+                // encoders only take increasing timestamps, and a frame that
+                // lost its timestamp somewhere is best placed right after the last one
+                let pts = match (frame.pts(), last_pts) {
+                    (Some(pts), Some(last_pts)) if pts <= last_pts => {
+                        debug!("dropping a frame at {} that is not after {}", pts, last_pts);
+                        continue;
+                    }
+                    (Some(pts), _) => pts,
+                    (None, last_pts) => last_pts.map_or(0, |last_pts| last_pts + 1),
+                };
+                last_pts = Some(pts);
+                frame.set_pts(Some(pts));
+
+                if let Err(e) = frame_encoder.encode(&frame, &detections) {
+                    warn!("could not encode a frame: {}", e);
+                }
+            }
+
+            if let Some(frame_encoder) = frame_encoder {
+                if let Err(e) = frame_encoder.finish() {
+                    warn!("could not flush the encoder: {}", e);
+                }
+            }
+        })
+        .expect("failed to spawn thread")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn frame_rates_from_metadata() {
+        let cases = [
+            (Some(25.0), Some(Rational(25000, 1000))),
+            (Some(29.97), Some(Rational(29970, 1000))),
+            (Some(0.0), None),
+            (Some(-30.0), None),
+            (Some(f32::NAN), None),
+            (Some(f32::INFINITY), None),
+            (None, None),
+        ];
+        for (video_frame_rate, expected) in cases {
+            let metadata = StreamMetadata {
+                video_frame_rate,
+                ..StreamMetadata::new()
+            };
+            assert_eq!(
+                frame_rate(Some(&metadata)),
+                expected,
+                "{:?}",
+                video_frame_rate
+            );
+        }
+        assert_eq!(frame_rate(None), None);
+    }
+}
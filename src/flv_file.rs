@@ -1,17 +1,25 @@
+//! FLV in both directions: [`FLVWriterWrapper`] turns RTMP audio, video and
+//! script data messages into an FLV byte stream (e.g. for ffmpeg's `flv`
+//! demuxer), and [`FLVTagReader`] splits one back into tags.
+
 use std::io;
 use std::io::Write;
 
 use bytes::Bytes;
 
+/// Writes RTMP messages into `W` as FLV tags
 #[derive(Debug)]
 pub struct FLVWriterWrapper<W> {
     inner: W,
 }
 
 impl<W: Write> FLVWriterWrapper<W> {
+    /// Nothing is written until [`Self::write_header`]
     pub fn new(inner: W) -> Self {
         Self { inner }
     }
+    /// The file header, announcing both audio and video. Has to come before
+    /// any tags.
     pub fn write_header(&mut self) -> io::Result<()> {
         const HEADER_BYTES: &[u8] = &[
             b'F', b'L', b'V', 0x01, 0x05, 0x00, 0x00, 0x00, 0x09, 0x00, 0x00, 0x00, 0x00,
@@ -20,39 +28,52 @@ impl<W: Write> FLVWriterWrapper<W> {
         Ok(())
     }
 
+    /// The body of an RTMP video message
     pub fn write_video_bytes(&mut self, timestamp: u32, video_bytes: &Bytes) -> io::Result<()> {
+        self.write_tag(9, timestamp, video_bytes)
+    }
+
+    /// The body of an RTMP audio message
+    pub fn write_audio_bytes(&mut self, timestamp: u32, audio_bytes: &Bytes) -> io::Result<()> {
+        self.write_tag(8, timestamp, audio_bytes)
+    }
+
+    /// AMF0 script data, e.g. a cue point
+    pub fn write_script_bytes(&mut self, timestamp: u32, script_bytes: &Bytes) -> io::Result<()> {
+        self.write_tag(18, timestamp, script_bytes)
+    }
+
+    fn write_tag(&mut self, tag_type: u8, timestamp: u32, bytes: &Bytes) -> io::Result<()> {
         // Step 1: Write the header for this block
         {
-            let payload_size: [u8; 4] = (video_bytes.len() as u32).to_be_bytes();
+            let payload_size: [u8; 4] = (bytes.len() as u32).to_be_bytes();
             let timestamp_bytes: [u8; 4] = timestamp.to_be_bytes();
 
-            self.inner.write_all(&[9])?; // indicates this is a video
+            self.inner.write_all(&[tag_type])?;
             self.inner.write_all(&payload_size[1..])?;
             self.inner.write_all(&timestamp_bytes[1..])?;
             self.inner.write_all(&timestamp_bytes[..1])?;
             self.inner.write_all(&[0, 0, 0])?; // stream ID, TODO: maybe this should not be 0?
         }
 
-        // Step 2: Write the actual video bytes
-        self.inner.write_all(video_bytes.as_ref())?;
+        // Step 2: Write the actual bytes
+        self.inner.write_all(bytes.as_ref())?;
 
         // Step 3: Start writing the header for the next block
         {
-            let prev_tag_size = (11 + video_bytes.len()) as u32;
+            let prev_tag_size = (11 + bytes.len()) as u32;
             self.inner.write_all(&prev_tag_size.to_be_bytes())?;
         }
 
         Ok(())
     }
 
-    // TODO:: implement writing for audio
-
-    // TODO: implement writinhg for metadata
-
+    /// Flush `W`
     pub fn flush_inner(&mut self) -> io::Result<()> {
         self.inner.flush()
     }
 
+    /// Gives back `W`, without flushing it
     pub fn into_inner(self) -> W {
         self.inner
     }
@@ -63,6 +84,8 @@ impl<W: Write> FLVWriterWrapper<W> {
 use arrayvec::ArrayVec;
 use std::sync::mpsc::{SendError, Sender};
 
+/// Sends whatever is written to it down a channel, `BUF_SIZE` bytes at a
+/// time. A short chunk only goes out on flush.
 #[derive(Debug)]
 pub struct BufferedSenderWriter<const BUF_SIZE: usize> {
     sender: Sender<ArrayVec<u8, BUF_SIZE>>,
@@ -70,6 +93,7 @@ pub struct BufferedSenderWriter<const BUF_SIZE: usize> {
 }
 
 impl<const BUF_SIZE: usize> BufferedSenderWriter<BUF_SIZE> {
+    /// Writes fail once the receiver is gone
     pub fn new(sender: Sender<ArrayVec<u8, BUF_SIZE>>) -> Self {
         Self {
             sender,
@@ -106,3 +130,114 @@ impl<const BUF_SIZE: usize> Write for BufferedSenderWriter<BUF_SIZE> {
         }
     }
 }
+
+//-----
+
+use bytes::{Buf, BytesMut};
+
+/// Length of the `FLV` file header, including the first (always zero) previous tag size
+const FLV_HEADER_LEN: usize = 13;
+
+/// Length of the header in front of every tag
+const FLV_TAG_HEADER_LEN: usize = 11;
+
+/// What a tag holds
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FLVTagType {
+    /// An RTMP audio message
+    Audio,
+    /// An RTMP video message
+    Video,
+    /// AMF0 data, e.g. `onMetaData`
+    ScriptData,
+}
+
+impl FLVTagType {
+    fn from_byte(byte: u8) -> Option<Self> {
+        // the upper bits are reserved/filter flags
+        match byte & 0x1f {
+            8 => Some(Self::Audio),
+            9 => Some(Self::Video),
+            18 => Some(Self::ScriptData),
+            _ => None,
+        }
+    }
+}
+
+/// One tag from an FLV byte stream
+#[derive(Debug, Clone)]
+pub struct FLVTag {
+    /// What's in [`Self::data`]
+    pub tag_type: FLVTagType,
+    /// Milliseconds
+    pub timestamp: u32,
+    /// Exactly what RTMP carries in an audio/video message
+    pub data: Bytes,
+}
+
+/// Splits an FLV byte stream (e.g. whatever ffmpeg's `flv` muxer writes) back
+/// into tags. Bytes can be pushed in arbitrarily sized pieces.
+#[derive(Debug, Default)]
+pub struct FLVTagReader {
+    buffer: BytesMut,
+    seen_header: bool,
+}
+
+impl FLVTagReader {
+    /// Expects the FLV file header first
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// More of the stream, to come out of [`Self::next_tag`]
+    pub fn push_bytes(&mut self, bytes: &[u8]) {
+        self.buffer.extend_from_slice(bytes);
+    }
+
+    /// Returns the next complete tag, or `None` if we need more bytes first.
+    /// Tags of unknown types are skipped.
+    pub fn next_tag(&mut self) -> io::Result<Option<FLVTag>> {
+        if !self.seen_header {
+            if self.buffer.len() < FLV_HEADER_LEN {
+                return Ok(None);
+            }
+            if &self.buffer[..3] != b"FLV" {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "stream does not start with an FLV header",
+                ));
+            }
+            self.buffer.advance(FLV_HEADER_LEN);
+            self.seen_header = true;
+        }
+
+        loop {
+            if self.buffer.len() < FLV_TAG_HEADER_LEN {
+                return Ok(None);
+            }
+            let header = &self.buffer[..FLV_TAG_HEADER_LEN];
+            let payload_size = u32::from_be_bytes([0, header[1], header[2], header[3]]) as usize;
+            // the 4th timestamp byte holds the upper 8 bits
+            let timestamp = u32::from_be_bytes([header[7], header[4], header[5], header[6]]);
+            let tag_type = FLVTagType::from_byte(header[0]);
+
+            // the tag is followed by the size of the tag we just read
+            let total_len = FLV_TAG_HEADER_LEN + payload_size + 4;
+            if self.buffer.len() < total_len {
+                return Ok(None);
+            }
+
+            let mut tag_bytes = self.buffer.split_to(total_len);
+            tag_bytes.advance(FLV_TAG_HEADER_LEN);
+            tag_bytes.truncate(payload_size);
+
+            if let Some(tag_type) = tag_type {
+                return Ok(Some(FLVTag {
+                    tag_type,
+                    timestamp,
+                    data: tag_bytes.freeze(),
+                }));
+            }
+        }
+    }
+}
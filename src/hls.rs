@@ -0,0 +1,288 @@
+//! HLS, so the blurred output can be watched in a browser.
+//!
+//! Each stream gets a directory with a rolling playlist and its segments:
+//!
+//! ```text
+//! <directory>/<app>/<stream key>/index.m3u8
+//! ```
+//!
+//! which the admin server serves under `/hls/<app>/<stream key>/index.m3u8`.
+//! The segmenting itself is done by ffmpeg's hls muxer; all we do is feed it.
+
+use std::{
+    fs, io,
+    path::Path,
+    sync::{
+        mpsc::{channel, Receiver},
+        Arc,
+    },
+    thread::{self, JoinHandle},
+};
+
+use ffmpeg::Dictionary;
+use ffmpeg_next as ffmpeg;
+use tracing::{debug, info, span, warn, Level};
+
+use crate::{
+    config::{HlsConfig, HlsSegmentType},
+    flv_file::{BufferedSenderWriter, FLVWriterWrapper},
+    remux,
+    stream_registry::{CachedHeaders, LiveStream, MediaKind, MediaPacket},
+};
+
+/// How many packets can wait for the muxer before we start skipping to keyframes
+const HLS_QUEUE_LEN: usize = 1024;
+
+/// The name of each stream's playlist, next to its segments
+pub const PLAYLIST: &str = "index.m3u8";
+/// Only written for fMP4 segments
+const INIT_SEGMENT: &str = "init.mp4";
+const SEGMENT_PREFIX: &str = "segment_";
+
+/// Packages one stream for as long as it is live. Like [`crate::recording::Recorder`],
+/// dropping this waits for the stream to end and the playlist to be finished.
+#[derive(Debug)]
+pub struct HlsPackager {
+    thread: Option<JoinHandle<()>>,
+}
+
+impl HlsPackager {
+    /// Returns `None` if HLS is turned off
+    pub fn start(stream: &Arc<LiveStream>, config: &Arc<HlsConfig>) -> Option<Self> {
+        let directory = remux::stream_directory(config.directory.as_ref()?, stream.name());
+        let (headers, packets) = stream.subscribe();
+        let packets = remux::forward_packets(packets, HLS_QUEUE_LEN, "hls");
+
+        let name = stream.name().clone();
+        let config = config.clone();
+        let thread = thread::Builder::new()
+            .name("hls thread".to_owned())
+            .spawn(move || {
+                let _span = span!(Level::TRACE, "hls", stream = %name).entered();
+                match package(&directory, &config, headers, packets) {
+                    Ok(()) => debug!("finished {}", directory.join(PLAYLIST).display()),
+                    Err(e) => warn!("hls for {} stopped: {}", name, e),
+                }
+            })
+            .expect("failed to spawn thread");
+
+        Some(Self {
+            thread: Some(thread),
+        })
+    }
+}
+
+impl Drop for HlsPackager {
+    /// Blocks until the stream has ended, so don't drop this on an async worker
+    fn drop(&mut self) {
+        if let Some(thread) = self.thread.take() {
+            if thread.join().is_err() {
+                warn!("hls thread panicked");
+            }
+        }
+    }
+}
+
+fn package(
+    directory: &Path,
+    config: &HlsConfig,
+    headers: CachedHeaders,
+    packets: Receiver<MediaPacket>,
+) -> io::Result<()> {
+    clear_directory(directory)?;
+
+    let (flv_tx, flv_rx) = channel();
+    let playlist = directory.join(PLAYLIST);
+    let options = muxer_options(directory, config);
+    let remux_thread = thread::Builder::new()
+        .name("hls remuxing thread".to_owned())
+        .spawn(move || remux::remux_flv(flv_rx, &playlist, "hls", options))?;
+    info!("writing hls to {}", directory.display());
+
+    let mut input = FLVWriterWrapper::new(BufferedSenderWriter::<1024>::new(flv_tx));
+    let fed = feed(&mut input, headers, packets);
+    // closing the input lets ffmpeg write out the last segment
+    let flushed = input.flush_inner();
+    drop(input);
+    match remux_thread.join() {
+        Ok(Ok(())) => fed.and(flushed),
+        Ok(Err(e)) => Err(io::Error::new(io::ErrorKind::Other, e)),
+        Err(_) => Err(io::Error::new(
+            io::ErrorKind::Other,
+            "hls remuxing thread panicked",
+        )),
+    }
+}
+
+/// Write the stream into `input` as FLV, starting at the first keyframe, until
+/// the stream ends
+fn feed<W: io::Write>(
+    input: &mut FLVWriterWrapper<W>,
+    headers: CachedHeaders,
+    packets: Receiver<MediaPacket>,
+) -> io::Result<()> {
+    let mut video_sequence_header = headers.video_sequence_header;
+    let mut audio_sequence_header = headers.audio_sequence_header;
+    let mut first_timestamp = None;
+    let mut skipping_video = false;
+
+    input.write_header()?;
+    for packet in packets.iter() {
+        if let Some(codec) = remux::unremuxable_codec(&packet) {
+            if !skipping_video {
+                warn!(
+                    "hls can only package H.264, leaving out the {:?} video",
+                    codec
+                );
+                skipping_video = true;
+            }
+            continue;
+        }
+        if packet.is_sequence_header() {
+            match packet.kind {
+                MediaKind::Video => video_sequence_header = Some(packet.clone()),
+                MediaKind::Audio => audio_sequence_header = Some(packet.clone()),
+                MediaKind::Data => (),
+            }
+        }
+
+        // segments have to start on a keyframe, so the first one does too
+        let first_timestamp = match first_timestamp {
+            Some(timestamp) => timestamp,
+            None if packet.is_keyframe() && !packet.is_sequence_header() => {
+                for header in [&video_sequence_header, &audio_sequence_header]
+                    .into_iter()
+                    .flatten()
+                {
+                    remux::write_packet(input, 0, header)?;
+                }
+                *first_timestamp.insert(packet.timestamp)
+            }
+            None => continue,
+        };
+        remux::write_packet(
+            input,
+            packet.timestamp.saturating_sub(first_timestamp),
+            &packet,
+        )?;
+    }
+    Ok(())
+}
+
+fn muxer_options(directory: &Path, config: &HlsConfig) -> Dictionary<'static> {
+    let extension = config.segment_type.extension();
+    let segments = directory.join(format!("{}%05d.{}", SEGMENT_PREFIX, extension));
+
+    let mut options = Dictionary::new();
+    options.set("hls_time", &config.segment_secs.to_string());
+    options.set("hls_list_size", &config.playlist_len.to_string());
+    options.set("hls_flags", "delete_segments+independent_segments");
+    options.set("hls_segment_filename", &segments.to_string_lossy());
+    match config.segment_type {
+        HlsSegmentType::MpegTs => options.set("hls_segment_type", "mpegts"),
+        HlsSegmentType::Fmp4 => {
+            options.set("hls_segment_type", "fmp4");
+            options.set("hls_fmp4_init_filename", INIT_SEGMENT);
+        }
+    }
+    options
+}
+
+/// Make sure nobody gets served a playlist or segments left over from the last
+/// time this stream was live. Leaves anything else alone, in case the directory
+/// is shared with the recordings.
+fn clear_directory(directory: &Path) -> io::Result<()> {
+    fs::create_dir_all(directory)?;
+    for entry in fs::read_dir(directory)? {
+        let entry = entry?;
+        let file_name = entry.file_name();
+        let file_name = file_name.to_string_lossy();
+        let ours = file_name == PLAYLIST
+            || file_name == INIT_SEGMENT
+            || file_name.starts_with(SEGMENT_PREFIX);
+        if ours && entry.file_type()?.is_file() {
+            fs::remove_file(entry.path())?;
+        }
+    }
+    Ok(())
+}
+
+/// What to serve a file in an HLS directory as. `None` for anything that is
+/// not ours.
+pub fn content_type(path: &Path) -> Option<&'static str> {
+    match path.extension()?.to_str()? {
+        "m3u8" => Some("application/vnd.apple.mpegurl"),
+        "ts" => Some("video/mp2t"),
+        "m4s" => Some("video/iso.segment"),
+        "mp4" => Some("video/mp4"),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn clearing_leaves_recordings_alone() {
+        let directory = tempfile::tempdir().unwrap();
+        let ours = [
+            "index.m3u8",
+            "init.mp4",
+            "segment_00001.ts",
+            "segment_00002.m4s",
+        ];
+        let theirs = [
+            "live_cam_2022-06-01T12-00-00.flv",
+            "index.m3u8.bak",
+            "notes.txt",
+        ];
+        for name in ours.iter().chain(&theirs) {
+            fs::write(directory.path().join(name), b"").unwrap();
+        }
+        fs::create_dir(directory.path().join("segment_directory")).unwrap();
+
+        clear_directory(directory.path()).unwrap();
+
+        let mut remaining: Vec<_> = fs::read_dir(directory.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect();
+        remaining.sort();
+        assert_eq!(
+            remaining,
+            [
+                "index.m3u8.bak",
+                "live_cam_2022-06-01T12-00-00.flv",
+                "notes.txt",
+                "segment_directory"
+            ]
+        );
+    }
+
+    #[test]
+    fn clearing_creates_the_directory() {
+        let root = tempfile::tempdir().unwrap();
+        let directory = root.path().join("live").join("cam");
+        clear_directory(&directory).unwrap();
+        assert!(directory.is_dir());
+    }
+
+    #[test]
+    fn content_types() {
+        let cases = [
+            ("index.m3u8", Some("application/vnd.apple.mpegurl")),
+            ("segment_00001.ts", Some("video/mp2t")),
+            ("segment_00001.m4s", Some("video/iso.segment")),
+            ("init.mp4", Some("video/mp4")),
+            ("live_cam.flv", None),
+            ("notes.txt", None),
+            ("index", None),
+            ("m3u8", None),
+            ("", None),
+        ];
+        for (name, expected) in cases {
+            assert_eq!(content_type(Path::new(name)), expected, "{}", name);
+        }
+    }
+}
@@ -1,13 +1,27 @@
+//! Finding and anonymizing faces, by way of OpenCV on the C++ side.
+//!
+//! [`init_models`] has to be called before any faces are looked for with
+//! [`FaceDetector::Models`], and [`enroll_identities`] after it for anyone who
+//! shouldn't be blurred. [`BlurStage`] is the step of a
+//! [`Pipeline`](crate::pipeline::Pipeline) that does the blurring.
+
 use std::{
-    env::temp_dir,
-    io::Write,
-    os::unix::prelude::OsStrExt,
-    sync::mpsc::{channel, Receiver},
-    thread,
+    env::temp_dir, fmt, fs, io::Write, os::unix::prelude::OsStrExt, sync::Arc, time::Instant,
 };
 
+use anyhow::Context;
 use cxx::let_cxx_string;
 use ffmpeg_next::{format::pixel, frame};
+use tracing::{info, warn};
+
+use crate::{
+    audit::AuditLog,
+    config::{BlurStyle, WhitelistConfig},
+    controls::{Controls, Curtain, OperatorControls},
+    pipeline::FrameStage,
+    policy::StreamPolicy,
+    tracking::Tracker,
+};
 
 // these paths are relative to the current file
 const CAFFE_PROTOTXT: &[u8] = include_bytes!("../models/deploy.prototxt");
@@ -15,8 +29,79 @@ const CAFFE_MODEL: &[u8] =
     include_bytes!("../models/res10_300x300_ssd_iter_140000_fp16.caffemodel");
 const TORCH_MODEL: &[u8] = include_bytes!("../models/openface_nn4.small2.v1.t7");
 
+/// The face detector, see [`crate::audit`]
+pub const DETECTOR_MODEL: &str = "res10_300x300_ssd_iter_140000_fp16";
+/// The face embedder that enrolled identities are matched with
+pub const EMBEDDER_MODEL: &str = "openface_nn4.small2.v1";
+
+/// What every channel of a [`Curtain::Slate`] frame is set to
+const SLATE_GREY: u8 = 0x40;
+
 #[cxx::bridge(namespace=anonynews_rs)]
 mod ffi {
+    /// A face that was found, in pixels of the frame it was found in
+    #[derive(Debug, Clone)]
+    struct Detection {
+        /// Left edge
+        x: i32,
+        /// Top edge
+        y: i32,
+        /// Of the face's box
+        width: i32,
+        /// Of the face's box
+        height: i32,
+        /// How sure the detector is that this is a face, from 0 to 1
+        confidence: f32,
+        /// `false` if it matched an enrolled identity and was left alone
+        blurred: bool,
+        /// The enrolled identity it matched, empty if none did
+        identity: String,
+        /// How far its embedding was from the closest enrolled identity's,
+        /// negative if nobody is enrolled
+        distance: f32,
+    }
+
+    struct BlurResult {
+        pixels: UniquePtr<CxxVector<u8>>,
+        detections: Vec<Detection>,
+    }
+
+    /// See [`crate::config::BlurStyle`]
+    #[repr(u8)]
+    enum BlurStyle {
+        Blur,
+        Pixelate,
+        Fill,
+    }
+
+    /// See [`crate::policy::Zone`]
+    #[derive(Clone)]
+    struct Zone {
+        points: Vec<ZonePoint>,
+    }
+
+    #[derive(Clone)]
+    struct ZonePoint {
+        x: f32,
+        y: f32,
+    }
+
+    /// How to anonymize a frame
+    struct BlurOptions {
+        zones: Vec<Zone>,
+        style: BlurStyle,
+        /// Zones are anonymized either way
+        detect_faces: bool,
+        /// Even the ones that match an enrolled identity
+        blur_everyone: bool,
+        /// Otherwise faces are only looked for, for the debug overlay
+        anonymize: bool,
+        /// Run the models loaded by `init_models`, rather than taking
+        /// `given_faces` as found
+        use_models: bool,
+        given_faces: Vec<Detection>,
+    }
+
     extern "Rust" {}
     unsafe extern "C++" {
         include!("/usr/local/include/opencv4/opencv2/core.hpp");
@@ -34,7 +119,24 @@ mod ffi {
 
         fn loadFaceEmbedderNet(pathname: &CxxString) -> ();
 
-        fn blurFFMpegFrame(pngBuffer: &[u8]) -> UniquePtr<CxxVector<u8>>;
+        /// `false` if there is no face in `image`
+        fn enrollIdentity(name: &str, image: &[u8]) -> bool;
+
+        fn setMaxIdentityDistance(maxDistance: f32);
+
+        fn blurFFMpegFrame(pngBuffer: &[u8], options: &BlurOptions) -> BlurResult;
+
+        /// Draws onto an RGB frame in place
+        fn drawDebugOverlay(
+            pixels: &mut [u8],
+            width: i32,
+            height: i32,
+            stride: i32,
+            detections: &[Detection],
+            trackIds: &[u32],
+            zones: &[Zone],
+            processingMs: f32,
+        );
     }
 }
 
@@ -59,47 +161,325 @@ pub fn init_models() {
         .expect("could not load openface model into opencv");
 }
 
-pub fn frame_to_ppm_format(frame: frame::Video) -> Vec<u8> {
+/// Leave the faces of everyone in `config`'s directory alone, unless an
+/// operator says to blur everyone. Has to be called after [`init_models`] and
+/// before any frames are blurred. Returns how many people were enrolled.
+pub fn enroll_identities(config: &WhitelistConfig) -> anyhow::Result<usize> {
+    let directory = match &config.directory {
+        Some(directory) => directory,
+        None => return Ok(0),
+    };
+    ffi::setMaxIdentityDistance(config.max_distance);
+
+    let mut enrolled = 0;
+    let entries = fs::read_dir(directory)
+        .with_context(|| format!("could not read whitelist {}", directory.display()))?;
+    for entry in entries {
+        let path = entry?.path();
+        let extension = path
+            .extension()
+            .and_then(|extension| extension.to_str())
+            .map(str::to_ascii_lowercase);
+        let is_photo = matches!(extension.as_deref(), Some("jpg" | "jpeg" | "png"));
+        let name = match path.file_stem().and_then(|stem| stem.to_str()) {
+            Some(name) if is_photo => name,
+            _ => continue,
+        };
+
+        let photo =
+            fs::read(&path).with_context(|| format!("could not read {}", path.display()))?;
+        if ffi::enrollIdentity(name, &photo) {
+            enrolled += 1;
+        } else {
+            warn!("no face in {}, not enrolling {}", path.display(), name);
+        }
+    }
+    info!("enrolled {} people from {}", enrolled, directory.display());
+    Ok(enrolled)
+}
+
+/// An RGB24 frame as a binary PPM image, which is how frames get to OpenCV
+pub fn frame_to_ppm_format(frame: &frame::Video) -> Vec<u8> {
     let mut buf: Vec<u8> = Vec::new();
 
     buf.extend_from_slice(format!("P6\n{} {}\n255\n", frame.width(), frame.height()).as_bytes());
-    buf.extend_from_slice(frame.data(0));
+    // ffmpeg pads each row out to its stride, PPM doesn't
+    let row_len = frame.width() as usize * 3;
+    for row in frame
+        .data(0)
+        .chunks(frame.stride(0))
+        .take(frame.height() as usize)
+    {
+        buf.extend_from_slice(&row[..row_len]);
+    }
 
     buf
 }
 
-pub fn blur_a_frame(frame: frame::Video) -> frame::Video {
-    // remember how big the frame was
+pub use ffi::Detection;
+
+/// Says where the faces in an RGB24 frame are
+pub type DetectFaces = dyn Fn(&frame::Video) -> Vec<Detection> + Send + Sync;
+
+/// Where faces come from
+#[derive(Clone)]
+pub enum FaceDetector {
+    /// The models loaded by [`init_models`]
+    Models,
+    /// Whatever the function says is in each frame, e.g. from a detector of
+    /// your own, or so tests can know ahead of time what should get blurred
+    Scripted(Arc<DetectFaces>),
+}
+
+impl FaceDetector {
+    /// What finds the faces, as the audit log names it
+    pub fn detector_name(&self) -> &'static str {
+        match self {
+            Self::Models => DETECTOR_MODEL,
+            Self::Scripted(_) => "scripted",
+        }
+    }
+
+    /// What matches faces against enrolled identities, if anything does
+    pub fn embedder_name(&self) -> Option<&'static str> {
+        match self {
+            Self::Models => Some(EMBEDDER_MODEL),
+            Self::Scripted(_) => None,
+        }
+    }
+}
+
+impl fmt::Debug for FaceDetector {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Models => f.write_str("Models"),
+            Self::Scripted(_) => f.write_str("Scripted"),
+        }
+    }
+}
+
+/// A [`StreamPolicy`], plus whatever operators have switched on, in the form
+/// the C++ side wants it
+struct Redaction {
+    /// The policy's, for when the operators are done overriding them
+    zones: Vec<ffi::Zone>,
+    style: BlurStyle,
+    options: ffi::BlurOptions,
+    /// Don't even bother blurring, see [`Curtain::Slate`]
+    slate: bool,
+    /// Set in debug mode (see [`StreamPolicy::debug`]), to tell faces apart
+    debug: Option<Tracker>,
+    detector: FaceDetector,
+}
+
+impl Redaction {
+    fn new(policy: &StreamPolicy, detector: FaceDetector) -> Self {
+        let zones: Vec<_> = policy
+            .zones
+            .iter()
+            .map(|zone| ffi::Zone {
+                points: zone
+                    .points
+                    .iter()
+                    .map(|&(x, y)| ffi::ZonePoint { x, y })
+                    .collect(),
+            })
+            .collect();
+        Self {
+            options: ffi::BlurOptions {
+                zones: zones.clone(),
+                style: to_ffi_style(policy.style),
+                detect_faces: true,
+                blur_everyone: false,
+                anonymize: !policy.debug,
+                use_models: matches!(detector, FaceDetector::Models),
+                given_faces: Vec::new(),
+            },
+            zones,
+            style: policy.style,
+            slate: false,
+            debug: policy.debug.then(Tracker::new),
+            detector,
+        }
+    }
+
+    /// Whether frames actually get anonymized, which they don't in debug mode
+    /// unless a curtain is down
+    fn anonymizes(&self) -> bool {
+        self.options.anonymize
+    }
+
+    /// Follow what the operators have switched on
+    fn apply(&mut self, controls: &Controls) {
+        let style = controls.style.unwrap_or(self.style);
+        self.slate = controls.curtain == Curtain::Slate;
+        self.options = match controls.curtain {
+            Curtain::Off => ffi::BlurOptions {
+                zones: self.zones.clone(),
+                style: to_ffi_style(style),
+                detect_faces: !controls.pause_detection,
+                blur_everyone: controls.blur_everyone,
+                anonymize: self.debug.is_none(),
+                use_models: matches!(self.detector, FaceDetector::Models),
+                given_faces: Vec::new(),
+            },
+            // the whole frame is one big zone, so there's no point looking for faces
+            Curtain::Blur | Curtain::Slate => ffi::BlurOptions {
+                zones: vec![ffi::Zone {
+                    points: [(0.0, 0.0), (1.0, 0.0), (1.0, 1.0), (0.0, 1.0)]
+                        .into_iter()
+                        .map(|(x, y)| ffi::ZonePoint { x, y })
+                        .collect(),
+                }],
+                style: ffi::BlurStyle::Blur,
+                detect_faces: false,
+                blur_everyone: true,
+                anonymize: true,
+                use_models: false,
+                given_faces: Vec::new(),
+            },
+        };
+    }
+}
+
+fn to_ffi_style(style: BlurStyle) -> ffi::BlurStyle {
+    match style {
+        BlurStyle::Blur => ffi::BlurStyle::Blur,
+        BlurStyle::Pixelate => ffi::BlurStyle::Pixelate,
+        BlurStyle::Fill => ffi::BlurStyle::Fill,
+    }
+}
+
+/// Blur every face in `frame` that isn't whitelisted, and every zone of
+/// `redaction`. Also returns every face that was found, whitelisted or not.
+///
+/// In debug mode, faces and zones are outlined instead.
+fn blur_a_frame(frame: frame::Video, redaction: &mut Redaction) -> (frame::Video, Vec<Detection>) {
+    let started = Instant::now();
+    // remember how big the frame was, it can change from one frame to the next
     let width = frame.width();
     let height = frame.height();
 
-    let ppm_bytes = frame_to_ppm_format(frame);
-    let blurred = ffi::blurFFMpegFrame(&ppm_bytes);
-
     let mut ret = frame::Video::new(pixel::Pixel::RGB24, width, height);
+    ret.set_pts(frame.pts());
+    if redaction.slate {
+        ret.data_mut(0).fill(SLATE_GREY);
+        return (ret, Vec::new());
+    }
+
+    if let FaceDetector::Scripted(detect) = &redaction.detector {
+        if redaction.options.detect_faces {
+            redaction.options.given_faces = detect(&frame);
+        }
+    }
+    let ppm_bytes = frame_to_ppm_format(&frame);
+    let result = ffi::blurFFMpegFrame(&ppm_bytes, &redaction.options);
+    let blurred = result.pixels;
+    let row_len = width as usize * 3;
+    let stride = ret.stride(0);
+    if blurred.len() != row_len * height as usize {
+        // never let an unblurred frame through, black it out instead
+        warn!(
+            "blurring a {}x{} frame gave back {} bytes",
+            width,
+            height,
+            blurred.len()
+        );
+        ret.data_mut(0).fill(0);
+        return (ret, result.detections);
+    }
     // TODO: would be cooler if we didn't copy, i.e we got opencv to write into this directly
-    ret.data_mut(0).copy_from_slice(blurred.as_slice());
-    ret
+    for (src, dst) in blurred
+        .as_slice()
+        .chunks(row_len)
+        .zip(ret.data_mut(0).chunks_mut(stride))
+    {
+        dst[..row_len].copy_from_slice(src);
+    }
+
+    if !redaction.options.anonymize {
+        if let Some(tracker) = &mut redaction.debug {
+            let track_ids = tracker.update(&result.detections);
+            ffi::drawDebugOverlay(
+                ret.data_mut(0),
+                width as i32,
+                height as i32,
+                stride as i32,
+                &result.detections,
+                &track_ids,
+                &redaction.options.zones,
+                started.elapsed().as_secs_f32() * 1000.0,
+            );
+        }
+    }
+    (ret, result.detections)
 }
 
+/// Checks that the C++ side is linked in
 pub fn print_hello_from_cxx() {
     ffi::printHelloFromCxx();
 }
 
-pub fn start_blur_thread(frame_receiver: Receiver<frame::Video>) -> Receiver<frame::Video> {
-    let (blurred_tx, blurred_rx) = channel();
+/// Anonymizes frames as a step of a [`Pipeline`](crate::pipeline::Pipeline),
+/// or on its own through [`FrameStage::process`]
+pub struct BlurStage {
+    redaction: Redaction,
+    controls: OperatorControls,
+    audit: Option<AuditLog>,
+}
+
+impl BlurStage {
+    /// Faces found by `detector` and the zones of `policy` are anonymized in
+    /// the style it asks for, unless `controls` say otherwise. They are
+    /// checked before every frame. What was blurred goes into `audit`, if
+    /// there is one.
+    pub fn new(
+        policy: &StreamPolicy,
+        detector: FaceDetector,
+        controls: OperatorControls,
+        audit: Option<AuditLog>,
+    ) -> Self {
+        Self {
+            redaction: Redaction::new(policy, detector),
+            controls,
+            audit,
+        }
+    }
+}
 
-    thread::Builder::new()
-        .name("frame blur thread".to_owned())
-        .spawn(move || loop {
-            // if they stop sending us frames, unwrap will trigger
-            let to_blur = frame_receiver.recv().expect("frame splitter thread died");
-            let blurred_buffer = blur_a_frame(to_blur);
+impl FrameStage for BlurStage {
+    fn name(&self) -> &str {
+        "frame blur thread"
+    }
 
-            // if they stop listening to our frames, unwrap will trigger
-            blurred_tx.send(blurred_buffer).expect("whoever was supposed to consume blurred frames died");
-        })
-        .expect("failed to spawn thread");
+    /// Every face that was found is passed on along with the blurred frame,
+    /// whitelisted or not
+    fn process(
+        &mut self,
+        frame: frame::Video,
+        mut detections: Vec<Detection>,
+    ) -> (frame::Video, Vec<Detection>) {
+        let pts = frame.pts();
+        let (current, changes) = self.controls.take_changes();
+        if !changes.is_empty() {
+            self.redaction.apply(&current);
+            if let Some(audit) = &mut self.audit {
+                for change in &changes {
+                    audit.record_control(pts, change, &current);
+                }
+            }
+        }
 
-    blurred_rx
+        let (blurred, found) = blur_a_frame(frame, &mut self.redaction);
+        if let Some(audit) = &mut self.audit {
+            audit.record(
+                pts,
+                &self.redaction.detector,
+                &found,
+                !self.redaction.anonymizes(),
+            );
+        }
+        detections.extend(found);
+        (blurred, detections)
+    }
 }
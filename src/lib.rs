@@ -0,0 +1,92 @@
+//! Blurs faces out of live video.
+//!
+//! The RTMP proxy in [`server`] is built out of pieces that work on their own
+//! too:
+//!
+//! - [`pipeline`] takes a stream's video bytes through a decoder, any number
+//!   of [stages](pipeline::FrameStage) (such as
+//!   [blurring faces](image_processing::BlurStage)) and an encoder, into a
+//!   [`LiveStream`](stream_registry::LiveStream) that players, recordings and
+//!   the like subscribe to.
+//! - [`egress`], [`recording`] and [`hls`] subscribe to a
+//!   [`LiveStream`](stream_registry::LiveStream) and push it on to another
+//!   RTMP server, archive it, or package it for browsers. [`admin`] serves
+//!   the HLS, along with [`metrics`] and each stream's operator
+//!   [`controls`].
+//! - [`custom_ffmpeg_io`] lets ffmpeg read from and write to any Rust source
+//!   or sink, rather than just files and URLs.
+//! - [`flv_file`] splits FLV into tags and puts them back together.
+//!
+//! Nothing that uses faces works until the models have been loaded with
+//! [`image_processing::init_models`], unless faces come from a
+//! [scripted detector](image_processing::FaceDetector::Scripted). Anyone who
+//! shouldn't be blurred is then enrolled with
+//! [`image_processing::enroll_identities`].
+//!
+//! ```no_run
+//! use anonynews_rs::{
+//!     config::VideoConfig,
+//!     controls::OperatorControls,
+//!     image_processing::{self, BlurStage, FaceDetector},
+//!     pipeline::Pipeline,
+//!     policy::StreamPolicy,
+//!     stream_registry::{StreamName, StreamRegistry},
+//! };
+//!
+//! image_processing::init_models();
+//! let registry = StreamRegistry::new();
+//! let guard = registry
+//!     .start_publishing(StreamName::new("live", "camera-1"))
+//!     .unwrap();
+//! let (_, mut blurred) = guard.stream().subscribe();
+//!
+//! let mut pipeline = Pipeline::builder(guard.stream().clone())
+//!     .video(VideoConfig::default())
+//!     .stage(BlurStage::new(
+//!         &StreamPolicy::default(),
+//!         FaceDetector::Models,
+//!         OperatorControls::new(),
+//!         None,
+//!     ))
+//!     .start();
+//! // RTMP video message bodies, e.g. from a publisher
+//! # let (timestamp, video_bytes) = (0, bytes::Bytes::new());
+//! pipeline.send_video_bytes(timestamp, &video_bytes)?;
+//! // ...and blurred H.264 comes out of `blurred`
+//! # Ok::<(), std::io::Error>(())
+//! ```
+
+#![warn(missing_docs)]
+
+/// The ffmpeg bindings that frames are passed around in
+pub use ffmpeg_next;
+
+pub mod admin;
+pub mod audit;
+pub mod auth;
+pub mod config;
+pub mod controls;
+pub mod custom_ffmpeg_io;
+pub mod decoding_frames;
+pub mod egress;
+pub mod flv_file;
+pub mod hls;
+pub mod image_processing;
+pub mod metrics;
+pub mod offline;
+pub mod pipeline;
+pub mod policy;
+pub mod recording;
+pub mod region_metadata;
+pub mod routing;
+pub mod server;
+pub mod shutdown;
+pub mod stream_registry;
+pub mod video_tags;
+
+mod connection_error;
+mod connection_manager;
+mod encoding_frames;
+mod remux;
+mod rescaling;
+mod tracking;
@@ -1,36 +1,109 @@
-use tokio::net::{TcpListener, TcpStream};
-use tracing::{info, error};
+use std::{
+    path::PathBuf,
+    time::{SystemTime, UNIX_EPOCH},
+};
 
-mod connection_manager;
-mod decoding_frames;
-mod custom_ffmpeg_io;
-mod flv_file;
-mod image_processing;
+use clap::{Parser, Subcommand};
 
-#[tokio::main]
-async fn main() -> anyhow::Result<()> {
-    tracing_subscriber::fmt::init();
+use anonynews_rs::{
+    auth,
+    config::Config,
+    image_processing::{self, FaceDetector},
+    offline,
+    policy::Policies,
+    server,
+    stream_registry::StreamName,
+};
 
-    image_processing::init_models();
+#[derive(Debug, Parser)]
+#[clap(version, about)]
+struct Args {
+    /// TOML config file. Everything has a default if this is left out.
+    #[clap(long, short)]
+    config: Option<PathBuf>,
 
-    let listener = TcpListener::bind("0.0.0.0:8899").await?;
+    #[clap(subcommand)]
+    command: Option<Command>,
+}
 
-    image_processing::print_hello_from_cxx();
-    loop {
-        info!("ready to accept connections");
-        let (tcp_stream, _addr) = listener.accept().await?;
-        info!("accepting connection from {:?}", _addr);
-        tokio::spawn(manage_connection(tcp_stream));
-    }
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Run the RTMP proxy (the default)
+    Serve,
+    /// Print a publish token for a stream, using the configured token secret
+    SignToken {
+        #[clap(long)]
+        app: String,
+        #[clap(long)]
+        stream_key: String,
+        /// How long the token stays valid for
+        #[clap(long, default_value = "86400")]
+        valid_for_secs: u64,
+    },
+    /// Blur a video file (FLV, MP4, MKV, ...) the same way a live stream would
+    /// be, keeping its audio
+    ProcessFile {
+        /// `-` to read from stdin
+        input: PathBuf,
+        /// The format is guessed from the extension
+        output: PathBuf,
+        /// Skip probing and read the input as this ffmpeg format, e.g. `mpegts`
+        #[clap(long)]
+        input_format: Option<String>,
+        /// Blur the file with the policy a stream published on this app would get
+        #[clap(long, default_value = "")]
+        app: String,
+        /// Blur the file with the policy a stream published with this key would get
+        #[clap(long, default_value = "")]
+        stream_key: String,
+    },
 }
 
-#[tracing::instrument]
-async fn manage_connection(socket: TcpStream) {
-    let mut conn = connection_manager::ConnectionManager::connect(socket)
-        .await
-        .unwrap();
-    if let Err(e) = conn.handle_connection().await {
-        let err_dyn: &dyn std::error::Error = e.as_ref();
-        error!(problem = err_dyn, "bruh what the hell?",);
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    tracing_subscriber::fmt::init();
+
+    let args = Args::parse();
+    let config = Config::load(args.config.as_deref())?;
+
+    match args.command.unwrap_or(Command::Serve) {
+        Command::Serve => server::serve(config).await,
+        Command::SignToken {
+            app,
+            stream_key,
+            valid_for_secs,
+        } => {
+            let secret = config
+                .auth
+                .token_secret
+                .ok_or_else(|| anyhow::anyhow!("no auth.token_secret in the config"))?;
+            let expires = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() + valid_for_secs;
+            let name = StreamName::new(app, stream_key);
+            let token = auth::sign_token(secret.as_bytes(), &name, expires);
+            println!("{}?expires={}&token={}", name.stream_key, expires, token);
+            Ok(())
+        }
+        Command::ProcessFile {
+            input,
+            output,
+            input_format,
+            app,
+            stream_key,
+        } => {
+            let policy =
+                Policies::new(&config.policies)?.for_stream(&StreamName::new(app, stream_key));
+            image_processing::init_models();
+            image_processing::enroll_identities(&config.whitelist)?;
+            tokio::task::spawn_blocking(move || {
+                offline::process_file(
+                    &input,
+                    &output,
+                    input_format.as_deref(),
+                    &policy,
+                    FaceDetector::Models,
+                )
+            })
+            .await?
+        }
     }
 }
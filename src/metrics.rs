@@ -0,0 +1,187 @@
+//! Numbers for the admin server's `/metrics`, in the Prometheus text format.
+//!
+//! Everything here is a plain atomic that the hot paths bump as they go, and
+//! only gets turned into text when someone scrapes us.
+
+use std::{
+    fmt::Write,
+    sync::{
+        atomic::{AtomicU64, AtomicU8, Ordering},
+        Arc, Mutex, Weak,
+    },
+};
+
+use crate::{routing::Destination, stream_registry::StreamName};
+
+/// Name, help text, and how to read it
+type Counter = (&'static str, &'static str, fn(&EgressMetrics) -> u64);
+
+/// Shared between every connection. Cheap to clone.
+#[derive(Debug, Clone, Default)]
+pub struct Metrics {
+    /// Dead entries get cleaned up whenever we render
+    egress: Arc<Mutex<Vec<Weak<EgressMetrics>>>>,
+}
+
+impl Metrics {
+    /// Nothing to report yet
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The returned metrics are reported for as long as they are alive
+    pub fn register_egress(
+        &self,
+        stream: &StreamName,
+        destination: &Destination,
+    ) -> Arc<EgressMetrics> {
+        let metrics = Arc::new(EgressMetrics {
+            stream: stream.to_string(),
+            destination: destination.to_string(),
+            state: AtomicU8::new(EgressState::Connecting as u8),
+            packets_sent: AtomicU64::new(0),
+            bytes_sent: AtomicU64::new(0),
+            packets_dropped: AtomicU64::new(0),
+            reconnects: AtomicU64::new(0),
+        });
+        self.egress.lock().unwrap().push(Arc::downgrade(&metrics));
+        metrics
+    }
+
+    /// Everything, in the Prometheus text format
+    pub fn render(&self) -> String {
+        let egress: Vec<_> = {
+            let mut egress = self.egress.lock().unwrap();
+            egress.retain(|metrics| metrics.strong_count() > 0);
+            egress.iter().filter_map(Weak::upgrade).collect()
+        };
+
+        let mut out = String::new();
+        out.push_str("# HELP rtmp_egress_state Connection state of each egress destination\n");
+        out.push_str("# TYPE rtmp_egress_state gauge\n");
+        for metrics in &egress {
+            let current = metrics.state();
+            for state in EgressState::ALL {
+                let _ = writeln!(
+                    out,
+                    "rtmp_egress_state{{{},state=\"{}\"}} {}",
+                    metrics.labels(),
+                    state.name(),
+                    (state == current) as u8
+                );
+            }
+        }
+
+        let counters: [Counter; 4] = [
+            (
+                "rtmp_egress_packets_sent_total",
+                "Audio, video and data messages sent to each egress destination",
+                |m| m.packets_sent.load(Ordering::Relaxed),
+            ),
+            (
+                "rtmp_egress_bytes_sent_total",
+                "Bytes sent to each egress destination, RTMP chunk headers included",
+                |m| m.bytes_sent.load(Ordering::Relaxed),
+            ),
+            (
+                "rtmp_egress_packets_dropped_total",
+                "Packets skipped because an egress destination could not keep up",
+                |m| m.packets_dropped.load(Ordering::Relaxed),
+            ),
+            (
+                "rtmp_egress_reconnects_total",
+                "Times an egress destination was retried after losing it or failing to connect",
+                |m| m.reconnects.load(Ordering::Relaxed),
+            ),
+        ];
+        for (name, help, value) in counters {
+            let _ = writeln!(out, "# HELP {} {}", name, help);
+            let _ = writeln!(out, "# TYPE {} counter", name);
+            for metrics in &egress {
+                let _ = writeln!(out, "{}{{{}}} {}", name, metrics.labels(), value(metrics));
+            }
+        }
+        out
+    }
+}
+
+/// Where a destination's connection is at
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum EgressState {
+    /// Connecting, or waiting for the destination to accept the stream
+    Connecting,
+    /// Pushing the stream
+    Live,
+    /// Lost the destination, waiting a bit before trying again
+    Reconnecting,
+    /// The stream ended and the destination was told so
+    Finished,
+}
+
+impl EgressState {
+    const ALL: [Self; 4] = [
+        Self::Connecting,
+        Self::Live,
+        Self::Reconnecting,
+        Self::Finished,
+    ];
+
+    fn name(self) -> &'static str {
+        match self {
+            Self::Connecting => "connecting",
+            Self::Live => "live",
+            Self::Reconnecting => "reconnecting",
+            Self::Finished => "finished",
+        }
+    }
+}
+
+/// How one stream is doing at one destination
+#[derive(Debug)]
+pub struct EgressMetrics {
+    stream: String,
+    destination: String,
+    state: AtomicU8,
+    /// Audio, video and data messages
+    pub packets_sent: AtomicU64,
+    /// What went out on the connection, i.e. the messages chunked up with
+    /// their RTMP headers
+    pub bytes_sent: AtomicU64,
+    /// Skipped because the destination couldn't keep up, or was gone
+    pub packets_dropped: AtomicU64,
+    /// Times we waited to try again, whether the connection was lost or never
+    /// got anywhere
+    pub reconnects: AtomicU64,
+}
+
+impl EgressMetrics {
+    /// Where the connection is at
+    pub fn state(&self) -> EgressState {
+        let state = self.state.load(Ordering::Relaxed);
+        EgressState::ALL
+            .into_iter()
+            .find(|s| *s as u8 == state)
+            .unwrap_or(EgressState::Connecting)
+    }
+
+    /// For the egress task, as the connection moves along
+    pub fn set_state(&self, state: EgressState) {
+        self.state.store(state as u8, Ordering::Relaxed);
+    }
+
+    fn labels(&self) -> String {
+        format!(
+            "stream=\"{}\",destination=\"{}\"",
+            escape_label(&self.stream),
+            escape_label(&self.destination)
+        )
+    }
+}
+
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
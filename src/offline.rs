@@ -0,0 +1,267 @@
+//! Blurring files that are already on disk, for footage that was recorded
+//! somewhere else.
+//!
+//! The video goes through the same steps as a live stream (decode, convert to
+//! RGB, [`BlurStage`], encode with x264), but all on one thread and with the
+//! original timestamps. Audio is copied over as is.
+
+use std::{
+    fs::File,
+    io::{self, BufReader},
+    path::Path,
+    time::{Duration, Instant},
+};
+
+use anyhow::Context as _;
+use ffmpeg::{
+    codec, decoder, encoder, format, frame, media, util::format::Pixel, Dictionary, Packet,
+    Rational,
+};
+use ffmpeg_next as ffmpeg;
+use tracing::{info, warn};
+
+use crate::{
+    controls::OperatorControls,
+    custom_ffmpeg_io::{
+        read_from_custom_input, read_from_custom_input_as, CustomFFMpegRead, CustomInput,
+        SeekableReader,
+    },
+    encoding_frames,
+    image_processing::{BlurStage, FaceDetector},
+    pipeline::FrameStage,
+    policy::StreamPolicy,
+    rescaling::Rescaler,
+};
+
+/// How often to say how far along we are
+const PROGRESS_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Decode, blur and re-encode the video in `input_path` (`-` for stdin) into
+/// `output_path`, anonymizing it the way `policy` says with the faces
+/// `detector` finds. The input format is probed unless `input_format` is
+/// given; the output format is guessed from the file extension. If `detector`
+/// is [`FaceDetector::Models`], they must have been loaded already.
+pub fn process_file(
+    input_path: &Path,
+    output_path: &Path,
+    input_format: Option<&str>,
+    policy: &StreamPolicy,
+    detector: FaceDetector,
+) -> anyhow::Result<()> {
+    if input_path == Path::new("-") {
+        let input = open_input(io::stdin(), input_format).context("could not read stdin")?;
+        process(input, "stdin", output_path, policy, detector)
+    } else {
+        let file = File::open(input_path)
+            .with_context(|| format!("could not open {}", input_path.display()))?;
+        let input = open_input(SeekableReader::new(BufReader::new(file)), input_format)
+            .with_context(|| format!("could not read {}", input_path.display()))?;
+        process(
+            input,
+            &input_path.display().to_string(),
+            output_path,
+            policy,
+            detector,
+        )
+    }
+}
+
+fn open_input<T: CustomFFMpegRead>(
+    reader: T,
+    format_name: Option<&str>,
+) -> Result<CustomInput<T>, ffmpeg::Error> {
+    match format_name {
+        Some(format_name) => read_from_custom_input_as(reader, format_name),
+        None => read_from_custom_input(reader),
+    }
+}
+
+fn process<T: CustomFFMpegRead>(
+    mut input: CustomInput<T>,
+    input_name: &str,
+    output_path: &Path,
+    policy: &StreamPolicy,
+    detector: FaceDetector,
+) -> anyhow::Result<()> {
+    let video_stream = input
+        .streams()
+        .best(media::Type::Video)
+        .ok_or_else(|| anyhow::anyhow!("{} has no video", input_name))?;
+    let video_index = video_stream.index();
+    let video_time_base = video_stream.time_base();
+    let frame_rate = video_stream.avg_frame_rate();
+    let decoder = codec::Context::from_parameters(video_stream.parameters())?
+        .decoder()
+        .video()?;
+
+    // only once we know there is something to blur, so bad inputs don't leave
+    // empty files behind
+    let mut output = format::output(&output_path)
+        .with_context(|| format!("could not create {}", output_path.display()))?;
+
+    // the video stream gets encoded, audio streams get copied, the rest is dropped
+    let global_header = output
+        .format()
+        .flags()
+        .contains(format::Flags::GLOBAL_HEADER);
+    let codec = encoder::find(codec::Id::H264).ok_or(ffmpeg::Error::EncoderNotFound)?;
+    let mut video_output = output.add_stream(codec)?;
+    let encoder = encoding_frames::configure_video_encoder(
+        decoder.width(),
+        decoder.height(),
+        video_time_base,
+        Some(frame_rate).filter(|frame_rate| frame_rate.numerator() > 0),
+        global_header,
+    )?;
+    // nobody is waiting on this, so there is no need for zerolatency
+    let mut options = Dictionary::new();
+    options.set("preset", "veryfast");
+    let encoder = encoder.open_as_with(codec, options)?;
+    video_output.set_parameters(&encoder);
+    let mut video = VideoTranscoder::new(
+        decoder,
+        encoder,
+        video_time_base,
+        video_output.index(),
+        policy,
+        detector,
+    );
+
+    let mut stream_mapping = vec![None; input.nb_streams() as usize];
+    stream_mapping[video_index] = Some((video.output_index, video_time_base));
+    for (input_index, input_stream) in input.streams().enumerate() {
+        if input_stream.parameters().medium() != media::Type::Audio {
+            continue;
+        }
+        let mut output_stream = output.add_stream(encoder::find(codec::Id::None))?;
+        output_stream.set_parameters(input_stream.parameters());
+        // codec tags mean nothing to other containers, so let the muxer pick its own
+        unsafe {
+            (*output_stream.parameters().as_mut_ptr()).codec_tag = 0;
+        }
+        stream_mapping[input_index] = Some((output_stream.index(), input_stream.time_base()));
+    }
+
+    output
+        .write_header()
+        .with_context(|| format!("could not write to {}", output_path.display()))?;
+    video.output_time_base = output.stream(video.output_index).unwrap().time_base();
+
+    for (input_stream, mut packet) in input.packets() {
+        // streams that show up after the header was read have no mapping
+        let (output_index, input_time_base) =
+            match stream_mapping.get(input_stream.index()).copied().flatten() {
+                Some(mapping) => mapping,
+                None => continue,
+            };
+        if input_stream.index() == video_index {
+            if let Err(e) = video.decoder.send_packet(&packet) {
+                warn!("could not decode a packet: {}", e);
+                continue;
+            }
+            video.blur_decoded_frames(&mut output)?;
+        } else {
+            let output_time_base = output.stream(output_index).unwrap().time_base();
+            packet.rescale_ts(input_time_base, output_time_base);
+            packet.set_position(-1);
+            packet.set_stream(output_index);
+            packet.write_interleaved(&mut output)?;
+        }
+    }
+
+    video.finish(&mut output)?;
+    output.write_trailer()?;
+    info!(
+        "blurred {} frames from {} into {}",
+        video.frames,
+        input_name,
+        output_path.display()
+    );
+    Ok(())
+}
+
+struct VideoTranscoder {
+    decoder: decoder::Video,
+    encoder: encoder::video::Encoder,
+    /// Whatever the decoder gives us -> RGB for the blurrer
+    to_rgb: Rescaler,
+    /// RGB from the blurrer -> YUV for x264, at the size the output started with
+    to_yuv: Rescaler,
+    /// Files have no operators, so only the policy decides what gets blurred
+    blur: BlurStage,
+    /// Same as the input stream's, so frame timestamps carry straight over
+    encoder_time_base: Rational,
+    output_index: usize,
+    /// Only known once the output header has been written
+    output_time_base: Rational,
+    frames: u64,
+    last_progress: Instant,
+}
+
+impl VideoTranscoder {
+    fn new(
+        decoder: decoder::Video,
+        encoder: encoder::video::Encoder,
+        encoder_time_base: Rational,
+        output_index: usize,
+        policy: &StreamPolicy,
+        detector: FaceDetector,
+    ) -> Self {
+        let (width, height) = (decoder.width(), decoder.height());
+        let to_rgb = Rescaler::new(Pixel::RGB24, "decoded frames");
+        let to_yuv = Rescaler::with_size(Pixel::YUV420P, width, height, "blurred frames");
+        Self {
+            decoder,
+            encoder,
+            to_rgb,
+            to_yuv,
+            blur: BlurStage::new(policy, detector, OperatorControls::new(), None),
+            encoder_time_base,
+            output_index,
+            output_time_base: Rational(0, 1),
+            frames: 0,
+            last_progress: Instant::now(),
+        }
+    }
+
+    fn blur_decoded_frames(&mut self, output: &mut format::context::Output) -> anyhow::Result<()> {
+        let mut decoded = frame::Video::empty();
+        while self.decoder.receive_frame(&mut decoded).is_ok() {
+            let rgb_frame = self.to_rgb.run(&decoded)?;
+            let (blurred, _detections) = self.blur.process(rgb_frame, Vec::new());
+
+            // the decoded frame's timestamp carries through
+            let yuv_frame = self.to_yuv.run(&blurred)?;
+            self.encoder.send_frame(&yuv_frame)?;
+            self.write_encoded_packets(output)?;
+
+            self.frames += 1;
+            if self.last_progress.elapsed() >= PROGRESS_INTERVAL {
+                info!("blurred {} frames so far", self.frames);
+                self.last_progress = Instant::now();
+            }
+        }
+        Ok(())
+    }
+
+    /// Drain the decoder and then the encoder
+    fn finish(&mut self, output: &mut format::context::Output) -> anyhow::Result<()> {
+        self.decoder.send_eof()?;
+        self.blur_decoded_frames(output)?;
+        self.encoder.send_eof()?;
+        self.write_encoded_packets(output)
+    }
+
+    fn write_encoded_packets(
+        &mut self,
+        output: &mut format::context::Output,
+    ) -> anyhow::Result<()> {
+        let mut packet = Packet::empty();
+        while self.encoder.receive_packet(&mut packet).is_ok() {
+            packet.set_stream(self.output_index);
+            packet.rescale_ts(self.encoder_time_base, self.output_time_base);
+            packet.write_interleaved(output)?;
+        }
+        Ok(())
+    }
+}
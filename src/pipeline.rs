@@ -0,0 +1,208 @@
+//! The per-session chain of threads that turns RTMP video bytes into blurred
+//! video: decoder -> stages -> encoder -> [`LiveStream`].
+//!
+//! The source is whoever calls [`Pipeline::send_video_bytes`], e.g. an RTMP
+//! publisher. Decoded RGB frames go through each [`FrameStage`] in turn (the
+//! proxy only has the one, [`BlurStage`](crate::image_processing::BlurStage)),
+//! then get encoded and published on the [`LiveStream`], where the sinks
+//! (players, [egress](crate::egress), [recordings](crate::recording),
+//! [HLS](crate::hls)) subscribe to them.
+//!
+//! Frames keep their PTS all the way through, in [`TIME_BASE`], so the output
+//! has the same timing as the input.
+//!
+//! A [`Pipeline`] owns all of those threads. Dropping it closes the decoder's
+//! input, lets each stage drain into the next, and joins every thread, so a
+//! session can't leave anything running behind it.
+
+use std::{
+    fmt,
+    sync::{
+        mpsc::{channel, Receiver},
+        Arc, Mutex,
+    },
+    thread::{self, JoinHandle},
+};
+
+use bytes::Bytes;
+use ffmpeg_next::{frame, Rational};
+use tracing::{debug, warn};
+
+use crate::{
+    config::VideoConfig, decoding_frames::FrameExtractor, encoding_frames,
+    image_processing::Detection, shutdown::Shutdown, stream_registry::LiveStream,
+};
+
+/// What frame timestamps are in between the decoder and the encoder. Same as
+/// RTMP's, so they carry straight over from the input to the output.
+pub const TIME_BASE: Rational = Rational(1, 1000);
+
+/// RGB24 frames, each with the regions that have been anonymized in it so far
+pub type Frames = Receiver<(frame::Video, Vec<Detection>)>;
+
+/// One step between the decoder and the encoder, which gets a thread of its own
+pub trait FrameStage: Send + 'static {
+    /// What its thread is called
+    fn name(&self) -> &str;
+
+    /// Change an RGB24 frame, adding whatever it anonymized to `detections`.
+    /// The frame's PTS has to come out the same as it went in.
+    fn process(
+        &mut self,
+        frame: frame::Video,
+        detections: Vec<Detection>,
+    ) -> (frame::Video, Vec<Detection>);
+}
+
+/// The threads that blur one stream, see the [module docs](self). Built with
+/// [`Pipeline::builder`].
+#[derive(Debug)]
+pub struct Pipeline {
+    /// `None` only while dropping
+    frame_decoder: Option<FrameExtractor>,
+    /// Threads downstream of the frame decoder, in pipeline order
+    threads: Vec<JoinHandle<()>>,
+}
+
+/// Sets up a [`Pipeline`], see [`Pipeline::builder`]
+pub struct PipelineBuilder {
+    output: Arc<LiveStream>,
+    video: VideoConfig,
+    stages: Vec<Box<dyn FrameStage>>,
+    abort: Shutdown,
+}
+
+impl fmt::Debug for PipelineBuilder {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PipelineBuilder")
+            .field("output", &self.output.name())
+            .field("video", &self.video)
+            .field(
+                "stages",
+                &self
+                    .stages
+                    .iter()
+                    .map(|stage| stage.name())
+                    .collect::<Vec<_>>(),
+            )
+            .finish()
+    }
+}
+
+impl PipelineBuilder {
+    /// The codec and settings to encode with, the defaults if left out
+    pub fn video(mut self, video: VideoConfig) -> Self {
+        self.video = video;
+        self
+    }
+
+    /// Add a stage after the ones added so far
+    pub fn stage(mut self, stage: impl FrameStage) -> Self {
+        self.stages.push(Box::new(stage));
+        self
+    }
+
+    /// Make the stages stop without draining once `abort` fires, so only fire
+    /// it once a graceful teardown has taken too long
+    pub fn abort_on(mut self, abort: Shutdown) -> Self {
+        self.abort = abort;
+        self
+    }
+
+    /// Spawn every stage of the pipeline
+    pub fn start(self) -> Pipeline {
+        let Self {
+            output,
+            video,
+            stages,
+            abort,
+        } = self;
+        let ingest_codec = Arc::new(Mutex::new(None));
+        let (frame_decoder, mut frames) = FrameExtractor::new(abort.clone(), ingest_codec.clone());
+        let mut threads = Vec::with_capacity(stages.len() + 1);
+        for stage in stages {
+            let (stage_output, thread) = start_stage_thread(stage, frames, abort.clone());
+            frames = stage_output;
+            threads.push(thread);
+        }
+        threads.push(encoding_frames::start_encode_thread(
+            frames,
+            output,
+            video,
+            ingest_codec,
+            abort,
+        ));
+
+        Pipeline {
+            frame_decoder: Some(frame_decoder),
+            threads,
+        }
+    }
+}
+
+impl Pipeline {
+    /// Encoded video ends up in `output`, in the codec the builder's
+    /// [`VideoConfig`] asks for, along with where faces were if it asks for
+    /// that. Nothing happens to the frames in between unless stages are added.
+    pub fn builder(output: Arc<LiveStream>) -> PipelineBuilder {
+        PipelineBuilder {
+            output,
+            video: VideoConfig::default(),
+            stages: Vec::new(),
+            abort: Shutdown::new(),
+        }
+    }
+
+    /// Fails with `BrokenPipe` if the decode thread has gone away
+    pub fn send_video_bytes(&mut self, timestamp: u32, bytes: &Bytes) -> std::io::Result<()> {
+        match &mut self.frame_decoder {
+            Some(frame_decoder) => frame_decoder.send_bytes(timestamp, bytes),
+            None => Err(std::io::ErrorKind::BrokenPipe.into()),
+        }
+    }
+}
+
+impl Drop for Pipeline {
+    /// Blocks until every stage has drained, so don't drop this on an async worker
+    fn drop(&mut self) {
+        if let Some(frame_decoder) = self.frame_decoder.take() {
+            frame_decoder.finish();
+        }
+        for handle in self.threads.drain(..) {
+            let name = handle.thread().name().unwrap_or("<unnamed>").to_owned();
+            if handle.join().is_err() {
+                warn!("pipeline thread '{}' panicked", name);
+            }
+        }
+    }
+}
+
+/// Runs `stage` on every frame until the sender hangs up, the receiver goes
+/// away, or `cancel` fires
+fn start_stage_thread(
+    mut stage: Box<dyn FrameStage>,
+    frame_receiver: Frames,
+    cancel: Shutdown,
+) -> (Frames, JoinHandle<()>) {
+    let (processed_tx, processed_rx) = channel();
+    let name = stage.name().to_owned();
+
+    let handle = thread::Builder::new()
+        .name(name.clone())
+        .spawn(move || {
+            // a disconnected receiver means the stage before us is done
+            for (frame, detections) in frame_receiver.iter() {
+                if cancel.is_cancelled() {
+                    debug!("{} cancelled", name);
+                    return;
+                }
+                if processed_tx.send(stage.process(frame, detections)).is_err() {
+                    debug!("nobody is consuming frames from the {} anymore", name);
+                    return;
+                }
+            }
+        })
+        .expect("failed to spawn thread");
+
+    (processed_rx, handle)
+}
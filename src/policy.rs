@@ -0,0 +1,297 @@
+//! Per stream rules for how to anonymize, on top of blurring faces.
+//!
+//! Like routes (see [`crate::routing`]), policies are tried in order and the
+//! first one whose `app` and `stream_key` patterns both match wins. Streams
+//! that no policy matches get plain blurred faces.
+//!
+//! Zones are anonymized in every frame, whether or not a face was found in
+//! them. Their coordinates are fractions of the frame's width and height, so
+//! they stay over the same thing if the publisher changes resolution:
+//!
+//! ```toml
+//! [[policies]]
+//! app = "studio"
+//! stream_key = "set-*"
+//! style = "pixelate"
+//! zones = [
+//!     # the whiteboard
+//!     { x = 0.05, y = 0.1, width = 0.3, height = 0.4 },
+//!     # the window facing the street
+//!     { points = [[0.7, 0.0], [1.0, 0.0], [1.0, 0.6], [0.8, 0.5]] },
+//! ]
+//! ```
+//!
+//! A policy with `debug = true` anonymizes nothing. Instead, every face gets a
+//! box with its confidence, track id and whitelist match, zones are outlined,
+//! and each frame says how long it took to process. The annotated frames go
+//! out like any others, so only ever use it on streams that are safe to show.
+
+use std::collections::HashMap;
+
+use thiserror::Error;
+
+use crate::{
+    config::{BlurStyle, PolicyConfig, ZoneConfig},
+    routing::{Pattern, RouteError},
+    stream_registry::StreamName,
+};
+
+/// Why the policies in the config can't be used
+#[derive(Debug, Error, PartialEq)]
+pub enum PolicyError {
+    /// A pattern for the app or stream key didn't parse
+    #[error(transparent)]
+    Pattern(#[from] RouteError),
+
+    /// A zone goes past the edge of the frame
+    #[error("zone corner ({0}, {1}) is outside the frame, coordinates go from 0 to 1")]
+    OutsideFrame(f32, f32),
+
+    /// A zone has no area
+    #[error("a zone needs at least 3 corners, not {0}")]
+    TooFewCorners(usize),
+}
+
+/// How one stream gets anonymized
+#[derive(Debug, Clone, PartialEq)]
+pub struct StreamPolicy {
+    /// What faces and zones look like once anonymized
+    pub style: BlurStyle,
+    /// Anonymized whether or not there is a face in them
+    pub zones: Vec<Zone>,
+    /// Outline faces and zones instead of anonymizing them, for tuning
+    pub debug: bool,
+}
+
+/// Blurred faces and nothing else
+impl Default for StreamPolicy {
+    fn default() -> Self {
+        Self {
+            style: BlurStyle::Blur,
+            zones: Vec::new(),
+            debug: false,
+        }
+    }
+}
+
+/// A polygon, in fractions of the frame's width and height
+#[derive(Debug, Clone, PartialEq)]
+pub struct Zone {
+    /// `(x, y)` corners, at least three of them
+    pub points: Vec<(f32, f32)>,
+}
+
+impl Zone {
+    fn new(config: &ZoneConfig) -> Result<Self, PolicyError> {
+        let points = match *config {
+            ZoneConfig::Rectangle {
+                x,
+                y,
+                width,
+                height,
+            } => vec![
+                (x, y),
+                (x + width, y),
+                (x + width, y + height),
+                (x, y + height),
+            ],
+            ZoneConfig::Polygon { ref points } => {
+                points.iter().map(|&[x, y]| (x, y)).collect::<Vec<_>>()
+            }
+        };
+        if points.len() < 3 {
+            return Err(PolicyError::TooFewCorners(points.len()));
+        }
+        if let Some(&(x, y)) = points
+            .iter()
+            .find(|(x, y)| !(0.0..=1.0).contains(x) || !(0.0..=1.0).contains(y))
+        {
+            return Err(PolicyError::OutsideFrame(x, y));
+        }
+        Ok(Self { points })
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Policy {
+    app: Pattern,
+    stream_key: Pattern,
+    policy: StreamPolicy,
+}
+
+/// The policies from the config
+#[derive(Debug, Clone, Default)]
+pub struct Policies {
+    policies: Vec<Policy>,
+}
+
+impl Policies {
+    /// Checks every policy, so none can fail later
+    pub fn new(policies: &[PolicyConfig]) -> Result<Self, PolicyError> {
+        let policies = policies
+            .iter()
+            .map(|config| {
+                Ok(Policy {
+                    app: Pattern::parse(&config.app)?,
+                    stream_key: Pattern::parse(&config.stream_key)?,
+                    policy: StreamPolicy {
+                        style: config.style,
+                        zones: config
+                            .zones
+                            .iter()
+                            .map(Zone::new)
+                            .collect::<Result<_, _>>()?,
+                        debug: config.debug,
+                    },
+                })
+            })
+            .collect::<Result<_, PolicyError>>()?;
+        Ok(Self { policies })
+    }
+
+    /// The first policy that matches `name`, or the default
+    pub fn for_stream(&self, name: &StreamName) -> StreamPolicy {
+        // policies can't use what their patterns capture
+        let mut captures = HashMap::new();
+        self.policies
+            .iter()
+            .find(|policy| {
+                policy.app.matches(&name.app_name, &mut captures)
+                    && policy.stream_key.matches(&name.stream_key, &mut captures)
+            })
+            .map(|policy| policy.policy.clone())
+            .unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rectangle(x: f32, y: f32, width: f32, height: f32) -> ZoneConfig {
+        ZoneConfig::Rectangle {
+            x,
+            y,
+            width,
+            height,
+        }
+    }
+
+    fn polygon(points: &[[f32; 2]]) -> ZoneConfig {
+        ZoneConfig::Polygon {
+            points: points.to_vec(),
+        }
+    }
+
+    fn policy(app: &str, stream_key: &str, style: BlurStyle) -> PolicyConfig {
+        PolicyConfig {
+            app: app.to_owned(),
+            stream_key: stream_key.to_owned(),
+            style,
+            zones: Vec::new(),
+            debug: false,
+        }
+    }
+
+    #[test]
+    fn rectangles_become_four_corners() {
+        let zone = Zone::new(&rectangle(0.25, 0.5, 0.5, 0.25)).unwrap();
+        assert_eq!(
+            zone.points,
+            [(0.25, 0.5), (0.75, 0.5), (0.75, 0.75), (0.25, 0.75)]
+        );
+
+        let points = [[0.0, 0.0], [1.0, 0.0], [0.5, 1.0]];
+        let zone = Zone::new(&polygon(&points)).unwrap();
+        assert_eq!(zone.points, [(0.0, 0.0), (1.0, 0.0), (0.5, 1.0)]);
+    }
+
+    #[test]
+    fn bad_zones() {
+        let cases = [
+            (polygon(&[]), PolicyError::TooFewCorners(0)),
+            (
+                polygon(&[[0.0, 0.0], [1.0, 1.0]]),
+                PolicyError::TooFewCorners(2),
+            ),
+            (
+                polygon(&[[0.0, 0.0], [1.0, 0.0], [0.5, -0.25]]),
+                PolicyError::OutsideFrame(0.5, -0.25),
+            ),
+            (
+                polygon(&[[0.0, 0.0], [1.5, 0.0], [0.5, 1.0]]),
+                PolicyError::OutsideFrame(1.5, 0.0),
+            ),
+            (
+                rectangle(0.5, 0.0, 0.75, 0.5),
+                PolicyError::OutsideFrame(1.25, 0.0),
+            ),
+            (
+                rectangle(0.0, 0.5, 0.5, 0.75),
+                PolicyError::OutsideFrame(0.5, 1.25),
+            ),
+            (
+                rectangle(-0.25, 0.0, 0.5, 0.5),
+                PolicyError::OutsideFrame(-0.25, 0.0),
+            ),
+        ];
+        for (config, expected) in cases {
+            assert_eq!(Zone::new(&config), Err(expected), "{:?}", config);
+        }
+    }
+
+    #[test]
+    fn nan_is_outside_the_frame() {
+        let configs = [
+            polygon(&[[0.0, 0.0], [f32::NAN, 0.0], [0.5, 1.0]]),
+            rectangle(0.0, f32::NAN, 0.5, 0.5),
+            rectangle(0.0, 0.0, f32::NAN, 0.5),
+        ];
+        for config in configs {
+            assert!(
+                matches!(Zone::new(&config), Err(PolicyError::OutsideFrame(..))),
+                "{:?}",
+                config
+            );
+        }
+    }
+
+    #[test]
+    fn the_first_matching_policy_wins() {
+        let mut zoned = policy("studio", "set-*", BlurStyle::Pixelate);
+        zoned.zones.push(rectangle(0.0, 0.0, 0.5, 0.5));
+        let policies = Policies::new(&[
+            zoned,
+            policy("studio", "*", BlurStyle::Fill),
+            policy("*", "set-*", BlurStyle::Blur),
+        ])
+        .unwrap();
+
+        let set = policies.for_stream(&StreamName::new("studio", "set-1"));
+        assert_eq!(set.style, BlurStyle::Pixelate);
+        assert_eq!(set.zones.len(), 1);
+
+        let other = policies.for_stream(&StreamName::new("studio", "lobby"));
+        assert_eq!(other.style, BlurStyle::Fill);
+        assert!(other.zones.is_empty());
+
+        assert_eq!(
+            policies.for_stream(&StreamName::new("live", "lobby")),
+            StreamPolicy::default()
+        );
+        assert_eq!(
+            Policies::default().for_stream(&StreamName::new("studio", "set-1")),
+            StreamPolicy::default()
+        );
+    }
+
+    #[test]
+    fn bad_policies_are_rejected() {
+        let mut zoned = policy("*", "*", BlurStyle::Blur);
+        zoned.zones.push(polygon(&[[0.0, 0.0], [1.0, 1.0]]));
+        assert_eq!(
+            Policies::new(&[policy("*", "*", BlurStyle::Blur), zoned]).unwrap_err(),
+            PolicyError::TooFewCorners(2)
+        );
+    }
+}
@@ -0,0 +1,366 @@
+//! Archives what actually went out, i.e. the blurred video plus the audio.
+//!
+//! Each stream is recorded into its own directory as a series of files:
+//!
+//! ```text
+//! <directory>/<app>/<stream key>/<app>_<stream key>_<start time>.flv
+//! ```
+//!
+//! A new file is started (at the next keyframe) once the current one gets too
+//! long or too big, and old files are cleaned up whenever that happens.
+//!
+//! FLV is written directly. MP4 goes through ffmpeg's muxer and is fragmented,
+//! so a file is still playable if we die halfway through writing it.
+
+use std::{
+    fs::{self, File},
+    io::{self, BufWriter},
+    path::PathBuf,
+    sync::{mpsc::Receiver, Arc},
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+use chrono::Utc;
+use ffmpeg::Dictionary;
+use ffmpeg_next as ffmpeg;
+use tracing::{debug, info, span, warn, Level};
+
+use crate::{
+    config::{RecordingConfig, RecordingFormat},
+    flv_file::{BufferedSenderWriter, FLVWriterWrapper},
+    remux,
+    stream_registry::{LiveStream, MediaKind, MediaPacket, StreamName},
+};
+
+/// How many packets can wait for the disk before we start skipping to keyframes
+const RECORDER_QUEUE_LEN: usize = 1024;
+
+/// Records one stream for as long as it is live. Dropping this waits for the
+/// last file to be finished, which only happens once the stream has ended.
+#[derive(Debug)]
+pub struct Recorder {
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Recorder {
+    /// Returns `None` if recording is turned off
+    pub fn start(stream: &Arc<LiveStream>, config: &Arc<RecordingConfig>) -> Option<Self> {
+        let directory = remux::stream_directory(config.directory.as_ref()?, stream.name());
+        let (headers, packets) = stream.subscribe();
+        let packet_rx = remux::forward_packets(packets, RECORDER_QUEUE_LEN, "recording");
+
+        let mut recording = Recording {
+            name: stream.name().clone(),
+            directory,
+            config: config.clone(),
+            video_sequence_header: headers.video_sequence_header,
+            audio_sequence_header: headers.audio_sequence_header,
+            segment: None,
+            skipping_video: false,
+        };
+        let thread = thread::Builder::new()
+            .name("recording thread".to_owned())
+            .spawn(move || {
+                let _span = span!(Level::TRACE, "recording", stream = %recording.name).entered();
+                recording.run(packet_rx);
+            })
+            .expect("failed to spawn thread");
+
+        Some(Self {
+            thread: Some(thread),
+        })
+    }
+}
+
+impl Drop for Recorder {
+    /// Blocks until the stream has ended and the last file is finished, so
+    /// don't drop this on an async worker
+    fn drop(&mut self) {
+        if let Some(thread) = self.thread.take() {
+            if thread.join().is_err() {
+                warn!("recording thread panicked");
+            }
+        }
+    }
+}
+
+struct Recording {
+    name: StreamName,
+    directory: PathBuf,
+    config: Arc<RecordingConfig>,
+    /// Every file has to start with these
+    video_sequence_header: Option<MediaPacket>,
+    audio_sequence_header: Option<MediaPacket>,
+    segment: Option<Segment>,
+    /// Set once we've said the video can't go into MP4
+    skipping_video: bool,
+}
+
+impl Recording {
+    fn run(&mut self, packets: Receiver<MediaPacket>) {
+        if let Err(e) = fs::create_dir_all(&self.directory) {
+            warn!(
+                "could not create {}, not recording: {}",
+                self.directory.display(),
+                e
+            );
+            return;
+        }
+
+        for packet in packets.iter() {
+            if self.config.format == RecordingFormat::Mp4 {
+                if let Some(codec) = remux::unremuxable_codec(&packet) {
+                    if !self.skipping_video {
+                        warn!(
+                            "mp4 recordings can only hold H.264, leaving out the {:?} video",
+                            codec
+                        );
+                        self.skipping_video = true;
+                    }
+                    continue;
+                }
+            }
+
+            if packet.is_sequence_header() {
+                match packet.kind {
+                    MediaKind::Video => self.video_sequence_header = Some(packet.clone()),
+                    MediaKind::Audio => self.audio_sequence_header = Some(packet.clone()),
+                    MediaKind::Data => (),
+                }
+            }
+
+            // files can only start (and so, end) on a keyframe
+            let starts_gop = packet.is_keyframe() && !packet.is_sequence_header();
+            let rotate = match &self.segment {
+                Some(segment) => starts_gop && segment.is_full(&self.config, packet.timestamp),
+                None => starts_gop,
+            };
+            if rotate {
+                self.finish_segment();
+                self.start_segment(packet.timestamp);
+            }
+
+            if let Some(segment) = &mut self.segment {
+                if let Err(e) = segment.write(&packet) {
+                    warn!("could not write to {}: {}", segment.path.display(), e);
+                    self.finish_segment();
+                }
+            }
+        }
+        self.finish_segment();
+    }
+
+    fn start_segment(&mut self, first_timestamp: u32) {
+        let file_name = format!(
+            "{}_{}_{}.{}",
+            remux::sanitize(&self.name.app_name),
+            remux::sanitize(&self.name.stream_key),
+            Utc::now().format("%Y%m%dT%H%M%S%.3fZ"),
+            self.config.format.extension(),
+        );
+        let path = self.directory.join(file_name);
+
+        let mut segment = match Segment::create(path.clone(), self.config.format, first_timestamp) {
+            Ok(segment) => segment,
+            Err(e) => {
+                warn!("could not start recording to {}: {}", path.display(), e);
+                return;
+            }
+        };
+        info!("recording to {}", path.display());
+
+        for header in [&self.video_sequence_header, &self.audio_sequence_header]
+            .into_iter()
+            .flatten()
+        {
+            if let Err(e) = segment.write(header) {
+                warn!("could not write to {}: {}", path.display(), e);
+                return;
+            }
+        }
+        self.segment = Some(segment);
+
+        let removed = remux::remove_old_files(
+            &self.directory,
+            self.config.format.extension(),
+            self.config.max_files,
+            self.config.max_age_hours,
+        );
+        if let Err(e) = removed {
+            warn!(
+                "could not clean up old recordings in {}: {}",
+                self.directory.display(),
+                e
+            );
+        }
+    }
+
+    fn finish_segment(&mut self) {
+        if let Some(segment) = self.segment.take() {
+            let path = segment.path.clone();
+            match segment.finish() {
+                Ok(()) => debug!("finished recording {}", path.display()),
+                Err(e) => warn!("could not finish recording {}: {}", path.display(), e),
+            }
+        }
+    }
+}
+
+/// One recording file
+struct Segment {
+    path: PathBuf,
+    sink: SegmentSink,
+    /// Timestamps in the file start from zero
+    first_timestamp: u32,
+    bytes_written: u64,
+}
+
+enum SegmentSink {
+    Flv(FLVWriterWrapper<BufWriter<File>>),
+    /// FLV goes in, ffmpeg remuxes it into MP4 on its own thread
+    Mp4 {
+        input: Box<FLVWriterWrapper<BufferedSenderWriter<1024>>>,
+        remux_thread: JoinHandle<Result<(), ffmpeg::Error>>,
+    },
+}
+
+impl Segment {
+    fn create(path: PathBuf, format: RecordingFormat, first_timestamp: u32) -> io::Result<Self> {
+        let sink = match format {
+            RecordingFormat::Flv => {
+                SegmentSink::Flv(FLVWriterWrapper::new(BufWriter::new(File::create(&path)?)))
+            }
+            RecordingFormat::Mp4 => {
+                let (flv_tx, flv_rx) = std::sync::mpsc::channel();
+                let output_path = path.clone();
+                let remux_thread = thread::Builder::new()
+                    .name("mp4 remuxing thread".to_owned())
+                    .spawn(move || {
+                        let mut options = Dictionary::new();
+                        options.set("movflags", "frag_keyframe+empty_moov+default_base_moof");
+                        remux::remux_flv(flv_rx, &output_path, "mp4", options)
+                    })?;
+                SegmentSink::Mp4 {
+                    input: Box::new(FLVWriterWrapper::new(BufferedSenderWriter::new(flv_tx))),
+                    remux_thread,
+                }
+            }
+        };
+
+        let mut segment = Self {
+            path,
+            sink,
+            first_timestamp,
+            bytes_written: 0,
+        };
+        match &mut segment.sink {
+            SegmentSink::Flv(writer) => writer.write_header()?,
+            SegmentSink::Mp4 { input, .. } => input.write_header()?,
+        }
+        Ok(segment)
+    }
+
+    fn is_full(&self, config: &RecordingConfig, timestamp: u32) -> bool {
+        let duration = Duration::from_millis(timestamp.saturating_sub(self.first_timestamp) as u64);
+        (config.max_segment_secs > 0 && duration.as_secs() >= config.max_segment_secs)
+            || (config.max_segment_bytes > 0 && self.bytes_written >= config.max_segment_bytes)
+    }
+
+    fn write(&mut self, packet: &MediaPacket) -> io::Result<()> {
+        // audio can be a little older than the keyframe the file starts on
+        let timestamp = packet.timestamp.saturating_sub(self.first_timestamp);
+        let write = match &mut self.sink {
+            SegmentSink::Flv(writer) => remux::write_packet(writer, timestamp, packet),
+            SegmentSink::Mp4 { input, .. } => remux::write_packet(input, timestamp, packet),
+        };
+        write?;
+        self.bytes_written += packet.data.len() as u64;
+        Ok(())
+    }
+
+    fn finish(self) -> io::Result<()> {
+        match self.sink {
+            SegmentSink::Flv(mut writer) => writer.flush_inner(),
+            SegmentSink::Mp4 {
+                mut input,
+                remux_thread,
+            } => {
+                // closing the input lets ffmpeg write out the last fragment
+                let flushed = input.flush_inner();
+                drop(input);
+                match remux_thread.join() {
+                    Ok(Ok(())) => flushed,
+                    Ok(Err(e)) => Err(io::Error::new(io::ErrorKind::Other, e)),
+                    Err(_) => Err(io::Error::new(
+                        io::ErrorKind::Other,
+                        "mp4 remuxing thread panicked",
+                    )),
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+
+    use super::*;
+
+    fn config(max_segment_secs: u64, max_segment_bytes: u64) -> RecordingConfig {
+        RecordingConfig {
+            max_segment_secs,
+            max_segment_bytes,
+            ..RecordingConfig::default()
+        }
+    }
+
+    fn audio(timestamp: u32, len: usize) -> MediaPacket {
+        MediaPacket {
+            kind: MediaKind::Audio,
+            timestamp,
+            data: Bytes::from(vec![0xaf; len]),
+        }
+    }
+
+    #[test]
+    fn segments_fill_up_with_time() {
+        let directory = tempfile::tempdir().unwrap();
+        let segment =
+            Segment::create(directory.path().join("a.flv"), RecordingFormat::Flv, 5_000).unwrap();
+
+        let cases = [
+            (config(60, 0), 5_000, false, "just started"),
+            (config(60, 0), 64_999, false, "almost a minute"),
+            (config(60, 0), 65_000, true, "a minute"),
+            (config(60, 0), 1_000, false, "before the first timestamp"),
+            (config(0, 0), u32::MAX, false, "no limits"),
+        ];
+        for (config, timestamp, full, message) in cases {
+            assert_eq!(segment.is_full(&config, timestamp), full, "{}", message);
+        }
+        segment.finish().unwrap();
+    }
+
+    #[test]
+    fn segments_fill_up_with_bytes() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("a.flv");
+        let mut segment = Segment::create(path.clone(), RecordingFormat::Flv, 0).unwrap();
+        segment.write(&audio(0, 600)).unwrap();
+        assert!(!segment.is_full(&config(0, 1000), 20));
+        segment.write(&audio(20, 400)).unwrap();
+        assert!(
+            segment.is_full(&config(0, 1000), 40),
+            "1000 bytes of packets"
+        );
+        assert!(!segment.is_full(&config(0, 0), 40), "no limits");
+        assert!(
+            segment.is_full(&config(60, 1000), 40),
+            "either limit will do"
+        );
+        segment.finish().unwrap();
+        assert!(fs::metadata(&path).unwrap().len() > 1000);
+    }
+}
@@ -0,0 +1,366 @@
+//! Tells whoever receives the blurred stream where faces were redacted, so
+//! they don't have to run detection again.
+//!
+//! Regions travel two ways, both stamped with the PTS of the frame they
+//! describe:
+//!
+//! * an AMF0 `onCuePoint` data message, which goes out over RTMP and into FLV
+//!   recordings right before the frame's video tag:
+//!
+//!   ```text
+//!   onCuePoint {name: "faceblur", type: "event", time: <seconds>,
+//!               parameters: {regions: [{x, y, width, height, confidence, action}, ...]}}
+//!   ```
+//!
+//! * a user data unregistered SEI message inside the H.264 access unit itself,
+//!   made of [`SEI_UUID`] followed by JSON with the frame's PTS and its
+//!   regions, each written the way a line of the audit log (see
+//!   [`crate::audit`]) writes them:
+//!
+//!   ```text
+//!   {"pts_ms":40,"regions":[{"x":10,"y":20,"width":64,"height":64,"confidence":0.93,"action":"blurred"}]}
+//!   ```
+//!
+//! Only frames that had faces in them get either. A frame without them had
+//! nothing redacted.
+
+use bytes::{BufMut, Bytes, BytesMut};
+use serde::Serialize;
+
+use crate::{audit::Region, image_processing::Detection};
+
+/// Identifies our SEI messages among any others in the stream
+pub const SEI_UUID: [u8; 16] = *b"faceblur-regions";
+
+/// The cue point's `name`
+const CUE_POINT_NAME: &str = "faceblur";
+
+/// Script data messages go on a chunk stream of their own, so they don't
+/// disturb the header compression of the chunk streams rml_rtmp uses
+const DATA_CHUNK_STREAM_ID: u8 = 16;
+
+/// RTMP message type of an AMF0 data message
+const AMF0_DATA_MESSAGE: u8 = 18;
+
+/// Timestamps from here on need the extended timestamp field
+const EXTENDED_TIMESTAMP: u32 = 0xff_ffff;
+
+#[derive(Serialize)]
+struct SeiPayload<'a> {
+    pts_ms: i64,
+    regions: Vec<Region<'a>>,
+}
+
+/// The `onCuePoint` data message for the frame at `pts_ms`, i.e. the body of
+/// an FLV script data tag
+pub fn cue_point(pts_ms: i64, detections: &[Detection]) -> Bytes {
+    let mut amf = BytesMut::new();
+    write_string(&mut amf, "onCuePoint");
+
+    write_object_start(&mut amf);
+    write_property(&mut amf, "name");
+    write_string(&mut amf, CUE_POINT_NAME);
+    write_property(&mut amf, "type");
+    write_string(&mut amf, "event");
+    write_property(&mut amf, "time");
+    write_number(&mut amf, pts_ms as f64 / 1000.0);
+    write_property(&mut amf, "parameters");
+    write_object_start(&mut amf);
+    write_property(&mut amf, "regions");
+    // strict array
+    amf.put_u8(0x0a);
+    amf.put_u32(detections.len() as u32);
+    for detection in detections {
+        write_object_start(&mut amf);
+        for (name, value) in [
+            ("x", detection.x as f64),
+            ("y", detection.y as f64),
+            ("width", detection.width as f64),
+            ("height", detection.height as f64),
+            ("confidence", detection.confidence as f64),
+        ] {
+            write_property(&mut amf, name);
+            write_number(&mut amf, value);
+        }
+        write_property(&mut amf, "action");
+        write_string(
+            &mut amf,
+            if detection.blurred {
+                "blurred"
+            } else {
+                "whitelisted"
+            },
+        );
+        if !detection.identity.is_empty() {
+            write_property(&mut amf, "identity");
+            write_string(&mut amf, &detection.identity);
+        }
+        write_object_end(&mut amf);
+    }
+    write_object_end(&mut amf);
+    write_object_end(&mut amf);
+
+    amf.freeze()
+}
+
+/// What goes into a user data unregistered SEI message (payload type 5) for
+/// the frame at `pts_ms`, UUID included
+pub fn sei_payload(pts_ms: i64, detections: &[Detection]) -> Vec<u8> {
+    let mut payload = SEI_UUID.to_vec();
+    serde_json::to_writer(
+        &mut payload,
+        &SeiPayload {
+            pts_ms,
+            regions: detections.iter().map(Region::from).collect(),
+        },
+    )
+    .expect("regions always serialize");
+    payload
+}
+
+/// Chunk an AMF0 data message for sending on an RTMP connection whose
+/// outgoing chunk size is `chunk_size`.
+///
+/// rml_rtmp 0.6 can only send `onMetaData`, so this does what its chunk
+/// serializer would, always with full headers. It has to be sent between
+/// whole packets from the session, never in the middle of one.
+pub fn rtmp_data_message(
+    message_stream_id: u32,
+    timestamp: u32,
+    data: &[u8],
+    chunk_size: u32,
+) -> Vec<u8> {
+    let chunk_size = chunk_size.max(1) as usize;
+    let extended = timestamp >= EXTENDED_TIMESTAMP;
+    let mut bytes = Vec::with_capacity(data.len() + 16 + data.len() / chunk_size * 5);
+
+    // basic header with format 0, then the message header
+    bytes.push(DATA_CHUNK_STREAM_ID);
+    bytes.extend_from_slice(&timestamp.min(EXTENDED_TIMESTAMP).to_be_bytes()[1..]);
+    bytes.extend_from_slice(&(data.len() as u32).to_be_bytes()[1..]);
+    bytes.push(AMF0_DATA_MESSAGE);
+    // the one little endian field in RTMP
+    bytes.extend_from_slice(&message_stream_id.to_le_bytes());
+    if extended {
+        bytes.extend_from_slice(&timestamp.to_be_bytes());
+    }
+
+    for (i, chunk) in data.chunks(chunk_size).enumerate() {
+        if i > 0 {
+            // format 3, i.e. same header as before
+            bytes.push(0xc0 | DATA_CHUNK_STREAM_ID);
+            if extended {
+                bytes.extend_from_slice(&timestamp.to_be_bytes());
+            }
+        }
+        bytes.extend_from_slice(chunk);
+    }
+    bytes
+}
+
+fn write_number(amf: &mut BytesMut, value: f64) {
+    amf.put_u8(0x00);
+    amf.put_f64(value);
+}
+
+fn write_string(amf: &mut BytesMut, value: &str) {
+    amf.put_u8(0x02);
+    write_property(amf, value);
+}
+
+/// A property name, which is a string without the type marker
+fn write_property(amf: &mut BytesMut, name: &str) {
+    // nothing we write comes close to 64k
+    let name = &name.as_bytes()[..name.len().min(u16::MAX as usize)];
+    amf.put_u16(name.len() as u16);
+    amf.put_slice(name);
+}
+
+fn write_object_start(amf: &mut BytesMut) {
+    amf.put_u8(0x03);
+}
+
+fn write_object_end(amf: &mut BytesMut) {
+    // an empty property name and the object end marker
+    amf.put_slice(&[0x00, 0x00, 0x09]);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn detection(blurred: bool, identity: &str) -> Detection {
+        Detection {
+            x: 10,
+            y: 20,
+            width: 64,
+            height: 48,
+            confidence: 0.5,
+            blurred,
+            identity: identity.to_string(),
+            distance: if identity.is_empty() { -1.0 } else { 0.25 },
+        }
+    }
+
+    /// AMF0, written out the long way
+    enum Amf {
+        String(&'static str),
+        Number(f64),
+        Object(Vec<(&'static str, Amf)>),
+        StrictArray(Vec<Amf>),
+    }
+
+    fn encode(value: &Amf, bytes: &mut Vec<u8>) {
+        match value {
+            Amf::String(value) => {
+                bytes.push(0x02);
+                bytes.extend_from_slice(&(value.len() as u16).to_be_bytes());
+                bytes.extend_from_slice(value.as_bytes());
+            }
+            Amf::Number(value) => {
+                bytes.push(0x00);
+                bytes.extend_from_slice(&value.to_be_bytes());
+            }
+            Amf::Object(properties) => {
+                bytes.push(0x03);
+                for (name, value) in properties {
+                    bytes.extend_from_slice(&(name.len() as u16).to_be_bytes());
+                    bytes.extend_from_slice(name.as_bytes());
+                    encode(value, bytes);
+                }
+                bytes.extend_from_slice(&[0x00, 0x00, 0x09]);
+            }
+            Amf::StrictArray(values) => {
+                bytes.push(0x0a);
+                bytes.extend_from_slice(&(values.len() as u32).to_be_bytes());
+                for value in values {
+                    encode(value, bytes);
+                }
+            }
+        }
+    }
+
+    fn region(action: &'static str, identity: Option<&'static str>) -> Amf {
+        let mut properties = vec![
+            ("x", Amf::Number(10.0)),
+            ("y", Amf::Number(20.0)),
+            ("width", Amf::Number(64.0)),
+            ("height", Amf::Number(48.0)),
+            ("confidence", Amf::Number(0.5)),
+            ("action", Amf::String(action)),
+        ];
+        if let Some(identity) = identity {
+            properties.push(("identity", Amf::String(identity)));
+        }
+        Amf::Object(properties)
+    }
+
+    #[test]
+    fn cue_points_are_amf0() {
+        let cases = [
+            (1500, vec![], vec![], "no regions"),
+            (
+                40,
+                vec![detection(true, "")],
+                vec![region("blurred", None)],
+                "a blurred face",
+            ),
+            (
+                40,
+                vec![detection(true, ""), detection(false, "alice")],
+                vec![
+                    region("blurred", None),
+                    region("whitelisted", Some("alice")),
+                ],
+                "a whitelisted face",
+            ),
+        ];
+
+        for (pts_ms, detections, regions, message) in cases {
+            let mut expected = Vec::new();
+            encode(&Amf::String("onCuePoint"), &mut expected);
+            encode(
+                &Amf::Object(vec![
+                    ("name", Amf::String("faceblur")),
+                    ("type", Amf::String("event")),
+                    ("time", Amf::Number(pts_ms as f64 / 1000.0)),
+                    (
+                        "parameters",
+                        Amf::Object(vec![("regions", Amf::StrictArray(regions))]),
+                    ),
+                ]),
+                &mut expected,
+            );
+            assert_eq!(cue_point(pts_ms, &detections), expected, "{}", message);
+        }
+    }
+
+    #[test]
+    fn sei_payloads_are_uuid_and_json() {
+        let payload = sei_payload(40, &[detection(true, ""), detection(false, "alice")]);
+
+        assert_eq!(&payload[..16], &SEI_UUID);
+        assert_eq!(
+            std::str::from_utf8(&payload[16..]).unwrap(),
+            concat!(
+                r#"{"pts_ms":40,"regions":["#,
+                r#"{"x":10,"y":20,"width":64,"height":48,"confidence":0.5,"action":"blurred"},"#,
+                r#"{"x":10,"y":20,"width":64,"height":48,"confidence":0.5,"action":"whitelisted","identity":"alice"}]}"#,
+            )
+        );
+    }
+
+    #[test]
+    fn data_messages_fit_in_one_chunk() {
+        assert_eq!(
+            rtmp_data_message(1, 0x01_0203, b"abc", 128),
+            [
+                &[16][..],
+                // timestamp, length, type
+                &[0x01, 0x02, 0x03, 0x00, 0x00, 0x03, 18],
+                // message stream id, little endian
+                &[1, 0, 0, 0],
+                b"abc",
+            ]
+            .concat()
+        );
+    }
+
+    #[test]
+    fn data_messages_are_split_into_chunks() {
+        assert_eq!(
+            rtmp_data_message(1, 40, b"abcdefghij", 4),
+            [
+                &[16, 0x00, 0x00, 40, 0x00, 0x00, 10, 18, 1, 0, 0, 0][..],
+                b"abcd",
+                &[0xc0 | 16],
+                b"efgh",
+                &[0xc0 | 16],
+                b"ij",
+            ]
+            .concat()
+        );
+    }
+
+    #[test]
+    fn late_data_messages_use_extended_timestamps() {
+        let timestamp: u32 = 0x0100_0000;
+        assert_eq!(
+            rtmp_data_message(1, timestamp, b"abcdef", 4),
+            [
+                &[16, 0xff, 0xff, 0xff, 0x00, 0x00, 6, 18, 1, 0, 0, 0][..],
+                &timestamp.to_be_bytes(),
+                b"abcd",
+                &[0xc0 | 16],
+                &timestamp.to_be_bytes(),
+                b"ef",
+            ]
+            .concat()
+        );
+        // the marker value itself needs the extended field too
+        assert_eq!(
+            rtmp_data_message(1, EXTENDED_TIMESTAMP, b"a", 4)[12..16],
+            EXTENDED_TIMESTAMP.to_be_bytes()
+        );
+    }
+}
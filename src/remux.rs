@@ -0,0 +1,320 @@
+//! Plumbing shared by the sinks that hand the blurred stream to one of
+//! ffmpeg's muxers on a thread of their own, i.e. [`crate::recording`] and
+//! [`crate::hls`], and by the other things that keep files per stream.
+
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+    sync::mpsc::{sync_channel, Receiver, TrySendError},
+    time::Duration,
+};
+
+use arrayvec::ArrayVec;
+use ffmpeg::{codec, encoder, format, media, Dictionary};
+use ffmpeg_next as ffmpeg;
+use tokio::sync::broadcast;
+use tracing::{info, warn};
+
+use crate::{
+    custom_ffmpeg_io::{read_from_custom_input_as, MPSCReader},
+    flv_file::FLVWriterWrapper,
+    stream_registry::{MediaKind, MediaPacket, StreamName},
+    video_tags::{VideoCodec, VideoTag},
+};
+
+/// Hand the packets of a stream over to a thread. The broadcast channel can
+/// only be waited on from async code, so this spawns a task to do the waiting.
+///
+/// The returned queue holds `queue_len` packets. If the thread falls further
+/// behind than that, packets are skipped up to the next keyframe, and `what`
+/// is used to say who couldn't keep up.
+pub fn forward_packets(
+    mut packets: broadcast::Receiver<MediaPacket>,
+    queue_len: usize,
+    what: &'static str,
+) -> Receiver<MediaPacket> {
+    let (packet_tx, packet_rx) = sync_channel(queue_len);
+    tokio::spawn(async move {
+        let mut waiting_for_keyframe = false;
+        loop {
+            let packet = match packets.recv().await {
+                Ok(packet) => packet,
+                Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                    warn!("{} fell {} packets behind", what, skipped);
+                    waiting_for_keyframe = true;
+                    continue;
+                }
+                Err(broadcast::error::RecvError::Closed) => return,
+            };
+            if waiting_for_keyframe && packet.kind == MediaKind::Video {
+                if !packet.is_keyframe() {
+                    continue;
+                }
+                waiting_for_keyframe = false;
+            }
+            match packet_tx.try_send(packet) {
+                Ok(()) => (),
+                Err(TrySendError::Full(_)) => {
+                    if !waiting_for_keyframe {
+                        warn!("{} is not keeping up, skipping to the next keyframe", what);
+                    }
+                    waiting_for_keyframe = true;
+                }
+                Err(TrySendError::Disconnected(_)) => return,
+            }
+        }
+    });
+    packet_rx
+}
+
+/// Copies the audio and video out of an FLV byte stream into `path` using
+/// ffmpeg's `format` muxer, until the byte stream ends
+pub fn remux_flv(
+    flv: Receiver<ArrayVec<u8, 1024>>,
+    path: &Path,
+    format: &str,
+    options: Dictionary,
+) -> Result<(), ffmpeg::Error> {
+    let mut input = read_from_custom_input_as(MPSCReader::new(flv), "flv")?;
+    let mut output = format::output_as(&path, format)?;
+
+    let mut stream_mapping = vec![None; input.nb_streams() as usize];
+    for (input_index, input_stream) in input.streams().enumerate() {
+        let medium = input_stream.parameters().medium();
+        if medium != media::Type::Video && medium != media::Type::Audio {
+            continue;
+        }
+        let mut output_stream = output.add_stream(encoder::find(codec::Id::None))?;
+        output_stream.set_parameters(input_stream.parameters());
+        // FLV's codec tags mean nothing to other muxers, so let them pick their own
+        unsafe {
+            (*output_stream.parameters().as_mut_ptr()).codec_tag = 0;
+        }
+        stream_mapping[input_index] = Some((output_stream.index(), input_stream.time_base()));
+    }
+
+    output.write_header_with(options)?;
+
+    for (input_stream, mut packet) in input.packets() {
+        // the demuxer adds a stream for script data it doesn't know when it first sees it
+        let (output_index, input_time_base) =
+            match stream_mapping.get(input_stream.index()).copied().flatten() {
+                Some(mapping) => mapping,
+                None => continue,
+            };
+        let output_time_base = output.stream(output_index).unwrap().time_base();
+        packet.rescale_ts(input_time_base, output_time_base);
+        packet.set_position(-1);
+        packet.set_stream(output_index);
+        packet.write_interleaved(&mut output)?;
+    }
+    output.write_trailer()
+}
+
+/// The codec of `packet` if it is video that [`remux_flv`] can't take. ffmpeg
+/// 5's flv demuxer only knows the original video tag header, so the HEVC and
+/// AV1 that `codec = "same"` can send out would be garbage to it.
+pub fn unremuxable_codec(packet: &MediaPacket) -> Option<VideoCodec> {
+    if packet.kind != MediaKind::Video {
+        return None;
+    }
+    VideoTag::parse(&packet.data)
+        .filter(|tag| tag.enhanced)
+        .map(|tag| tag.codec)
+}
+
+/// Write one packet of the stream as an FLV tag
+pub fn write_packet<W: io::Write>(
+    writer: &mut FLVWriterWrapper<W>,
+    timestamp: u32,
+    packet: &MediaPacket,
+) -> io::Result<()> {
+    match packet.kind {
+        MediaKind::Video => writer.write_video_bytes(timestamp, &packet.data),
+        MediaKind::Audio => writer.write_audio_bytes(timestamp, &packet.data),
+        MediaKind::Data => writer.write_script_bytes(timestamp, &packet.data),
+    }
+}
+
+/// Where a sink rooted at `root` keeps the files for `name`
+pub fn stream_directory(root: &Path, name: &StreamName) -> PathBuf {
+    root.join(sanitize(&name.app_name))
+        .join(sanitize(&name.stream_key))
+}
+
+/// App names and stream keys come from the client, so keep them from
+/// wandering out of the directory we were given
+pub fn sanitize(name: &str) -> String {
+    let sanitized: String = name
+        .chars()
+        .map(|c| match c {
+            'a'..='z' | 'A'..='Z' | '0'..='9' | '-' | '_' | '.' => c,
+            _ => '_',
+        })
+        .collect();
+    match sanitized.as_str() {
+        "" | "." | ".." => "_".to_owned(),
+        _ => sanitized,
+    }
+}
+
+/// Delete the oldest files with `extension` in `directory` until there are at
+/// most `max_files` of them and none older than `max_age_hours` (0 for no
+/// limit). File names have to start with the time they were started (after
+/// the stream name, which is the same for every file), so they sort oldest
+/// first. The newest file is always kept, since it is the one being written.
+pub fn remove_old_files(
+    directory: &Path,
+    extension: &str,
+    max_files: usize,
+    max_age_hours: u64,
+) -> io::Result<()> {
+    if max_files == 0 && max_age_hours == 0 {
+        return Ok(());
+    }
+
+    let mut files = Vec::new();
+    for entry in fs::read_dir(directory)? {
+        let path = entry?.path();
+        if path.extension().and_then(|e| e.to_str()) == Some(extension) {
+            files.push(path);
+        }
+    }
+    files.sort();
+
+    let too_many = match max_files {
+        0 => 0,
+        max_files => files.len().saturating_sub(max_files),
+    };
+    let max_age = Duration::from_secs(max_age_hours * 60 * 60);
+    for (i, path) in files.iter().enumerate() {
+        let too_old = max_age_hours > 0
+            && fs::metadata(path)?
+                .modified()?
+                .elapsed()
+                .unwrap_or_default()
+                > max_age;
+        if (i < too_many || too_old) && i + 1 < files.len() {
+            info!("deleting old file {}", path.display());
+            fs::remove_file(path)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::process::Command;
+
+    use super::*;
+
+    #[test]
+    fn sanitize_names() {
+        let cases = [
+            ("live", "live", "plain"),
+            ("My-Show_2.flv", "My-Show_2.flv", "everything allowed"),
+            ("", "_", "empty"),
+            (".", "_", "this directory"),
+            ("..", "_", "the parent"),
+            ("a/b", "a_b", "a slash"),
+            ("../..", ".._..", "a way out"),
+            ("a\\b", "a_b", "a backslash"),
+            ("café", "caf_", "non-ASCII"),
+            ("ключ", "____", "only non-ASCII"),
+        ];
+        for (name, expected, message) in cases {
+            assert_eq!(sanitize(name), expected, "{}", message);
+        }
+    }
+
+    #[test]
+    fn stream_directories_stay_under_the_root() {
+        let root = Path::new("/srv/recordings");
+        assert_eq!(
+            stream_directory(root, &StreamName::new("live", "cam")),
+            root.join("live").join("cam")
+        );
+        assert_eq!(
+            stream_directory(root, &StreamName::new("..", "../../etc")),
+            root.join("_").join(".._.._etc")
+        );
+    }
+
+    /// Files named so they sort oldest first, the last being the newest
+    fn recordings(count: usize) -> (tempfile::TempDir, Vec<PathBuf>) {
+        let directory = tempfile::tempdir().unwrap();
+        let files = (0..count)
+            .map(|i| {
+                let path = directory
+                    .path()
+                    .join(format!("live_cam_2022-06-01T12-00-0{}.flv", i));
+                fs::write(&path, b"FLV").unwrap();
+                path
+            })
+            .collect();
+        fs::write(directory.path().join("notes.txt"), b"").unwrap();
+        (directory, files)
+    }
+
+    fn make_old(path: &Path) {
+        let status = Command::new("touch")
+            .args(["-d", "3 hours ago"])
+            .arg(path)
+            .status()
+            .unwrap();
+        assert!(status.success());
+    }
+
+    fn remaining(files: &[PathBuf]) -> Vec<bool> {
+        files.iter().map(|path| path.exists()).collect()
+    }
+
+    #[test]
+    fn no_limits_keep_everything() {
+        let (directory, files) = recordings(3);
+        files.iter().for_each(|path| make_old(path));
+        remove_old_files(directory.path(), "flv", 0, 0).unwrap();
+        assert_eq!(remaining(&files), [true, true, true]);
+    }
+
+    #[test]
+    fn too_many_files_deletes_the_oldest() {
+        let cases = [
+            (5, vec![true, true, true, true], "under the limit"),
+            (4, vec![true, true, true, true], "at the limit"),
+            (2, vec![false, false, true, true], "over the limit"),
+            (1, vec![false, false, false, true], "just the newest"),
+        ];
+        for (max_files, expected, message) in cases {
+            let (directory, files) = recordings(4);
+            remove_old_files(directory.path(), "flv", max_files, 0).unwrap();
+            assert_eq!(remaining(&files), expected, "{}", message);
+            assert!(
+                directory.path().join("notes.txt").exists(),
+                "{}: other files are left alone",
+                message
+            );
+        }
+    }
+
+    #[test]
+    fn old_files_are_deleted() {
+        let (directory, files) = recordings(3);
+        make_old(&files[0]);
+        remove_old_files(directory.path(), "flv", 0, 1).unwrap();
+        assert_eq!(remaining(&files), [false, true, true]);
+
+        let (directory, files) = recordings(3);
+        make_old(&files[0]);
+        remove_old_files(directory.path(), "flv", 0, 4).unwrap();
+        assert_eq!(remaining(&files), [true, true, true], "not old enough");
+    }
+
+    #[test]
+    fn the_newest_file_is_never_deleted() {
+        let (directory, files) = recordings(3);
+        files.iter().for_each(|path| make_old(path));
+        remove_old_files(directory.path(), "flv", 0, 1).unwrap();
+        assert_eq!(remaining(&files), [false, false, true]);
+    }
+}
@@ -0,0 +1,89 @@
+//! Pixel format conversion that keeps up with the input.
+//!
+//! An ffmpeg scaler is built for one input size and pixel format and refuses
+//! anything else, but publishers can switch resolution mid-stream (OBS scene
+//! changes, adaptive encoders). A [`Rescaler`] rebuilds its scaler whenever
+//! the frames it is given change.
+//!
+//! Unlike the bare scaler, it also keeps each frame's timestamp.
+
+use ffmpeg::{frame, software::scaling, util::format::Pixel};
+use ffmpeg_next as ffmpeg;
+use tracing::info;
+
+/// Converts frames to one pixel format, and optionally one size, whatever
+/// comes in
+pub struct Rescaler {
+    format: Pixel,
+    /// `None` keeps the size of the input
+    size: Option<(u32, u32)>,
+    /// Built from the first frame, since that is when we know its format
+    scaler: Option<scaling::Context>,
+    /// What to say if the input changes
+    what: &'static str,
+}
+
+impl Rescaler {
+    /// Converts to `format`, keeping whatever size the input has
+    pub fn new(format: Pixel, what: &'static str) -> Self {
+        Self {
+            format,
+            size: None,
+            scaler: None,
+            what,
+        }
+    }
+
+    /// Converts to `format` and always scales to `width` x `height`, so
+    /// whatever comes after never sees a change
+    pub fn with_size(format: Pixel, width: u32, height: u32, what: &'static str) -> Self {
+        Self {
+            size: Some((width, height)),
+            ..Self::new(format, what)
+        }
+    }
+
+    /// Convert `input`, with its timestamp
+    pub fn run(&mut self, input: &frame::Video) -> Result<frame::Video, ffmpeg::Error> {
+        let changed = match &self.scaler {
+            Some(scaler) => {
+                let current = scaler.input();
+                current.format != input.format()
+                    || current.width != input.width()
+                    || current.height != input.height()
+            }
+            None => true,
+        };
+        if changed {
+            if let Some(scaler) = &self.scaler {
+                let old = scaler.input();
+                info!(
+                    "{} changed from {}x{} {:?} to {}x{} {:?}",
+                    self.what,
+                    old.width,
+                    old.height,
+                    old.format,
+                    input.width(),
+                    input.height(),
+                    input.format()
+                );
+            }
+            let (width, height) = self.size.unwrap_or((input.width(), input.height()));
+            self.scaler = Some(scaling::Context::get(
+                input.format(),
+                input.width(),
+                input.height(),
+                self.format,
+                width,
+                height,
+                scaling::Flags::BILINEAR,
+            )?);
+        }
+
+        let mut output = frame::Video::empty();
+        self.scaler.as_mut().unwrap().run(input, &mut output)?;
+        // decoders fill in the best effort timestamp, everything else only has a PTS
+        output.set_pts(input.timestamp().or_else(|| input.pts()));
+        Ok(output)
+    }
+}
@@ -0,0 +1,685 @@
+//! Decides where each published stream gets pushed to once it is blurred.
+//!
+//! Routes are tried in order and the first one whose `app` and `stream_key`
+//! patterns both match wins. A pattern is plain text with two kinds of holes:
+//!
+//! - `*` matches anything
+//! - `{name}` matches anything and remembers it as `name`
+//!
+//! Destination URLs and stream keys are templates that can use `{app}`,
+//! `{stream_key}` and anything the route's patterns captured:
+//!
+//! ```toml
+//! [[routes]]
+//! app = "sports-*"
+//! stream_key = "{team}-{show}"
+//! destinations = [
+//!     { url = "rtmp://a.rtmp.youtube.com/live2", stream_key = "{show}" },
+//!     { url = "rtmp://ingest.example.com/{team}", stream_key = "{stream_key}" },
+//! ]
+//! ```
+
+use std::{
+    collections::{HashMap, HashSet},
+    fmt,
+};
+
+use thiserror::Error;
+use tracing::warn;
+
+use crate::{
+    config::{DestinationConfig, RouteConfig},
+    stream_registry::StreamName,
+};
+
+const DEFAULT_RTMP_PORT: u16 = 1935;
+
+/// Why a route in the config can't be used
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum RouteError {
+    /// A `{` without a `}` in a pattern or destination
+    #[error("unclosed '{{' in {0:?}")]
+    UnclosedBrace(String),
+
+    /// A destination uses a placeholder its route's patterns don't capture
+    #[error("{template:?} uses {{{name}}}, which the route does not capture")]
+    UnknownPlaceholder {
+        /// The destination URL or stream key it is in
+        template: String,
+        /// Without the braces
+        name: String,
+    },
+
+    /// A destination isn't `rtmp://`
+    #[error("{0:?} is not an rtmp:// URL")]
+    NotRtmp(String),
+
+    /// A destination URL without a host
+    #[error("{0:?} has no host")]
+    MissingHost(String),
+
+    /// A destination URL without a path
+    #[error("{0:?} has no app name")]
+    MissingApp(String),
+
+    /// A destination URL's port isn't a number up to 65535
+    #[error("{0:?} has an invalid port")]
+    BadPort(String),
+}
+
+/// Somewhere to push a stream to
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Destination {
+    /// Everything up to and including the app, i.e. what RTMP calls the tcUrl
+    pub url: String,
+    /// Where to connect to. IPv6 addresses come without their brackets.
+    pub host: String,
+    /// 1935 unless the URL says otherwise
+    pub port: u16,
+    /// What to connect to on the server
+    pub app: String,
+    /// What to publish as
+    pub stream_key: String,
+}
+
+impl Destination {
+    fn parse(url: String, stream_key: String) -> Result<Self, RouteError> {
+        let rest = url
+            .strip_prefix("rtmp://")
+            .ok_or_else(|| RouteError::NotRtmp(url.clone()))?;
+        let (authority, app) = rest
+            .split_once('/')
+            .ok_or_else(|| RouteError::MissingApp(url.clone()))?;
+        let app = app.trim_end_matches('/');
+        if app.is_empty() {
+            return Err(RouteError::MissingApp(url));
+        }
+
+        // an IPv6 host is bracketed, so its colons aren't taken for the port's
+        let (host, port) = match authority.strip_prefix('[') {
+            Some(bracketed) => {
+                let (host, rest) = bracketed
+                    .split_once(']')
+                    .ok_or_else(|| RouteError::MissingHost(url.clone()))?;
+                match rest {
+                    "" => (host, None),
+                    rest => (
+                        host,
+                        Some(
+                            rest.strip_prefix(':')
+                                .ok_or_else(|| RouteError::BadPort(url.clone()))?,
+                        ),
+                    ),
+                }
+            }
+            None => match authority.rsplit_once(':') {
+                Some((host, port)) => (host, Some(port)),
+                None => (authority, None),
+            },
+        };
+        let port = match port {
+            Some(port) => port.parse().map_err(|_| RouteError::BadPort(url.clone()))?,
+            None => DEFAULT_RTMP_PORT,
+        };
+        if host.is_empty() {
+            return Err(RouteError::MissingHost(url));
+        }
+
+        Ok(Self {
+            host: host.to_owned(),
+            port,
+            app: app.to_owned(),
+            stream_key,
+            url: url.trim_end_matches('/').to_owned(),
+        })
+    }
+}
+
+/// Stream keys are secrets, so only the URL is shown
+impl fmt::Display for Destination {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.url)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Part {
+    Literal(String),
+    /// `*`, or `{name}` in a pattern. `Hole(None)` can only come from `*`.
+    Hole(Option<String>),
+}
+
+/// Splits `s` into literals and `{name}` holes. `*` only means something in
+/// patterns, so it is up to the caller to ask for it.
+fn parse_parts(s: &str, star_is_hole: bool) -> Result<Vec<Part>, RouteError> {
+    let mut parts = Vec::new();
+    let mut literal = String::new();
+    let mut chars = s.chars();
+
+    while let Some(c) = chars.next() {
+        let hole = match c {
+            '*' if star_is_hole => None,
+            '{' => {
+                let mut name = String::new();
+                loop {
+                    match chars.next() {
+                        Some('}') => break,
+                        Some(c) => name.push(c),
+                        None => return Err(RouteError::UnclosedBrace(s.to_owned())),
+                    }
+                }
+                Some(name)
+            }
+            c => {
+                literal.push(c);
+                continue;
+            }
+        };
+        if !literal.is_empty() {
+            parts.push(Part::Literal(std::mem::take(&mut literal)));
+        }
+        parts.push(Part::Hole(hole));
+    }
+    if !literal.is_empty() {
+        parts.push(Part::Literal(literal));
+    }
+    Ok(parts)
+}
+
+/// An `app` or `stream_key` pattern, also used by [`crate::policy`]
+#[derive(Debug, Clone)]
+pub struct Pattern {
+    parts: Vec<Part>,
+}
+
+impl Pattern {
+    /// See the [module docs](self) for the syntax
+    pub fn parse(s: &str) -> Result<Self, RouteError> {
+        Ok(Self {
+            parts: parse_parts(s, true)?,
+        })
+    }
+
+    fn captures(&self) -> impl Iterator<Item = &str> {
+        self.parts.iter().filter_map(|part| match part {
+            Part::Hole(Some(name)) => Some(name.as_str()),
+            _ => None,
+        })
+    }
+
+    /// Adds whatever the pattern captured to `captures` if it matches
+    pub fn matches(&self, input: &str, captures: &mut HashMap<String, String>) -> bool {
+        let mut found = Vec::new();
+        if match_parts(&self.parts, input, &mut found) {
+            captures.extend(found);
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// Holes match as little as they can, so `{a}-{b}` splits on the first dash
+fn match_parts(parts: &[Part], input: &str, found: &mut Vec<(String, String)>) -> bool {
+    match_from(parts, input, 0, 0, found, &mut HashSet::new())
+}
+
+/// Matches `parts[part..]` against `input[offset..]`. Whether that can match
+/// doesn't depend on what came before, so every `(part, offset)` that failed
+/// once is remembered in `failed`. Without that, patterns with many holes
+/// backtrack exponentially on inputs that almost match.
+fn match_from(
+    parts: &[Part],
+    input: &str,
+    part: usize,
+    offset: usize,
+    found: &mut Vec<(String, String)>,
+    failed: &mut HashSet<(usize, usize)>,
+) -> bool {
+    if failed.contains(&(part, offset)) {
+        return false;
+    }
+
+    let matched = match parts.get(part) {
+        None => offset == input.len(),
+        Some(Part::Literal(literal)) => {
+            input[offset..].starts_with(literal.as_str())
+                && match_from(
+                    parts,
+                    input,
+                    part + 1,
+                    offset + literal.len(),
+                    found,
+                    failed,
+                )
+        }
+        Some(Part::Hole(name)) => input[offset..]
+            .char_indices()
+            .map(|(split, _)| offset + split)
+            .chain([input.len()])
+            .any(|end| {
+                let before = found.len();
+                if let Some(name) = name {
+                    found.push((name.clone(), input[offset..end].to_owned()));
+                }
+                let matched = match_from(parts, input, part + 1, end, found, failed);
+                if !matched {
+                    found.truncate(before);
+                }
+                matched
+            }),
+    };
+    if !matched {
+        failed.insert((part, offset));
+    }
+    matched
+}
+
+#[derive(Debug, Clone)]
+struct Template {
+    parts: Vec<Part>,
+}
+
+impl Template {
+    /// Fails if the template uses a name that `known` doesn't recognize
+    fn parse(s: &str, known: impl Fn(&str) -> bool) -> Result<Self, RouteError> {
+        let parts = parse_parts(s, false)?;
+        for part in &parts {
+            if let Part::Hole(Some(name)) = part {
+                if !known(name) {
+                    return Err(RouteError::UnknownPlaceholder {
+                        template: s.to_owned(),
+                        name: name.clone(),
+                    });
+                }
+            }
+        }
+        Ok(Self { parts })
+    }
+
+    fn render(&self, values: &HashMap<String, String>) -> String {
+        self.parts
+            .iter()
+            .map(|part| match part {
+                Part::Literal(literal) => literal.as_str(),
+                Part::Hole(Some(name)) => values.get(name).map_or("", String::as_str),
+                Part::Hole(None) => "",
+            })
+            .collect()
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Route {
+    app: Pattern,
+    stream_key: Pattern,
+    destinations: Vec<(Template, Template)>,
+}
+
+impl Route {
+    fn new(config: &RouteConfig) -> Result<Self, RouteError> {
+        let app = Pattern::parse(&config.app)?;
+        let stream_key = Pattern::parse(&config.stream_key)?;
+
+        let known = |name: &str| {
+            name == "app"
+                || name == "stream_key"
+                || app
+                    .captures()
+                    .chain(stream_key.captures())
+                    .any(|c| c == name)
+        };
+        let destinations = config
+            .destinations
+            .iter()
+            .map(|DestinationConfig { url, stream_key }| {
+                if !url.starts_with("rtmp://") {
+                    return Err(RouteError::NotRtmp(url.clone()));
+                }
+                Ok((
+                    Template::parse(url, known)?,
+                    Template::parse(stream_key, known)?,
+                ))
+            })
+            .collect::<Result<_, _>>()?;
+
+        Ok(Self {
+            app,
+            stream_key,
+            destinations,
+        })
+    }
+}
+
+/// The routing table from the config. Streams that no route matches are only
+/// served to local players.
+#[derive(Debug, Clone, Default)]
+pub struct Router {
+    routes: Vec<Route>,
+}
+
+impl Router {
+    /// Checks every route, so none can fail later
+    pub fn new(routes: &[RouteConfig]) -> Result<Self, RouteError> {
+        Ok(Self {
+            routes: routes.iter().map(Route::new).collect::<Result<_, _>>()?,
+        })
+    }
+
+    /// Where `name` should be pushed to, according to the first matching route
+    pub fn destinations(&self, name: &StreamName) -> Vec<Destination> {
+        let mut values = HashMap::new();
+        let route = self.routes.iter().find(|route| {
+            values.clear();
+            route.app.matches(&name.app_name, &mut values)
+                && route.stream_key.matches(&name.stream_key, &mut values)
+        });
+        let route = match route {
+            Some(route) => route,
+            None => return Vec::new(),
+        };
+        values.insert("app".to_owned(), name.app_name.clone());
+        values.insert("stream_key".to_owned(), name.stream_key.clone());
+
+        route
+            .destinations
+            .iter()
+            .filter_map(|(url, stream_key)| {
+                match Destination::parse(url.render(&values), stream_key.render(&values)) {
+                    Ok(destination) => Some(destination),
+                    Err(e) => {
+                        warn!("not pushing {} to a destination: {}", name, e);
+                        None
+                    }
+                }
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::*;
+    use crate::config::Config;
+
+    /// `(name, value)` pairs a pattern should capture
+    type Captures = &'static [(&'static str, &'static str)];
+
+    fn literal(s: &str) -> Part {
+        Part::Literal(s.to_owned())
+    }
+
+    fn hole(name: &str) -> Part {
+        Part::Hole(Some(name.to_owned()))
+    }
+
+    fn captures(pairs: &[(&str, &str)]) -> HashMap<String, String> {
+        pairs
+            .iter()
+            .map(|&(name, value)| (name.to_owned(), value.to_owned()))
+            .collect()
+    }
+
+    #[test]
+    fn parse_patterns() {
+        let cases = [
+            ("", vec![]),
+            ("live", vec![literal("live")]),
+            ("*", vec![Part::Hole(None)]),
+            ("sports-*", vec![literal("sports-"), Part::Hole(None)]),
+            (
+                "{team}-{show}",
+                vec![hole("team"), literal("-"), hole("show")],
+            ),
+            (
+                "a*{b}c",
+                vec![literal("a"), Part::Hole(None), hole("b"), literal("c")],
+            ),
+            ("{}", vec![hole("")]),
+            ("ü{ñ}é", vec![literal("ü"), hole("ñ"), literal("é")]),
+        ];
+        for (pattern, parts) in cases {
+            assert_eq!(Pattern::parse(pattern).unwrap().parts, parts, "{}", pattern);
+        }
+
+        assert_eq!(
+            Pattern::parse("a{b").unwrap_err(),
+            RouteError::UnclosedBrace("a{b".to_owned())
+        );
+        // in templates, `*` is just text
+        assert_eq!(parse_parts("a*", false).unwrap(), vec![literal("a*")]);
+    }
+
+    #[test]
+    fn match_patterns() {
+        let cases: &[(&str, &str, Option<Captures>)] = &[
+            ("live", "live", Some(&[])),
+            ("live", "live2", None),
+            ("live", "liv", None),
+            ("", "", Some(&[])),
+            ("", "x", None),
+            ("*", "", Some(&[])),
+            ("*", "anything", Some(&[])),
+            ("sports-*", "sports-", Some(&[])),
+            ("sports-*", "sports-ball", Some(&[])),
+            ("sports-*", "news-ball", None),
+            // the first dash splits
+            (
+                "{team}-{show}",
+                "red-sox-live",
+                Some(&[("team", "red"), ("show", "sox-live")]),
+            ),
+            ("{team}-{show}", "nodash", None),
+            ("{team}-{show}", "-", Some(&[("team", ""), ("show", "")])),
+            // trailing literals still have to match at the end
+            ("{show}-hd", "news-hd", Some(&[("show", "news")])),
+            ("{show}-hd", "news-hd-hd", Some(&[("show", "news-hd")])),
+            ("{show}-hd", "news-hd2", None),
+            ("*.{ext}", "a.b.c", Some(&[("ext", "b.c")])),
+            // holes only ever split between characters
+            ("{a}{b}", "é", Some(&[("a", ""), ("b", "é")])),
+            ("{a}ö{b}", "äöü", Some(&[("a", "ä"), ("b", "ü")])),
+            ("{a}😀", "x😀", Some(&[("a", "x")])),
+            ("{a}😀", "😀x", None),
+        ];
+        for &(pattern, input, expected) in cases {
+            let mut found = HashMap::new();
+            let matched = Pattern::parse(pattern).unwrap().matches(input, &mut found);
+            match expected {
+                Some(expected) => {
+                    assert!(matched, "{:?} should match {:?}", pattern, input);
+                    assert_eq!(found, captures(expected), "{:?} on {:?}", pattern, input);
+                }
+                None => {
+                    assert!(!matched, "{:?} should not match {:?}", pattern, input);
+                    assert!(found.is_empty(), "{:?} on {:?}", pattern, input);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn many_holes_do_not_backtrack_forever() {
+        let pattern = Pattern::parse(&"*a".repeat(16)).unwrap();
+        let input = "a".repeat(200) + "b";
+        let started = Instant::now();
+        assert!(!pattern.matches(&input, &mut HashMap::new()));
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn render_templates() {
+        let known = |name: &str| ["app", "stream_key", "show"].contains(&name);
+        let values = captures(&[("app", "live"), ("stream_key", "cam"), ("show", "ñews")]);
+        let cases = [
+            ("", ""),
+            ("plain", "plain"),
+            ("{show}", "ñews"),
+            ("rtmp://host/{app}/*", "rtmp://host/live/*"),
+            ("{app}-{stream_key}-{show}", "live-cam-ñews"),
+        ];
+        for (template, rendered) in cases {
+            assert_eq!(
+                Template::parse(template, known).unwrap().render(&values),
+                rendered,
+                "{}",
+                template
+            );
+        }
+
+        assert_eq!(
+            Template::parse("{team}", known).unwrap_err(),
+            RouteError::UnknownPlaceholder {
+                template: "{team}".to_owned(),
+                name: "team".to_owned(),
+            }
+        );
+        assert_eq!(
+            Template::parse("{show", known).unwrap_err(),
+            RouteError::UnclosedBrace("{show".to_owned())
+        );
+    }
+
+    #[test]
+    fn parse_destinations() {
+        let ok = |url: &str, host: &str, port: u16, app: &str, tc_url: &str| {
+            let destination = Destination::parse(url.to_owned(), "key".to_owned()).unwrap();
+            assert_eq!(
+                destination,
+                Destination {
+                    url: tc_url.to_owned(),
+                    host: host.to_owned(),
+                    port,
+                    app: app.to_owned(),
+                    stream_key: "key".to_owned(),
+                },
+                "{}",
+                url
+            );
+        };
+        ok(
+            "rtmp://example.com/live",
+            "example.com",
+            1935,
+            "live",
+            "rtmp://example.com/live",
+        );
+        ok(
+            "rtmp://example.com:1936/live/",
+            "example.com",
+            1936,
+            "live",
+            "rtmp://example.com:1936/live",
+        );
+        ok(
+            "rtmp://10.0.0.1/live/sub",
+            "10.0.0.1",
+            1935,
+            "live/sub",
+            "rtmp://10.0.0.1/live/sub",
+        );
+        ok(
+            "rtmp://[::1]/live",
+            "::1",
+            1935,
+            "live",
+            "rtmp://[::1]/live",
+        );
+        ok(
+            "rtmp://[2001:db8::1]:1936/live",
+            "2001:db8::1",
+            1936,
+            "live",
+            "rtmp://[2001:db8::1]:1936/live",
+        );
+
+        let errors = [
+            (
+                "http://example.com/live",
+                RouteError::NotRtmp as fn(String) -> RouteError,
+            ),
+            ("rtmp://example.com", RouteError::MissingApp),
+            ("rtmp://example.com/", RouteError::MissingApp),
+            ("rtmp:///live", RouteError::MissingHost),
+            ("rtmp://:1935/live", RouteError::MissingHost),
+            ("rtmp://example.com:port/live", RouteError::BadPort),
+            ("rtmp://example.com:99999/live", RouteError::BadPort),
+            ("rtmp://[]/live", RouteError::MissingHost),
+            ("rtmp://[::1/live", RouteError::MissingHost),
+            ("rtmp://[::1]1936/live", RouteError::BadPort),
+            ("rtmp://[::1]:port/live", RouteError::BadPort),
+        ];
+        for (url, error) in errors {
+            assert_eq!(
+                Destination::parse(url.to_owned(), "key".to_owned()).unwrap_err(),
+                error(url.to_owned()),
+                "{}",
+                url
+            );
+        }
+    }
+
+    #[test]
+    fn routes_fill_destinations_from_captures() {
+        let config: Config = toml::from_str(
+            r#"
+            [[routes]]
+            app = "sports-*"
+            stream_key = "{team}-{show}"
+            destinations = [
+                { url = "rtmp://a.example.com/live2", stream_key = "{show}" },
+                { url = "rtmp://ingest.example.com/{team}" },
+            ]
+
+            [[routes]]
+            destinations = [{ url = "rtmp://fallback.example.com/{app}" }]
+            "#,
+        )
+        .unwrap();
+        let router = Router::new(&config.routes).unwrap();
+
+        let destinations = router.destinations(&StreamName::new("sports-1", "red-sox-live"));
+        let rendered: Vec<_> = destinations
+            .iter()
+            .map(|d| (d.url.as_str(), d.stream_key.as_str()))
+            .collect();
+        assert_eq!(
+            rendered,
+            [
+                ("rtmp://a.example.com/live2", "sox-live"),
+                ("rtmp://ingest.example.com/red", "red-sox-live"),
+            ]
+        );
+
+        // the first route needs a dash in the key, so the second one wins
+        let destinations = router.destinations(&StreamName::new("sports-1", "solo"));
+        assert_eq!(destinations.len(), 1);
+        assert_eq!(destinations[0].url, "rtmp://fallback.example.com/sports-1");
+        assert_eq!(destinations[0].stream_key, "solo");
+
+        assert!(Router::default()
+            .destinations(&StreamName::new("live", "cam"))
+            .is_empty());
+    }
+
+    #[test]
+    fn routes_only_use_what_they_capture() {
+        let config = RouteConfig {
+            app: "*".to_owned(),
+            stream_key: "{show}".to_owned(),
+            destinations: vec![DestinationConfig {
+                url: "rtmp://example.com/{team}".to_owned(),
+                stream_key: "{show}".to_owned(),
+            }],
+        };
+        assert_eq!(
+            Router::new(&[config]).unwrap_err(),
+            RouteError::UnknownPlaceholder {
+                template: "rtmp://example.com/{team}".to_owned(),
+                name: "team".to_owned(),
+            }
+        );
+    }
+}
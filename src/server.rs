@@ -0,0 +1,201 @@
+//! The RTMP proxy itself. Publishers push to it, and get their stream blurred
+//! and handed on to players, recordings, HLS and any destinations it is routed
+//! to.
+//!
+//! [`serve`] runs it like the binary does. [`run`] is for running it inside
+//! something else, e.g. a test, on a listener and detector of your choosing.
+
+use std::{future::Future, sync::Arc, time::Duration};
+
+use tokio::{
+    net::{TcpListener, TcpStream},
+    sync::mpsc,
+    time::timeout,
+};
+use tracing::{error, info, warn};
+
+use crate::{
+    admin::{self, AdminState, Operators},
+    auth::PublishAuthorizer,
+    config::Config,
+    connection_error,
+    connection_manager::{ConnectionManager, ServerContext},
+    image_processing::{self, FaceDetector},
+    metrics::Metrics,
+    policy::Policies,
+    routing::Router,
+    shutdown::{self, Shutdown},
+    stream_registry::StreamRegistry,
+};
+
+/// How long publishers get to finish on their own after we are asked to stop
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How long pipelines get to flush once their connections have been closed
+const FLUSH_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Load the models, listen where `config` says to, and serve until SIGINT or
+/// SIGTERM
+pub async fn serve(config: Config) -> anyhow::Result<()> {
+    // check the config before spending time on the models
+    Router::new(&config.routes)?;
+    Policies::new(&config.policies)?;
+    Operators::new(&config.admin)?;
+
+    image_processing::init_models();
+    image_processing::enroll_identities(&config.whitelist)?;
+
+    let listener = TcpListener::bind(config.listen_address).await?;
+
+    image_processing::print_hello_from_cxx();
+
+    run(
+        config,
+        listener,
+        FaceDetector::Models,
+        shutdown::wait_for_signal(),
+    )
+    .await
+}
+
+/// Accept connections on `listener` until `stop` resolves, then give them a
+/// chance to finish before tearing them down. If `detector` is
+/// [`FaceDetector::Models`], they must have been loaded already.
+pub async fn run(
+    config: Config,
+    listener: TcpListener,
+    detector: FaceDetector,
+    stop: impl Future<Output = std::io::Result<()>>,
+) -> anyhow::Result<()> {
+    let router = Router::new(&config.routes)?;
+    let policies = Policies::new(&config.policies)?;
+
+    // Every connection task holds a clone of `drain_tx`. Once they have all
+    // been dropped, `drain_rx.recv()` returns `None`.
+    let (drain_tx, mut drain_rx) = mpsc::channel::<()>(1);
+    let force_close = Shutdown::new();
+    let abort = Shutdown::new();
+    let authorizer = PublishAuthorizer::new(&config.auth);
+    if authorizer.is_open() {
+        warn!("no stream keys or token secret configured, anyone can publish");
+    }
+    let metrics = Metrics::new();
+    let registry = StreamRegistry::new();
+    if let Some(admin_address) = config.admin_address {
+        let operators = Operators::new(&config.admin)?;
+        if operators.is_empty() {
+            warn!("no operators configured, nobody can change the controls of a stream");
+        }
+        admin::start(
+            admin_address,
+            AdminState {
+                metrics: metrics.clone(),
+                hls_directory: config.hls.directory.clone(),
+                registry: registry.clone(),
+                operators,
+            },
+        )?;
+    }
+
+    let context = ServerContext {
+        connection_config: config.connection_config(),
+        registry,
+        authorizer: Arc::new(authorizer),
+        router: Arc::new(router),
+        policies: Arc::new(policies),
+        detector,
+        egress_config: config.egress,
+        recording: Arc::new(config.recording.clone()),
+        hls: Arc::new(config.hls.clone()),
+        video: config.video,
+        audit: Arc::new(config.audit.clone()),
+        metrics,
+    };
+
+    tokio::pin!(stop);
+
+    loop {
+        info!("ready to accept connections");
+        tokio::select! {
+            accepted = listener.accept() => {
+                let (tcp_stream, _addr) = accepted?;
+                info!("accepting connection from {:?}", _addr);
+                tokio::spawn(manage_connection(
+                    tcp_stream,
+                    context.clone(),
+                    force_close.clone(),
+                    abort.clone(),
+                    drain_tx.clone(),
+                ));
+            }
+            res = &mut stop => {
+                res?;
+                info!("received shutdown signal, no longer accepting connections");
+                break;
+            }
+        }
+    }
+
+    drop(listener);
+    drop(drain_tx);
+
+    if timeout(DRAIN_TIMEOUT, drain_rx.recv()).await.is_err() {
+        warn!(
+            "publishers still connected after {:?}, closing their connections",
+            DRAIN_TIMEOUT
+        );
+        force_close.cancel();
+
+        if timeout(FLUSH_TIMEOUT, drain_rx.recv()).await.is_err() {
+            warn!(
+                "pipelines did not flush within {:?}, aborting them",
+                FLUSH_TIMEOUT
+            );
+            abort.cancel();
+            let _ = timeout(Duration::from_secs(1), drain_rx.recv()).await;
+        }
+    }
+
+    if abort.is_cancelled() {
+        warn!("shut down, but some pipelines had to be aborted");
+    } else {
+        info!("shut down cleanly");
+    }
+    Ok(())
+}
+
+#[tracing::instrument(skip(context, force_close, abort, _drain))]
+async fn manage_connection(
+    socket: TcpStream,
+    context: ServerContext,
+    force_close: Shutdown,
+    abort: Shutdown,
+    _drain: mpsc::Sender<()>,
+) {
+    let mut conn = match ConnectionManager::connect(socket, context, abort).await {
+        Ok(conn) => conn,
+        Err(e) => {
+            report_connection_error(&e);
+            return;
+        }
+    };
+    if let Err(e) = conn.handle_connection(&force_close).await {
+        report_connection_error(&e);
+    }
+
+    // dropping the connection joins its pipeline threads, so keep it off the async workers
+    if tokio::task::spawn_blocking(move || drop(conn))
+        .await
+        .is_err()
+    {
+        warn!("flushing the pipeline panicked");
+    }
+}
+
+fn report_connection_error(e: &connection_error::ConnectionError) {
+    if e.is_client_fault() {
+        warn!(problem = %e, "client misbehaved, disconnecting it");
+    } else {
+        error!(problem = %e, "connection ended with an error");
+    }
+}
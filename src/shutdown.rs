@@ -0,0 +1,68 @@
+//! Cooperative cancellation that works from both async tasks and plain
+//! `std::thread`s.
+//!
+//! Once the server is told to stop (see [`wait_for_signal`]) it stops accepting
+//! connections and gives the ones it has a drain period to finish on their
+//! own. After that it fires two of these in turn: `force_close` closes the
+//! connections that are left, which lets their pipelines flush, and `abort`
+//! stops pipeline threads without draining, if flushing takes too long too.
+
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
+
+use tokio::sync::Notify;
+
+#[derive(Debug, Default)]
+struct Inner {
+    cancelled: AtomicBool,
+    notify: Notify,
+}
+
+/// A cloneable cancellation flag. Cancelling any clone cancels all of them.
+#[derive(Debug, Clone, Default)]
+pub struct Shutdown {
+    inner: Arc<Inner>,
+}
+
+impl Shutdown {
+    /// Not cancelled yet
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Flip the flag and wake up everyone waiting in [`Shutdown::cancelled`]
+    pub fn cancel(&self) {
+        self.inner.cancelled.store(true, Ordering::SeqCst);
+        self.inner.notify.notify_waiters();
+    }
+
+    /// Cheap check for threads that poll between units of work
+    pub fn is_cancelled(&self) -> bool {
+        self.inner.cancelled.load(Ordering::SeqCst)
+    }
+
+    /// Resolves once [`Shutdown::cancel`] has been called
+    pub async fn cancelled(&self) {
+        loop {
+            // register interest before checking the flag so we can't miss a wakeup
+            let notified = self.inner.notify.notified();
+            if self.is_cancelled() {
+                return;
+            }
+            notified.await;
+        }
+    }
+}
+
+/// Resolves when the process receives SIGINT or SIGTERM
+pub async fn wait_for_signal() -> std::io::Result<()> {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut sigterm = signal(SignalKind::terminate())?;
+    tokio::select! {
+        res = tokio::signal::ctrl_c() => res,
+        _ = sigterm.recv() => Ok(()),
+    }
+}
@@ -0,0 +1,233 @@
+//! Keeps track of which anonymized streams are currently live, so that RTMP
+//! players can subscribe to them by app name and stream key.
+
+use std::{
+    collections::HashMap,
+    fmt,
+    sync::{Arc, Mutex},
+};
+
+use bytes::Bytes;
+use rml_rtmp::sessions::StreamMetadata;
+use tokio::sync::broadcast;
+use tracing::info;
+
+use crate::controls::OperatorControls;
+
+/// How many packets a player can fall behind before it starts missing some
+const PLAYER_BUFFER_PACKETS: usize = 1024;
+
+/// What a [`MediaPacket`] carries
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MediaKind {
+    /// An RTMP audio message
+    Audio,
+    /// An RTMP video message
+    Video,
+    /// AMF0 script data other than `onMetaData`, see [`crate::region_metadata`]
+    Data,
+}
+
+/// One RTMP audio, video or data message worth of data
+#[derive(Debug, Clone)]
+pub struct MediaPacket {
+    /// What [`Self::data`] is
+    pub kind: MediaKind,
+    /// Milliseconds
+    pub timestamp: u32,
+    /// FLV tag body, i.e. what goes into `send_video_data`/`send_audio_data`
+    pub data: Bytes,
+}
+
+impl MediaPacket {
+    /// AVC decoder configuration or AAC audio specific config. Players can't
+    /// decode anything until they have seen these.
+    pub fn is_sequence_header(&self) -> bool {
+        match self.kind {
+            // Enhanced RTMP: packet type 0 is the sequence start
+            MediaKind::Video if self.is_enhanced_video() => self.data[0] & 0x0f == 0,
+            // codec id 7 is AVC, packet type 0 is the sequence header
+            MediaKind::Video => {
+                self.data.len() >= 2 && self.data[0] & 0x0f == 7 && self.data[1] == 0
+            }
+            // sound format 10 is AAC, packet type 0 is the sequence header
+            MediaKind::Audio => {
+                self.data.len() >= 2 && self.data[0] >> 4 == 10 && self.data[1] == 0
+            }
+            MediaKind::Data => false,
+        }
+    }
+
+    /// A video keyframe, which players can start decoding from
+    pub fn is_keyframe(&self) -> bool {
+        if self.is_enhanced_video() {
+            return (self.data[0] >> 4) & 0x07 == 1;
+        }
+        self.kind == MediaKind::Video && !self.data.is_empty() && self.data[0] >> 4 == 1
+    }
+
+    /// Whether this uses an Enhanced RTMP video header, see [`crate::video_tags`]
+    fn is_enhanced_video(&self) -> bool {
+        self.kind == MediaKind::Video && !self.data.is_empty() && self.data[0] & 0x80 != 0
+    }
+}
+
+/// Which stream, as publishers and players ask for it
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct StreamName {
+    /// What RTMP clients connect to
+    pub app_name: String,
+    /// What they publish or play, without any query string
+    pub stream_key: String,
+}
+
+impl StreamName {
+    /// `app_name/stream_key`
+    pub fn new(app_name: impl Into<String>, stream_key: impl Into<String>) -> Self {
+        Self {
+            app_name: app_name.into(),
+            stream_key: stream_key.into(),
+        }
+    }
+}
+
+impl fmt::Display for StreamName {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.app_name, self.stream_key)
+    }
+}
+
+/// What a player needs before it can start decoding mid-stream
+#[derive(Debug, Default, Clone)]
+pub struct CachedHeaders {
+    /// The publisher's `onMetaData`
+    pub metadata: Option<StreamMetadata>,
+    /// The latest, see [`MediaPacket::is_sequence_header`]
+    pub video_sequence_header: Option<MediaPacket>,
+    /// The latest, see [`MediaPacket::is_sequence_header`]
+    pub audio_sequence_header: Option<MediaPacket>,
+}
+
+/// The anonymized output of one publisher
+#[derive(Debug)]
+pub struct LiveStream {
+    name: StreamName,
+    packets: broadcast::Sender<MediaPacket>,
+    headers: Mutex<CachedHeaders>,
+    controls: OperatorControls,
+}
+
+impl LiveStream {
+    fn new(name: StreamName) -> Self {
+        let (packets, _) = broadcast::channel(PLAYER_BUFFER_PACKETS);
+        Self {
+            name,
+            packets,
+            headers: Mutex::new(CachedHeaders::default()),
+            controls: OperatorControls::new(),
+        }
+    }
+
+    /// What it is published as
+    pub fn name(&self) -> &StreamName {
+        &self.name
+    }
+
+    /// What operators have switched on for this stream, see [`crate::controls`]
+    pub fn controls(&self) -> &OperatorControls {
+        &self.controls
+    }
+
+    /// Hand a packet to every subscriber. Sequence headers are also kept for
+    /// anyone who subscribes later. Never blocks.
+    pub fn send(&self, packet: MediaPacket) {
+        if packet.is_sequence_header() {
+            let mut headers = self.headers.lock().unwrap();
+            match packet.kind {
+                MediaKind::Video => headers.video_sequence_header = Some(packet.clone()),
+                MediaKind::Audio => headers.audio_sequence_header = Some(packet.clone()),
+                MediaKind::Data => (),
+            }
+        }
+        // an error just means nobody is watching right now
+        let _ = self.packets.send(packet);
+    }
+
+    /// For subscribers to send first, see [`Self::headers`]
+    pub fn set_metadata(&self, metadata: StreamMetadata) {
+        self.headers.lock().unwrap().metadata = Some(metadata);
+    }
+
+    /// What a subscriber that (re)starts mid-stream has to send first
+    pub fn headers(&self) -> CachedHeaders {
+        self.headers.lock().unwrap().clone()
+    }
+
+    /// Returns the headers to send first, and a receiver for everything after them
+    pub fn subscribe(&self) -> (CachedHeaders, broadcast::Receiver<MediaPacket>) {
+        // hold the lock so no header can slip in between the snapshot and the subscription
+        let headers = self.headers.lock().unwrap();
+        (headers.clone(), self.packets.subscribe())
+    }
+}
+
+/// Shared between every connection. Cheap to clone.
+#[derive(Debug, Clone, Default)]
+pub struct StreamRegistry {
+    streams: Arc<Mutex<HashMap<StreamName, Arc<LiveStream>>>>,
+}
+
+impl StreamRegistry {
+    /// Nothing is live yet
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Claim `name` for a publisher. Returns `None` if someone is already
+    /// publishing on it. The stream goes away when the guard is dropped.
+    pub fn start_publishing(&self, name: StreamName) -> Option<PublishGuard> {
+        let mut streams = self.streams.lock().unwrap();
+        if streams.contains_key(&name) {
+            return None;
+        }
+
+        info!("{} is now live", name);
+        let stream = Arc::new(LiveStream::new(name.clone()));
+        streams.insert(name, stream.clone());
+        Some(PublishGuard {
+            registry: self.clone(),
+            stream,
+        })
+    }
+
+    /// The stream published as `name`, if it is live
+    pub fn get(&self, name: &StreamName) -> Option<Arc<LiveStream>> {
+        self.streams.lock().unwrap().get(name).cloned()
+    }
+}
+
+/// Keeps a stream listed in the registry for as long as it is alive
+#[derive(Debug)]
+pub struct PublishGuard {
+    registry: StreamRegistry,
+    stream: Arc<LiveStream>,
+}
+
+impl PublishGuard {
+    /// The stream that was claimed
+    pub fn stream(&self) -> &Arc<LiveStream> {
+        &self.stream
+    }
+}
+
+impl Drop for PublishGuard {
+    fn drop(&mut self) {
+        let mut streams = self.registry.streams.lock().unwrap();
+        if let Some(listed) = streams.get(self.stream.name()) {
+            if Arc::ptr_eq(listed, &self.stream) {
+                streams.remove(self.stream.name());
+                info!("{} is no longer live", self.stream.name());
+            }
+        }
+    }
+}
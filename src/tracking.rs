@@ -0,0 +1,192 @@
+//! Follows faces from one frame to the next, so the debug overlay can show
+//! whether the detector keeps seeing the same face or flickers.
+//!
+//! Boxes are matched greedily by how much they overlap with where each face
+//! was last seen. That is plenty for eyeballing detector output, and nothing
+//! else should rely on it.
+
+use std::cmp::Ordering;
+
+use crate::image_processing::Detection;
+
+/// Boxes that overlap less than this are different faces
+const MIN_OVERLAP: f32 = 0.3;
+
+/// How many frames a face can go unseen before its id is retired
+const MAX_MISSED_FRAMES: u32 = 5;
+
+#[derive(Debug, Default)]
+pub struct Tracker {
+    tracks: Vec<Track>,
+    next_id: u32,
+}
+
+#[derive(Debug)]
+struct Track {
+    id: u32,
+    /// x, y, width, height where it was last seen
+    bounds: (i32, i32, i32, i32),
+    missed_frames: u32,
+}
+
+impl Tracker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns a track id for each of `detections`, in the same order
+    pub fn update(&mut self, detections: &[Detection]) -> Vec<u32> {
+        let bounds: Vec<_> = detections
+            .iter()
+            .map(|d| (d.x, d.y, d.width, d.height))
+            .collect();
+
+        // best overlaps first, so a face doesn't steal the track of a closer match
+        let mut candidates = Vec::new();
+        for (detection, &detection_bounds) in bounds.iter().enumerate() {
+            for (track, existing) in self.tracks.iter().enumerate() {
+                let overlap = overlap(detection_bounds, existing.bounds);
+                if overlap >= MIN_OVERLAP {
+                    candidates.push((overlap, detection, track));
+                }
+            }
+        }
+        candidates.sort_by(|a, b| b.0.partial_cmp(&a.0).unwrap_or(Ordering::Equal));
+
+        let mut ids = vec![None; detections.len()];
+        let mut seen = vec![false; self.tracks.len()];
+        for (_, detection, track) in candidates {
+            if ids[detection].is_some() || seen[track] {
+                continue;
+            }
+            ids[detection] = Some(self.tracks[track].id);
+            self.tracks[track].bounds = bounds[detection];
+            seen[track] = true;
+        }
+
+        for (track, seen) in self.tracks.iter_mut().zip(&seen) {
+            track.missed_frames = if *seen { 0 } else { track.missed_frames + 1 };
+        }
+        self.tracks
+            .retain(|track| track.missed_frames <= MAX_MISSED_FRAMES);
+
+        ids.into_iter()
+            .zip(bounds)
+            .map(|(id, bounds)| match id {
+                Some(id) => id,
+                None => {
+                    let id = self.next_id;
+                    self.next_id = self.next_id.wrapping_add(1);
+                    self.tracks.push(Track {
+                        id,
+                        bounds,
+                        missed_frames: 0,
+                    });
+                    id
+                }
+            })
+            .collect()
+    }
+}
+
+/// Intersection over union
+fn overlap(a: (i32, i32, i32, i32), b: (i32, i32, i32, i32)) -> f32 {
+    let width = (a.0 + a.2).min(b.0 + b.2) - a.0.max(b.0);
+    let height = (a.1 + a.3).min(b.1 + b.3) - a.1.max(b.1);
+    if width <= 0 || height <= 0 {
+        return 0.0;
+    }
+    let intersection = width as f32 * height as f32;
+    let union = (a.2 * a.3) as f32 + (b.2 * b.3) as f32 - intersection;
+    intersection / union
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn face(x: i32, y: i32) -> Detection {
+        Detection {
+            x,
+            y,
+            width: 100,
+            height: 100,
+            confidence: 0.9,
+            blurred: true,
+            identity: String::new(),
+            distance: -1.0,
+        }
+    }
+
+    #[test]
+    fn overlap_is_intersection_over_union() {
+        let cases = [
+            ((0, 0, 10, 10), (0, 0, 10, 10), 1.0, "the same box"),
+            ((0, 0, 10, 10), (5, 0, 10, 10), 50.0 / 150.0, "half across"),
+            ((0, 0, 10, 10), (5, 5, 10, 10), 25.0 / 175.0, "a corner"),
+            (
+                (0, 0, 10, 10),
+                (2, 2, 5, 5),
+                25.0 / 100.0,
+                "one inside the other",
+            ),
+            ((0, 0, 10, 10), (10, 0, 10, 10), 0.0, "touching"),
+            ((0, 0, 10, 10), (20, 20, 10, 10), 0.0, "apart"),
+            ((0, 0, 0, 0), (0, 0, 0, 0), 0.0, "empty"),
+        ];
+
+        for (a, b, expected, message) in cases {
+            assert!(
+                (overlap(a, b) - expected).abs() < 1e-6,
+                "{}: {}",
+                message,
+                overlap(a, b)
+            );
+            assert_eq!(overlap(a, b), overlap(b, a), "{} is symmetric", message);
+        }
+    }
+
+    #[test]
+    fn faces_keep_their_ids_while_they_move() {
+        let mut tracker = Tracker::new();
+        assert_eq!(tracker.update(&[face(0, 0), face(500, 0)]), [0, 1]);
+        assert_eq!(
+            tracker.update(&[face(510, 10), face(20, 0)]),
+            [1, 0],
+            "order doesn't matter"
+        );
+        assert_eq!(
+            tracker.update(&[face(30, 0), face(900, 0)]),
+            [0, 2],
+            "new faces get new ids"
+        );
+        assert_eq!(
+            tracker.update(&[face(300, 300)]),
+            [3],
+            "too far from anything"
+        );
+    }
+
+    #[test]
+    fn closest_match_wins() {
+        let mut tracker = Tracker::new();
+        assert_eq!(tracker.update(&[face(0, 0)]), [0]);
+        // both overlap the track, the second one more
+        assert_eq!(tracker.update(&[face(50, 0), face(10, 0)]), [1, 0]);
+    }
+
+    #[test]
+    fn ids_are_retired_after_missing_too_many_frames() {
+        let mut tracker = Tracker::new();
+        assert_eq!(tracker.update(&[face(0, 0)]), [0]);
+        for _ in 0..MAX_MISSED_FRAMES {
+            tracker.update(&[]);
+        }
+        assert_eq!(tracker.update(&[face(0, 0)]), [0], "back in time");
+
+        for _ in 0..=MAX_MISSED_FRAMES {
+            tracker.update(&[]);
+        }
+        assert_eq!(tracker.update(&[face(0, 0)]), [1], "gone too long");
+    }
+}
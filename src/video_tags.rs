@@ -0,0 +1,699 @@
+//! The header at the front of every RTMP video message (which is also an FLV
+//! video tag body), in both the original flavour and the one from Enhanced
+//! RTMP.
+//!
+//! The original header can only say "AVC", so newer encoders that want to send
+//! HEVC or AV1 set the top bit of the first byte and name the codec with a
+//! FourCC instead:
+//!
+//! ```text
+//! original: frame type (4 bits) | codec id (4) | AVC packet type (8) | composition time (24)
+//! enhanced: 1 | frame type (3) | packet type (4) | FourCC (32) | [composition time (24)]
+//! ```
+//!
+//! ffmpeg 5 knows nothing about the enhanced flavour, so this also builds the
+//! decoder configuration records it would otherwise have built for us.
+
+use bytes::{BufMut, Bytes, BytesMut};
+
+/// Set in the first byte of an Enhanced RTMP video tag
+const EX_HEADER: u8 = 0x80;
+const KEYFRAME: u8 = 1;
+const INTER_FRAME: u8 = 2;
+/// The codec id for AVC in the original header
+const AVC_CODEC_ID: u8 = 7;
+
+/// What a video tag is encoded with
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VideoCodec {
+    /// H.264
+    Avc,
+    /// H.265
+    Hevc,
+    /// AV1
+    Av1,
+    /// VP9
+    Vp9,
+}
+
+impl VideoCodec {
+    fn from_fourcc(fourcc: &[u8]) -> Option<Self> {
+        match fourcc {
+            b"avc1" => Some(Self::Avc),
+            b"hvc1" => Some(Self::Hevc),
+            b"av01" => Some(Self::Av1),
+            b"vp09" => Some(Self::Vp9),
+            _ => None,
+        }
+    }
+
+    fn fourcc(self) -> &'static [u8; 4] {
+        match self {
+            Self::Avc => b"avc1",
+            Self::Hevc => b"hvc1",
+            Self::Av1 => b"av01",
+            Self::Vp9 => b"vp09",
+        }
+    }
+
+    /// Only these carry a composition time offset in Enhanced RTMP
+    fn has_composition_time(self) -> bool {
+        matches!(self, Self::Avc | Self::Hevc)
+    }
+}
+
+/// What the payload of a video tag is
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VideoPacketType {
+    /// The decoder configuration record
+    SequenceStart,
+    /// Encoded frames
+    CodedFrames,
+    /// The end of the stream
+    SequenceEnd,
+    /// Metadata and the like, which we have no use for
+    Other,
+}
+
+/// An FLV video tag, or RTMP video message, taken apart
+#[derive(Debug, Clone)]
+pub struct VideoTag {
+    /// What the payload is encoded with
+    pub codec: VideoCodec,
+    /// Whether this used the Enhanced RTMP header
+    pub enhanced: bool,
+    /// Whether decoding can start here
+    pub keyframe: bool,
+    /// What the payload is
+    pub packet_type: VideoPacketType,
+    /// Milliseconds from the decode timestamp to the presentation timestamp
+    pub composition_time: i32,
+    /// What is left after the header, e.g. a decoder configuration record
+    /// or length-prefixed NAL units
+    pub payload: Bytes,
+}
+
+impl VideoTag {
+    /// `None` if this is not AVC or one of the Enhanced RTMP codecs we know
+    pub fn parse(data: &Bytes) -> Option<Self> {
+        let first = *data.first()?;
+        if first & EX_HEADER == 0 {
+            if first & 0x0f != AVC_CODEC_ID || data.len() < 5 {
+                return None;
+            }
+            let packet_type = match data[1] {
+                0 => VideoPacketType::SequenceStart,
+                1 => VideoPacketType::CodedFrames,
+                2 => VideoPacketType::SequenceEnd,
+                _ => VideoPacketType::Other,
+            };
+            return Some(Self {
+                codec: VideoCodec::Avc,
+                enhanced: false,
+                keyframe: first >> 4 == KEYFRAME,
+                packet_type,
+                composition_time: read_i24(&data[2..5]),
+                payload: data.slice(5..),
+            });
+        }
+
+        let codec = VideoCodec::from_fourcc(data.get(1..5)?)?;
+        let mut header_len = 5;
+        let mut composition_time = 0;
+        let packet_type = match first & 0x0f {
+            0 => VideoPacketType::SequenceStart,
+            1 => {
+                if codec.has_composition_time() {
+                    composition_time = read_i24(data.get(5..8)?);
+                    header_len = 8;
+                }
+                VideoPacketType::CodedFrames
+            }
+            2 => VideoPacketType::SequenceEnd,
+            // coded frames with an implied composition time of zero
+            3 => VideoPacketType::CodedFrames,
+            _ => VideoPacketType::Other,
+        };
+        Some(Self {
+            codec,
+            enhanced: true,
+            keyframe: (first >> 4) & 0x07 == KEYFRAME,
+            packet_type,
+            composition_time,
+            payload: data.slice(header_len..),
+        })
+    }
+
+    /// Serialize with the Enhanced RTMP header, which is the only one that can
+    /// say anything other than AVC
+    pub fn to_enhanced_bytes(&self) -> Bytes {
+        let packet_type = match self.packet_type {
+            VideoPacketType::SequenceStart => 0,
+            VideoPacketType::CodedFrames => 1,
+            VideoPacketType::SequenceEnd => 2,
+            VideoPacketType::Other => 4,
+        };
+        let frame_type = if self.keyframe { KEYFRAME } else { INTER_FRAME };
+
+        let mut data = BytesMut::with_capacity(8 + self.payload.len());
+        data.put_u8(EX_HEADER | frame_type << 4 | packet_type);
+        data.put_slice(self.codec.fourcc());
+        if self.packet_type == VideoPacketType::CodedFrames && self.codec.has_composition_time() {
+            data.put_slice(&self.composition_time.to_be_bytes()[1..]);
+        }
+        data.put_slice(&self.payload);
+        data.freeze()
+    }
+}
+
+fn read_i24(bytes: &[u8]) -> i32 {
+    // sign extend by shifting it into the top of an i32 and back
+    (i32::from_be_bytes([bytes[0], bytes[1], bytes[2], 0])) >> 8
+}
+
+/// Build an HEVCDecoderConfigurationRecord (`hvcC`) out of the VPS, SPS and
+/// PPS in an Annex B encoder header. Assumes 8 bit 4:2:0, since that is all
+/// we encode.
+pub fn hevc_decoder_configuration(annex_b: &[u8]) -> Option<Bytes> {
+    let nal_units: Vec<&[u8]> = split_annex_b(annex_b).collect();
+    let find = |nal_type: u8| {
+        nal_units
+            .iter()
+            .copied()
+            .find(|nal| nal.len() > 2 && (nal[0] >> 1) & 0x3f == nal_type)
+    };
+    let (vps, sps, pps) = (find(32)?, find(33)?, find(34)?);
+
+    // after the 2 byte NAL header: VPS id (4 bits), max sub layers - 1 (3),
+    // temporal id nesting (1), then the 12 byte general profile_tier_level
+    let sps_rbsp = remove_emulation_prevention(&sps[..sps.len().min(32)]);
+    let max_sub_layers = ((sps_rbsp.get(2)? >> 1) & 0x07) + 1;
+    let temporal_id_nested = sps_rbsp[2] & 0x01;
+    let profile_tier_level = sps_rbsp.get(3..15)?;
+
+    let mut record = BytesMut::new();
+    record.put_u8(1); // configurationVersion
+    record.put_slice(profile_tier_level);
+    record.put_u16(0xf000); // min_spatial_segmentation_idc
+    record.put_u8(0xfc); // parallelismType
+    record.put_u8(0xfc | 1); // chroma_format_idc: 4:2:0
+    record.put_u8(0xf8); // bit_depth_luma_minus8
+    record.put_u8(0xf8); // bit_depth_chroma_minus8
+    record.put_u16(0); // avgFrameRate
+    let length_size_minus_one = 3;
+    // constantFrameRate is left at 0
+    record.put_u8(max_sub_layers << 3 | temporal_id_nested << 2 | length_size_minus_one);
+    record.put_u8(3); // numOfArrays
+    for (nal_type, nal) in [(32, vps), (33, sps), (34, pps)] {
+        record.put_u8(0x80 | nal_type); // array_completeness
+        record.put_u16(1); // numNalus
+        record.put_u16(nal.len() as u16);
+        record.put_slice(nal);
+    }
+    Some(record.freeze())
+}
+
+/// Annex B (start code delimited) NAL units -> 4 byte length prefixed, which
+/// is what FLV and MP4 carry
+pub fn annex_b_to_length_prefixed(annex_b: &[u8]) -> Bytes {
+    let mut out = BytesMut::with_capacity(annex_b.len() + 16);
+    for nal in split_annex_b(annex_b) {
+        out.put_u32(nal.len() as u32);
+        out.put_slice(nal);
+    }
+    out.freeze()
+}
+
+fn split_annex_b(data: &[u8]) -> impl Iterator<Item = &[u8]> {
+    AnnexBNalUnits { data }.filter(|nal| !nal.is_empty())
+}
+
+/// Iterates over the NAL units between start codes (`00 00 01` or `00 00 00 01`)
+struct AnnexBNalUnits<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Iterator for AnnexBNalUnits<'a> {
+    type Item = &'a [u8];
+
+    fn next(&mut self) -> Option<&'a [u8]> {
+        let start = find_start_code(self.data)?;
+        let rest = &self.data[start..];
+        let end = find_start_code(rest)
+            .map(|next| {
+                // the next start code might have an extra leading zero
+                let mut end = next - 3;
+                while end > 0 && rest[end - 1] == 0 {
+                    end -= 1;
+                }
+                end
+            })
+            .unwrap_or(rest.len());
+        self.data = &rest[end..];
+        Some(&rest[..end])
+    }
+}
+
+/// The index just past the first `00 00 01`
+fn find_start_code(data: &[u8]) -> Option<usize> {
+    data.windows(3)
+        .position(|window| window == [0, 0, 1])
+        .map(|position| position + 3)
+}
+
+fn remove_emulation_prevention(nal: &[u8]) -> Vec<u8> {
+    let mut rbsp = Vec::with_capacity(nal.len());
+    let mut zeros = 0;
+    for &byte in nal {
+        if zeros >= 2 && byte == 3 {
+            zeros = 0;
+            continue;
+        }
+        zeros = if byte == 0 { zeros + 1 } else { 0 };
+        rbsp.push(byte);
+    }
+    rbsp
+}
+
+const OBU_SEQUENCE_HEADER: u8 = 1;
+const OBU_TEMPORAL_DELIMITER: u8 = 2;
+
+/// Build an AV1CodecConfigurationRecord (`av1C`) out of the sequence header OBU
+/// in an encoder header. Assumes 8 bit 4:2:0, since that is all we encode.
+pub fn av1_decoder_configuration(obus: &[u8]) -> Option<Bytes> {
+    // some encoders already give us the record, and its marker bit can't start an OBU
+    if obus.first() == Some(&0x81) {
+        return Some(Bytes::copy_from_slice(obus));
+    }
+    let sequence_header = Obus { data: obus }.find(|obu| obu.obu_type == OBU_SEQUENCE_HEADER)?;
+    let (profile, level, tier) = parse_av1_sequence_header(sequence_header.payload)?;
+
+    let mut record = BytesMut::new();
+    record.put_u8(0x81); // marker, version 1
+    record.put_u8(profile << 5 | level);
+    // tier, then high_bitdepth, twelve_bit, monochrome, subsampling x and y, chroma position
+    record.put_u8(tier << 7 | 0b0000_1100);
+    record.put_u8(0); // no initial_presentation_delay
+    record.put_slice(sequence_header.whole);
+    Some(record.freeze())
+}
+
+/// Temporal delimiters aren't stored in FLV or MP4
+pub fn strip_av1_temporal_delimiters(obus: &[u8]) -> Bytes {
+    let mut out = BytesMut::with_capacity(obus.len());
+    for obu in (Obus { data: obus }) {
+        if obu.obu_type != OBU_TEMPORAL_DELIMITER {
+            out.put_slice(obu.whole);
+        }
+    }
+    out.freeze()
+}
+
+struct Obu<'a> {
+    obu_type: u8,
+    /// Header included
+    whole: &'a [u8],
+    payload: &'a [u8],
+}
+
+/// Iterates over low overhead bitstream format OBUs, i.e. ones with a size field
+struct Obus<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Iterator for Obus<'a> {
+    type Item = Obu<'a>;
+
+    fn next(&mut self) -> Option<Obu<'a>> {
+        let header = *self.data.first()?;
+        let obu_type = (header >> 3) & 0x0f;
+        let has_extension = header & 0x04 != 0;
+        let has_size = header & 0x02 != 0;
+        let mut position = if has_extension { 2 } else { 1 };
+
+        let size = if has_size {
+            let (size, leb128_len) = read_leb128(self.data.get(position..)?)?;
+            position += leb128_len;
+            size as usize
+        } else {
+            self.data.len().checked_sub(position)?
+        };
+        let end = position.checked_add(size)?;
+        if end > self.data.len() {
+            self.data = &[];
+            return None;
+        }
+        let obu = Obu {
+            obu_type,
+            whole: &self.data[..end],
+            payload: &self.data[position..end],
+        };
+        self.data = &self.data[end..];
+        Some(obu)
+    }
+}
+
+fn read_leb128(data: &[u8]) -> Option<(u64, usize)> {
+    let mut value = 0;
+    for (i, &byte) in data.iter().enumerate().take(8) {
+        value |= ((byte & 0x7f) as u64) << (i * 7);
+        if byte & 0x80 == 0 {
+            return Some((value, i + 1));
+        }
+    }
+    None
+}
+
+/// Pull `seq_profile` and the level and tier of the first operating point out
+/// of a sequence header OBU's payload
+fn parse_av1_sequence_header(payload: &[u8]) -> Option<(u8, u8, u8)> {
+    let mut bits = BitReader::new(payload);
+    let profile = bits.read(3)? as u8;
+    let _still_picture = bits.read(1)?;
+    let reduced_still_picture_header = bits.read(1)? == 1;
+    if reduced_still_picture_header {
+        return Some((profile, bits.read(5)? as u8, 0));
+    }
+
+    let timing_info_present = bits.read(1)? == 1;
+    if timing_info_present {
+        bits.read(32)?; // num_units_in_display_tick
+        bits.read(32)?; // time_scale
+        if bits.read(1)? == 1 {
+            bits.read_uvlc()?; // num_ticks_per_picture_minus_1
+        }
+        let decoder_model_info_present = bits.read(1)? == 1;
+        if decoder_model_info_present {
+            bits.read(5)?; // buffer_delay_length_minus_1
+            bits.read(32)?; // num_units_in_decoding_tick
+            bits.read(5)?; // buffer_removal_time_length_minus_1
+            bits.read(5)?; // frame_presentation_time_length_minus_1
+        }
+    }
+    bits.read(1)?; // initial_display_delay_present
+    bits.read(5)?; // operating_points_cnt_minus_1
+
+    // the first operating point is the whole stream, so it is all we need
+    bits.read(12)?; // operating_point_idc
+    let level = bits.read(5)? as u8;
+    let tier = if level > 7 { bits.read(1)? as u8 } else { 0 };
+    Some((profile, level, tier))
+}
+
+struct BitReader<'a> {
+    data: &'a [u8],
+    position: usize,
+}
+
+impl<'a> BitReader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Self { data, position: 0 }
+    }
+
+    fn read(&mut self, bits: u32) -> Option<u64> {
+        let mut value = 0;
+        for _ in 0..bits {
+            let byte = self.data.get(self.position / 8)?;
+            let bit = (byte >> (7 - self.position % 8)) & 1;
+            value = value << 1 | bit as u64;
+            self.position += 1;
+        }
+        Some(value)
+    }
+
+    fn read_uvlc(&mut self) -> Option<u64> {
+        let mut leading_zeros = 0;
+        while self.read(1)? == 0 {
+            leading_zeros += 1;
+            if leading_zeros >= 32 {
+                return None;
+            }
+        }
+        Some(self.read(leading_zeros)? + (1 << leading_zeros) - 1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// x265's parameter sets for 1080p Main, level 3.1
+    const X265_VPS: &[u8] = &[
+        0x40, 0x01, 0x0c, 0x01, 0xff, 0xff, 0x01, 0x60, 0x00, 0x00, 0x03, 0x00, 0x90, 0x00, 0x00,
+        0x03, 0x00, 0x00, 0x03, 0x00, 0x5d, 0x95, 0x98, 0x09,
+    ];
+    const X265_SPS: &[u8] = &[
+        0x42, 0x01, 0x01, 0x01, 0x60, 0x00, 0x00, 0x03, 0x00, 0x90, 0x00, 0x00, 0x03, 0x00, 0x00,
+        0x03, 0x00, 0x5d, 0xa0, 0x03, 0xc0, 0x80, 0x10, 0xe5, 0x96, 0x56, 0x69, 0x24, 0xca, 0xe0,
+        0x10, 0x00, 0x00, 0x03, 0x00, 0x10, 0x00, 0x00, 0x03, 0x01, 0xe0, 0x80,
+    ];
+    const X265_PPS: &[u8] = &[0x44, 0x01, 0xc1, 0x72, 0xb4, 0x62, 0x40];
+
+    /// A 1080p Main profile sequence header the way SVT-AV1 writes it: no
+    /// timing info, one operating point at level 5.1 (`seq_level_idx` 13),
+    /// main tier
+    const SVT_AV1_SEQUENCE_HEADER: &[u8] = &[
+        0x0a, 0x0b, 0x00, 0x00, 0x00, 0x6a, 0xab, 0xbf, 0xc3, 0x73, 0xff, 0xe6, 0x01,
+    ];
+
+    fn annex_b(nal_units: &[&[u8]]) -> Vec<u8> {
+        nal_units
+            .iter()
+            .flat_map(|nal| [&[0, 0, 0, 1][..], nal].concat())
+            .collect()
+    }
+
+    #[test]
+    fn parses_legacy_avc_tags() {
+        // x264's AVCDecoderConfigurationRecord for 720p High
+        let data = Bytes::from_static(&[
+            0x17, 0x00, 0x00, 0x00, 0x00, 0x01, 0x64, 0x00, 0x1f, 0xff, 0xe1, 0x00, 0x19, 0x67,
+            0x64, 0x00, 0x1f, 0xac, 0xd9, 0x40, 0x50, 0x05, 0xbb, 0x01, 0x10, 0x00, 0x00, 0x03,
+            0x00, 0x10, 0x00, 0x00, 0x03, 0x03, 0xc0, 0xf1, 0x83, 0x19, 0x60, 0x01, 0x00, 0x06,
+            0x68, 0xeb, 0xe3, 0xcb, 0x22, 0xc0,
+        ]);
+        let tag = VideoTag::parse(&data).unwrap();
+        assert_eq!(tag.codec, VideoCodec::Avc);
+        assert!(!tag.enhanced);
+        assert!(tag.keyframe);
+        assert_eq!(tag.packet_type, VideoPacketType::SequenceStart);
+        assert_eq!(tag.composition_time, 0);
+        assert_eq!(tag.payload, data.slice(5..));
+
+        // a B-frame shown 40ms before it is decoded
+        let data = Bytes::from_static(&[0x27, 0x01, 0xff, 0xff, 0xd8, 0x00, 0x00, 0x00, 0x01]);
+        let tag = VideoTag::parse(&data).unwrap();
+        assert!(!tag.keyframe);
+        assert_eq!(tag.packet_type, VideoPacketType::CodedFrames);
+        assert_eq!(tag.composition_time, -40);
+        assert_eq!(&tag.payload[..], [0, 0, 0, 1]);
+
+        let end = VideoTag::parse(&Bytes::from_static(&[0x17, 0x02, 0, 0, 0])).unwrap();
+        assert_eq!(end.packet_type, VideoPacketType::SequenceEnd);
+    }
+
+    #[test]
+    fn parses_enhanced_tags() {
+        // an HEVC keyframe with an 80ms composition time
+        let data = Bytes::from_static(&[
+            0x91, b'h', b'v', b'c', b'1', 0x00, 0x00, 0x50, 0x00, 0x00, 0x00, 0x03, 0x26, 0x01,
+            0xaf,
+        ]);
+        let tag = VideoTag::parse(&data).unwrap();
+        assert_eq!(tag.codec, VideoCodec::Hevc);
+        assert!(tag.enhanced);
+        assert!(tag.keyframe);
+        assert_eq!(tag.packet_type, VideoPacketType::CodedFrames);
+        assert_eq!(tag.composition_time, 80);
+        assert_eq!(tag.payload, data.slice(8..));
+        assert_eq!(tag.to_enhanced_bytes(), data);
+
+        // CodedFramesX: the same, but with the composition time left out
+        let data = Bytes::from_static(&[0xa3, b'h', b'v', b'c', b'1', 0x00, 0x00, 0x00, 0x03]);
+        let tag = VideoTag::parse(&data).unwrap();
+        assert!(!tag.keyframe);
+        assert_eq!(tag.packet_type, VideoPacketType::CodedFrames);
+        assert_eq!(tag.composition_time, 0);
+        assert_eq!(tag.payload, data.slice(5..));
+        assert_eq!(
+            &tag.to_enhanced_bytes()[..],
+            [0xa1, b'h', b'v', b'c', b'1', 0, 0, 0, 0x00, 0x00, 0x00, 0x03]
+        );
+
+        // AV1 never has a composition time
+        let data = Bytes::from_static(&[0x91, b'a', b'v', b'0', b'1', 0x12, 0x00]);
+        let tag = VideoTag::parse(&data).unwrap();
+        assert_eq!(tag.codec, VideoCodec::Av1);
+        assert_eq!(tag.composition_time, 0);
+        assert_eq!(&tag.payload[..], [0x12, 0x00]);
+        assert_eq!(tag.to_enhanced_bytes(), data);
+
+        let start = VideoTag::parse(&Bytes::from_static(&[0x90, b'v', b'p', b'0', b'9'])).unwrap();
+        assert_eq!(start.codec, VideoCodec::Vp9);
+        assert_eq!(start.packet_type, VideoPacketType::SequenceStart);
+    }
+
+    #[test]
+    fn ignores_tags_it_cannot_read() {
+        for data in [
+            &[][..],
+            // Sorenson H.263 and VP6
+            &[0x12, 0x00, 0x00, 0x00, 0x00],
+            &[0x14, 0x00, 0x00, 0x00, 0x00],
+            // too short for an AVC header
+            &[0x17, 0x01, 0x00, 0x00],
+            // a FourCC we don't know, then a truncated one
+            &[0x91, b'a', b'b', b'c', b'd', 0x00, 0x00, 0x00],
+            &[0x91, b'h', b'v', b'c'],
+            // coded HEVC frames without room for the composition time
+            &[0x91, b'h', b'v', b'c', b'1', 0x00, 0x00],
+        ] {
+            assert!(
+                VideoTag::parse(&Bytes::copy_from_slice(data)).is_none(),
+                "{:02x?}",
+                data
+            );
+        }
+    }
+
+    #[test]
+    fn sign_extends_24_bit_integers() {
+        for (bytes, value) in [
+            ([0x00, 0x00, 0x00], 0),
+            ([0x00, 0x00, 0x50], 80),
+            ([0x7f, 0xff, 0xff], 8_388_607),
+            ([0xff, 0xff, 0xff], -1),
+            ([0xff, 0xff, 0xd8], -40),
+            ([0x80, 0x00, 0x00], -8_388_608),
+        ] {
+            assert_eq!(read_i24(&bytes), value, "{:02x?}", bytes);
+        }
+    }
+
+    #[test]
+    fn splits_annex_b() {
+        let data = [
+            0xaa, // before the first start code, so not a NAL unit
+            0x00, 0x00, 0x00, 0x01, 0x01, 0x02, // 4 byte start code
+            0x00, 0x00, 0x01, 0x03, // 3 byte start code
+            0x00, 0x00, 0x01, // nothing in between
+            0x00, 0x00, 0x00, 0x01, 0x04, 0x00, 0x05,
+        ];
+        let nal_units: Vec<&[u8]> = AnnexBNalUnits { data: &data }.collect();
+        assert_eq!(
+            nal_units,
+            [&[0x01, 0x02][..], &[0x03], &[], &[0x04, 0x00, 0x05]]
+        );
+        let nal_units: Vec<&[u8]> = split_annex_b(&data).collect();
+        assert_eq!(nal_units, [&[0x01, 0x02][..], &[0x03], &[0x04, 0x00, 0x05]]);
+
+        assert_eq!(
+            &annex_b_to_length_prefixed(&data)[..],
+            [0, 0, 0, 2, 0x01, 0x02, 0, 0, 0, 1, 0x03, 0, 0, 0, 3, 0x04, 0x00, 0x05]
+        );
+        assert_eq!(
+            AnnexBNalUnits {
+                data: &[0xaa, 0x00, 0x00]
+            }
+            .next(),
+            None
+        );
+    }
+
+    #[test]
+    fn builds_hvcc_from_x265_parameter_sets() {
+        // x265 puts an SEI with its settings in there too, which doesn't belong in hvcC
+        let sei: &[u8] = &[0x4e, 0x01, 0x05, 0x01, 0x00, 0x80];
+        let header = annex_b(&[X265_VPS, X265_SPS, X265_PPS, sei]);
+
+        let mut expected = vec![
+            0x01, // configurationVersion
+            // general_profile_space, tier and profile_idc (Main), compatibility
+            // flags, constraint flags without emulation prevention, level_idc
+            0x01, 0x60, 0x00, 0x00, 0x00, 0x90, 0x00, 0x00, 0x00, 0x00, 0x00, 0x5d, //
+            0xf0, 0x00, // min_spatial_segmentation_idc
+            0xfc, // parallelismType
+            0xfd, // chroma_format_idc
+            0xf8, 0xf8, // bit depths
+            0x00, 0x00, // avgFrameRate
+            0x0f, // 1 temporal layer, nested, 4 byte lengths
+            0x03, // numOfArrays
+        ];
+        for (array, nal) in [(0xa0, X265_VPS), (0xa1, X265_SPS), (0xa2, X265_PPS)] {
+            expected.extend([array, 0x00, 0x01, 0x00, nal.len() as u8]);
+            expected.extend(nal);
+        }
+        assert_eq!(
+            &hevc_decoder_configuration(&header).unwrap()[..],
+            &expected[..]
+        );
+
+        assert_eq!(
+            hevc_decoder_configuration(&annex_b(&[X265_VPS, X265_PPS])),
+            None
+        );
+    }
+
+    #[test]
+    fn builds_av1c_from_an_svt_av1_sequence_header() {
+        // with a temporal delimiter in front, as encoders like to
+        let obus = [&[0x12, 0x00][..], SVT_AV1_SEQUENCE_HEADER].concat();
+        let mut expected = vec![
+            0x81, // marker, version 1
+            0x0d, // main profile, level 5.1
+            0x0c, // main tier, 8 bit 4:2:0
+            0x00, // no initial_presentation_delay
+        ];
+        expected.extend(SVT_AV1_SEQUENCE_HEADER);
+        let record = av1_decoder_configuration(&obus).unwrap();
+        assert_eq!(&record[..], &expected[..]);
+
+        // a record is passed through as is
+        assert_eq!(av1_decoder_configuration(&record).unwrap(), record);
+        // no sequence header, no record
+        assert_eq!(av1_decoder_configuration(&[0x12, 0x00]), None);
+    }
+
+    #[test]
+    fn reads_levels_and_tiers_after_timing_info() {
+        // 1080p at 60000/1001 with a decoder model, level 4.0, high tier
+        let payload = [
+            0x04, 0x00, 0x00, 0x0f, 0xa4, 0x00, 0x03, 0xa9, 0x81, 0xf8, 0x00, 0x00, 0x1f, 0x4f,
+            0xfe, 0x00, 0x00, 0x22, 0xaa, 0xef, 0xf0, 0xdc, 0xff, 0xf9, 0x80, 0x40,
+        ];
+        assert_eq!(parse_av1_sequence_header(&payload), Some((0, 8, 1)));
+
+        // a still image with a reduced header at level 3.0
+        let payload = [0x19, 0x2a, 0xbb, 0xfc, 0x37, 0x6c, 0x02];
+        assert_eq!(parse_av1_sequence_header(&payload), Some((0, 4, 0)));
+
+        assert_eq!(parse_av1_sequence_header(&payload[..0]), None);
+        let sequence_header = &SVT_AV1_SEQUENCE_HEADER[2..];
+        assert_eq!(parse_av1_sequence_header(&sequence_header[..3]), None);
+    }
+
+    #[test]
+    fn strips_temporal_delimiters() {
+        let frame: &[u8] = &[0x32, 0x02, 0xaa, 0xbb];
+        let obus = [&[0x12, 0x00][..], frame, &[0x12, 0x00]].concat();
+        assert_eq!(&strip_av1_temporal_delimiters(&obus)[..], frame);
+    }
+
+    #[test]
+    fn reads_leb128() {
+        for (data, value) in [
+            (&[0x00][..], Some((0, 1))),
+            (&[0x7f], Some((127, 1))),
+            (&[0x80, 0x01], Some((128, 2))),
+            (&[0xe5, 0x8e, 0x26], Some((624_485, 3))),
+            // trailing bytes are left alone
+            (&[0x0b, 0xff], Some((11, 1))),
+            (&[], None),
+            (&[0x80], None),
+            // longer than the 8 bytes AV1 allows
+            (&[0xff; 9], None),
+        ] {
+            assert_eq!(read_leb128(data), value, "{:02x?}", data);
+        }
+    }
+}
@@ -0,0 +1,225 @@
+//! End-to-end tests. A synthetic publisher pushes a generated clip through a
+//! proxy running in-process, which routes the blurred stream on to a local
+//! sink that keeps everything it gets.
+//!
+//! The clip is made, and what comes out is decoded, by the `ffmpeg` command
+//! line tool, which has to be on the `PATH`. Faces come from a scripted
+//! detector that always finds the same one (see [`media::FACE`]), so the
+//! models are never loaded.
+
+use std::{net::SocketAddr, sync::Arc, time::Duration};
+
+use rml_rtmp::handshake::{Handshake, HandshakeProcessResult, PeerType};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    task::JoinHandle,
+    time::timeout,
+};
+
+use anonynews_rs::{
+    config::Config,
+    image_processing::{Detection, FaceDetector},
+    server,
+    shutdown::Shutdown,
+    stream_registry::{MediaKind, MediaPacket},
+};
+
+use media::{BYSTANDER, FACE, FPS, SECONDS};
+use sink::{Recording, Sink};
+
+mod media;
+mod publisher;
+mod sink;
+
+/// How long the proxy gets to pass everything on once the publisher is done
+const FINISH_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Drive either side of the RTMP handshake. Returns the bytes the peer sent
+/// after it, which belong to the session.
+async fn handshake(socket: &mut TcpStream, peer_type: PeerType) -> anyhow::Result<Vec<u8>> {
+    let is_client = matches!(peer_type, PeerType::Client);
+    let mut handshake = Handshake::new(peer_type);
+    if is_client {
+        socket
+            .write_all(&handshake.generate_outbound_p0_and_p1()?)
+            .await?;
+    }
+
+    let mut buf = vec![0; 4096];
+    loop {
+        let read = socket.read(&mut buf).await?;
+        if read == 0 {
+            anyhow::bail!("connection closed during the handshake");
+        }
+        match handshake.process_bytes(&buf[..read])? {
+            HandshakeProcessResult::InProgress { response_bytes } => {
+                socket.write_all(&response_bytes).await?;
+            }
+            HandshakeProcessResult::Completed {
+                response_bytes,
+                remaining_bytes,
+            } => {
+                socket.write_all(&response_bytes).await?;
+                return Ok(remaining_bytes);
+            }
+        }
+    }
+}
+
+/// The whole server, listening on a port of its own
+struct TestProxy {
+    address: SocketAddr,
+    stop: Shutdown,
+    task: JoinHandle<anyhow::Result<()>>,
+}
+
+impl TestProxy {
+    async fn start(config: Config, detector: FaceDetector) -> anyhow::Result<Self> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let address = listener.local_addr()?;
+        let stop = Shutdown::new();
+        let stopped = stop.clone();
+        let task = tokio::spawn(server::run(config, listener, detector, async move {
+            stopped.cancelled().await;
+            Ok(())
+        }));
+        Ok(Self {
+            address,
+            stop,
+            task,
+        })
+    }
+
+    async fn stop(self) -> anyhow::Result<()> {
+        self.stop.cancel();
+        self.task.await?
+    }
+}
+
+/// Always finds a face at [`FACE`], and nowhere else
+fn planted_face_detector() -> FaceDetector {
+    let (x, y, width, height) = FACE;
+    FaceDetector::Scripted(Arc::new(move |_| {
+        vec![Detection {
+            x: x as i32,
+            y: y as i32,
+            width: width as i32,
+            height: height as i32,
+            confidence: 1.0,
+            blurred: true,
+            identity: String::new(),
+            distance: -1.0,
+        }]
+    }))
+}
+
+/// What went into the proxy, and what came out the other end
+struct Session {
+    sent: Vec<MediaPacket>,
+    received: Recording,
+}
+
+/// Publish the test clip on `live/test`, which is routed to a sink as
+/// `sink/blurred-test`
+async fn run_session() -> anyhow::Result<Session> {
+    let sent = media::test_clip()?;
+    let sink = Sink::start().await?;
+    let config: Config = toml::from_str(&format!(
+        r#"
+        [[routes]]
+        app = "live"
+        destinations = [{{ url = "rtmp://{}/sink", stream_key = "blurred-{{stream_key}}" }}]
+
+        # the sink has to get the first keyframe, even if it is only
+        # connected to after the encoder has put it out
+        [egress]
+        catch_up_ms = 10000
+        "#,
+        sink.address
+    ))?;
+    let proxy = TestProxy::start(config, planted_face_detector()).await?;
+
+    publisher::publish(proxy.address, "live", "test", &sent).await?;
+    let received = timeout(FINISH_TIMEOUT, sink.finished()).await??;
+    proxy.stop().await?;
+    Ok(Session { sent, received })
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn publishes_on_to_the_routed_destination() {
+    let session = run_session().await.unwrap();
+
+    assert_eq!(session.received.app_name, "sink");
+    assert_eq!(session.received.stream_key, "blurred-test");
+    assert!(!session.received.video().is_empty());
+    assert!(!session.received.audio().is_empty());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn keeps_timing_and_keyframe_structure() {
+    let session = run_session().await.unwrap();
+    let video = session.received.video();
+
+    // nothing can be decoded without the sequence header, or before a keyframe
+    assert!(video[0].is_sequence_header());
+    let frames: Vec<_> = video
+        .iter()
+        .filter(|packet| !packet.is_sequence_header())
+        .collect();
+    assert!(frames[0].is_keyframe());
+    for pair in video.windows(2) {
+        assert!(pair[0].timestamp <= pair[1].timestamp);
+    }
+
+    // every frame is shown exactly when it was meant to be
+    let mut sent = media::presentation_times(&session.sent);
+    let mut received = media::presentation_times(&video);
+    assert_eq!(sent.len(), FPS * SECONDS);
+    sent.sort_unstable();
+    received.sort_unstable();
+    assert_eq!(received, sent);
+
+    // audio doesn't go near the pipeline, so it comes out untouched, apart
+    // from what arrived before the first blurred keyframe
+    let audio = session.received.audio();
+    assert!(audio[0].is_sequence_header());
+    let sent_audio: Vec<_> = session
+        .sent
+        .iter()
+        .filter(|packet| packet.kind == MediaKind::Audio && !packet.is_sequence_header())
+        .map(|packet| (packet.timestamp, packet.data.clone()))
+        .collect();
+    let received_audio: Vec<_> = audio[1..]
+        .iter()
+        .map(|packet| (packet.timestamp, packet.data.clone()))
+        .collect();
+    assert!(sent_audio.ends_with(&received_audio));
+    assert!(received_audio[0].0 < 1000);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn blurs_the_planted_face_and_nothing_else() {
+    let session = run_session().await.unwrap();
+    let sent = media::decode_video(media::to_flv(&session.sent).unwrap()).unwrap();
+    let received = media::decode_video(media::to_flv(&session.received.packets).unwrap()).unwrap();
+    assert_eq!(received.len(), sent.len());
+
+    let face_before = media::mean_detail(&sent, FACE);
+    let face_after = media::mean_detail(&received, FACE);
+    assert!(
+        face_after < face_before / 4.0,
+        "the face went from {:.1} to {:.1}",
+        face_before,
+        face_after
+    );
+
+    let bystander_before = media::mean_detail(&sent, BYSTANDER);
+    let bystander_after = media::mean_detail(&received, BYSTANDER);
+    assert!(
+        bystander_after > bystander_before / 2.0,
+        "the bystander went from {:.1} to {:.1}",
+        bystander_before,
+        bystander_after
+    );
+}
@@ -0,0 +1,186 @@
+//! The test clip, and how to look inside what comes out of the proxy. Both
+//! are done by the `ffmpeg` command line tool.
+
+use std::{
+    io::Write,
+    process::{Command, Stdio},
+    thread,
+};
+
+use anyhow::{bail, Context};
+use bytes::Bytes;
+
+use anonynews_rs::{
+    flv_file::{FLVTagReader, FLVTagType, FLVWriterWrapper},
+    stream_registry::{MediaKind, MediaPacket},
+};
+
+pub const WIDTH: usize = 320;
+pub const HEIGHT: usize = 240;
+pub const FPS: usize = 25;
+pub const SECONDS: usize = 4;
+
+/// x, y, width and height in pixels
+pub type Rect = (usize, usize, usize, usize);
+
+/// Where a face is planted in every frame. It is only a checkerboard, but the
+/// scripted detector says it's a face, and it has plenty of sharp edges to
+/// lose when blurred.
+pub const FACE: Rect = (32, 72, 96, 96);
+
+/// The same checkerboard, which nothing says is a face, so it should come out
+/// as sharp as it went in
+pub const BYSTANDER: Rect = (192, 72, 96, 96);
+
+/// Runs `ffmpeg` with `args`, feeding it `input` if there is any, and returns
+/// what it wrote to stdout
+fn ffmpeg(args: &[&str], input: Option<Vec<u8>>) -> anyhow::Result<Vec<u8>> {
+    let mut child = Command::new("ffmpeg")
+        .args(["-hide_banner", "-loglevel", "error"])
+        .args(args)
+        .stdin(if input.is_some() {
+            Stdio::piped()
+        } else {
+            Stdio::null()
+        })
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .context("could not run ffmpeg, is it on the PATH?")?;
+
+    // write from another thread, so neither of us blocks on a full pipe
+    let writer = input.map(|input| {
+        let mut stdin = child.stdin.take().unwrap();
+        thread::spawn(move || stdin.write_all(&input))
+    });
+    let output = child.wait_with_output()?;
+    if let Some(writer) = writer {
+        writer.join().unwrap()?;
+    }
+    if !output.status.success() {
+        bail!(
+            "ffmpeg failed: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    Ok(output.stdout)
+}
+
+/// `testsrc` H.264 video, with a keyframe every second and no B-frames, and a
+/// sine wave in AAC, as the messages a publisher would send. [`FACE`] and
+/// [`BYSTANDER`] are planted on top of the video.
+pub fn test_clip() -> anyhow::Result<Vec<MediaPacket>> {
+    let video = format!(
+        "testsrc=size={}x{}:rate={}:duration={}",
+        WIDTH, HEIGHT, FPS, SECONDS
+    );
+    let audio = format!("sine=frequency=440:sample_rate=44100:duration={}", SECONDS);
+    let checkerboard = format!(
+        "color=white:size={}x{}:rate={}:duration={},drawgrid=w=8:h=8:t=4:c=black",
+        FACE.2, FACE.3, FPS, SECONDS
+    );
+    let planted = format!(
+        "[2:v]split[face][bystander];[0:v][face]overlay={}:{}[v1];[v1][bystander]overlay={}:{}[v]",
+        FACE.0, FACE.1, BYSTANDER.0, BYSTANDER.1
+    );
+    let gop = FPS.to_string();
+    #[rustfmt::skip]
+    let args = [
+        "-f", "lavfi", "-i", &video,
+        "-f", "lavfi", "-i", &audio,
+        "-f", "lavfi", "-i", &checkerboard,
+        "-filter_complex", &planted,
+        "-map", "[v]", "-map", "1:a",
+        "-c:v", "libx264", "-pix_fmt", "yuv420p", "-g", &gop, "-bf", "0",
+        "-c:a", "aac",
+        "-f", "flv", "-",
+    ];
+    let flv = ffmpeg(&args, None)?;
+
+    let mut reader = FLVTagReader::new();
+    reader.push_bytes(&flv);
+    let mut packets = Vec::new();
+    while let Some(tag) = reader.next_tag()? {
+        let kind = match tag.tag_type {
+            FLVTagType::Audio => MediaKind::Audio,
+            FLVTagType::Video => MediaKind::Video,
+            // just onMetaData, which the proxy can do without
+            FLVTagType::ScriptData => continue,
+        };
+        packets.push(MediaPacket {
+            kind,
+            timestamp: tag.timestamp,
+            data: tag.data,
+        });
+    }
+    Ok(packets)
+}
+
+/// The packets as an FLV file, to hand to [`decode_video`]
+pub fn to_flv(packets: &[MediaPacket]) -> anyhow::Result<Vec<u8>> {
+    let mut writer = FLVWriterWrapper::new(Vec::new());
+    writer.write_header()?;
+    for packet in packets {
+        match packet.kind {
+            MediaKind::Video => writer.write_video_bytes(packet.timestamp, &packet.data)?,
+            MediaKind::Audio => writer.write_audio_bytes(packet.timestamp, &packet.data)?,
+            MediaKind::Data => writer.write_script_bytes(packet.timestamp, &packet.data)?,
+        }
+    }
+    Ok(writer.into_inner())
+}
+
+/// Every frame of an FLV file's video, as tightly packed RGB24
+pub fn decode_video(flv: Vec<u8>) -> anyhow::Result<Vec<Bytes>> {
+    let frame_len = WIDTH * HEIGHT * 3;
+    let args = [
+        "-f", "flv", "-i", "-", "-map", "0:v", "-f", "rawvideo", "-pix_fmt", "rgb24", "-",
+    ];
+    let raw = Bytes::from(ffmpeg(&args, Some(flv))?);
+    if raw.len() % frame_len != 0 {
+        bail!(
+            "decoded {} bytes, which isn't a whole number of {}x{} frames",
+            raw.len(),
+            WIDTH,
+            HEIGHT
+        );
+    }
+    Ok((0..raw.len())
+        .step_by(frame_len)
+        .map(|start| raw.slice(start..start + frame_len))
+        .collect())
+}
+
+/// How much neighbouring pixels in `rect` differ on average. Blurring brings
+/// this right down.
+pub fn detail(frame: &[u8], rect: Rect) -> f64 {
+    let (x, y, width, height) = rect;
+    let mut total = 0;
+    for row in y..y + height {
+        let pixels = &frame[(row * WIDTH + x) * 3..(row * WIDTH + x + width) * 3];
+        for (left, right) in pixels.iter().zip(&pixels[3..]) {
+            total += (*left as i32 - *right as i32).abs();
+        }
+    }
+    total as f64 / ((width - 1) * height * 3) as f64
+}
+
+/// [`detail`] averaged over every frame
+pub fn mean_detail(frames: &[Bytes], rect: Rect) -> f64 {
+    frames.iter().map(|frame| detail(frame, rect)).sum::<f64>() / frames.len() as f64
+}
+
+/// When each video frame is shown, i.e. its timestamp plus its composition
+/// time offset, in milliseconds
+pub fn presentation_times(packets: &[MediaPacket]) -> Vec<i64> {
+    packets
+        .iter()
+        .filter(|packet| packet.kind == MediaKind::Video && !packet.is_sequence_header())
+        .map(|packet| {
+            // AVC video tags: frame type and codec, packet type, then a signed 24 bit offset
+            let offset =
+                i32::from_be_bytes([packet.data[2], packet.data[3], packet.data[4], 0]) >> 8;
+            packet.timestamp as i64 + offset as i64
+        })
+        .collect()
+}
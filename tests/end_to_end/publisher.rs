@@ -0,0 +1,135 @@
+//! Publishes a clip to the proxy the way an encoder like OBS would: in real
+//! time, with every message timestamped from the start of the stream.
+
+use std::{net::SocketAddr, time::Duration};
+
+use anyhow::bail;
+use rml_rtmp::{
+    handshake::PeerType,
+    sessions::{
+        ClientSession, ClientSessionConfig, ClientSessionEvent, ClientSessionResult,
+        PublishRequestType,
+    },
+    time::RtmpTimestamp,
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+    time::{sleep_until, Instant},
+};
+
+use anonynews_rs::stream_registry::{MediaKind, MediaPacket};
+
+/// Connects to the proxy at `address`, publishes `packets` on
+/// `app_name/stream_key`, then stops publishing. Fails if the proxy turns
+/// the connection or the stream down.
+pub async fn publish(
+    address: SocketAddr,
+    app_name: &str,
+    stream_key: &str,
+    packets: &[MediaPacket],
+) -> anyhow::Result<()> {
+    let mut socket = TcpStream::connect(address).await?;
+    socket.set_nodelay(true)?;
+    let remaining_bytes = super::handshake(&mut socket, PeerType::Client).await?;
+
+    let (session, results) = ClientSession::new(ClientSessionConfig::new())?;
+    let mut publisher = Publisher { socket, session };
+    publisher.send(results).await?;
+    let results = publisher.session.handle_input(&remaining_bytes)?;
+    publisher.send(results).await?;
+
+    let result = publisher.session.request_connection(app_name.to_owned())?;
+    publisher.send(vec![result]).await?;
+    publisher
+        .wait_for(|event| matches!(event, ClientSessionEvent::ConnectionRequestAccepted))
+        .await?;
+
+    let result = publisher
+        .session
+        .request_publishing(stream_key.to_owned(), PublishRequestType::Live)?;
+    publisher.send(vec![result]).await?;
+    publisher
+        .wait_for(|event| matches!(event, ClientSessionEvent::PublishRequestAccepted))
+        .await?;
+
+    let started = Instant::now();
+    for packet in packets {
+        sleep_until(started + Duration::from_millis(packet.timestamp as u64)).await;
+        let timestamp = RtmpTimestamp::new(packet.timestamp);
+        let result = match packet.kind {
+            MediaKind::Video => {
+                publisher
+                    .session
+                    .publish_video_data(packet.data.clone(), timestamp, false)?
+            }
+            MediaKind::Audio => {
+                publisher
+                    .session
+                    .publish_audio_data(packet.data.clone(), timestamp, false)?
+            }
+            MediaKind::Data => continue,
+        };
+        publisher.send(vec![result]).await?;
+    }
+
+    let results = publisher.session.stop_publishing()?;
+    publisher.send(results).await?;
+    publisher.socket.shutdown().await?;
+    Ok(())
+}
+
+struct Publisher {
+    socket: TcpStream,
+    session: ClientSession,
+}
+
+impl Publisher {
+    /// Send whatever the session wants sent, returning the events it raised
+    async fn send(
+        &mut self,
+        results: Vec<ClientSessionResult>,
+    ) -> anyhow::Result<Vec<ClientSessionEvent>> {
+        let mut events = Vec::new();
+        for result in results {
+            match result {
+                ClientSessionResult::OutboundResponse(packet) => {
+                    self.socket.write_all(&packet.bytes).await?
+                }
+                ClientSessionResult::RaisedEvent(event) => events.push(event),
+                ClientSessionResult::UnhandleableMessageReceived(_) => {}
+            }
+        }
+        Ok(events)
+    }
+
+    /// Read until the proxy raises an event that satisfies `accepted`, or
+    /// turns us down
+    async fn wait_for(
+        &mut self,
+        accepted: impl Fn(&ClientSessionEvent) -> bool,
+    ) -> anyhow::Result<()> {
+        let mut buf = vec![0; 4096];
+        loop {
+            let read = self.socket.read(&mut buf).await?;
+            if read == 0 {
+                bail!("the proxy closed the connection");
+            }
+            let results = self.session.handle_input(&buf[..read])?;
+            for event in self.send(results).await? {
+                if accepted(&event) {
+                    return Ok(());
+                }
+                match event {
+                    ClientSessionEvent::ConnectionRequestRejected { description } => {
+                        bail!("the proxy rejected the connection: {}", description)
+                    }
+                    ClientSessionEvent::UnhandleableOnStatusCode { code } => {
+                        bail!("the proxy rejected the stream: {}", code)
+                    }
+                    _ => {}
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,150 @@
+//! Stands in for the RTMP server the proxy pushes to, e.g. a platform's
+//! ingest, and keeps everything that is published to it.
+
+use std::net::SocketAddr;
+
+use anyhow::bail;
+use rml_rtmp::{
+    handshake::PeerType,
+    sessions::{ServerSession, ServerSessionConfig, ServerSessionEvent, ServerSessionResult},
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    task::JoinHandle,
+};
+
+use anonynews_rs::stream_registry::{MediaKind, MediaPacket};
+
+/// What one publisher sent the sink
+#[derive(Debug, Default)]
+pub struct Recording {
+    pub app_name: String,
+    pub stream_key: String,
+    /// In the order they arrived
+    pub packets: Vec<MediaPacket>,
+}
+
+impl Recording {
+    pub fn video(&self) -> Vec<MediaPacket> {
+        self.of_kind(MediaKind::Video)
+    }
+
+    pub fn audio(&self) -> Vec<MediaPacket> {
+        self.of_kind(MediaKind::Audio)
+    }
+
+    fn of_kind(&self, kind: MediaKind) -> Vec<MediaPacket> {
+        self.packets
+            .iter()
+            .filter(|packet| packet.kind == kind)
+            .cloned()
+            .collect()
+    }
+}
+
+/// Takes a single connection, and accepts whatever it publishes
+#[derive(Debug)]
+pub struct Sink {
+    pub address: SocketAddr,
+    task: JoinHandle<anyhow::Result<Recording>>,
+}
+
+impl Sink {
+    pub async fn start() -> anyhow::Result<Self> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let address = listener.local_addr()?;
+        let task = tokio::spawn(async move {
+            let (socket, _) = listener.accept().await?;
+            record(socket).await
+        });
+        Ok(Self { address, task })
+    }
+
+    /// Waits for the publisher to finish, or hang up
+    pub async fn finished(self) -> anyhow::Result<Recording> {
+        self.task.await?
+    }
+}
+
+async fn record(mut socket: TcpStream) -> anyhow::Result<Recording> {
+    let remaining_bytes = super::handshake(&mut socket, PeerType::Server).await?;
+    let (mut session, mut results) = ServerSession::new(ServerSessionConfig::new())?;
+    let mut recording = Recording::default();
+    results.extend(session.handle_input(&remaining_bytes)?);
+
+    let mut buf = vec![0; 4096];
+    loop {
+        // answering can raise more events, e.g. accepting a publish request
+        while !results.is_empty() {
+            for result in std::mem::take(&mut results) {
+                match result {
+                    ServerSessionResult::OutboundResponse(packet) => {
+                        socket.write_all(&packet.bytes).await?
+                    }
+                    ServerSessionResult::RaisedEvent(event) => {
+                        match handle_event(&mut session, &mut recording, event)? {
+                            Some(more) => results.extend(more),
+                            None => return Ok(recording),
+                        }
+                    }
+                    ServerSessionResult::UnhandleableMessageReceived(_) => {}
+                }
+            }
+        }
+
+        let read = socket.read(&mut buf).await?;
+        if read == 0 {
+            return Ok(recording);
+        }
+        results = session.handle_input(&buf[..read])?;
+    }
+}
+
+/// Returns what to send back, or `None` once publishing is over
+fn handle_event(
+    session: &mut ServerSession,
+    recording: &mut Recording,
+    event: ServerSessionEvent,
+) -> anyhow::Result<Option<Vec<ServerSessionResult>>> {
+    Ok(Some(match event {
+        ServerSessionEvent::ConnectionRequested { request_id, .. } => {
+            session.accept_request(request_id)?
+        }
+        ServerSessionEvent::PublishStreamRequested {
+            request_id,
+            app_name,
+            stream_key,
+            ..
+        } => {
+            if !recording.app_name.is_empty() {
+                bail!("the proxy tried to publish twice on one connection");
+            }
+            recording.app_name = app_name;
+            recording.stream_key = stream_key;
+            session.accept_request(request_id)?
+        }
+        ServerSessionEvent::VideoDataReceived {
+            data, timestamp, ..
+        } => {
+            recording.packets.push(MediaPacket {
+                kind: MediaKind::Video,
+                timestamp: timestamp.value,
+                data,
+            });
+            Vec::new()
+        }
+        ServerSessionEvent::AudioDataReceived {
+            data, timestamp, ..
+        } => {
+            recording.packets.push(MediaPacket {
+                kind: MediaKind::Audio,
+                timestamp: timestamp.value,
+                data,
+            });
+            Vec::new()
+        }
+        ServerSessionEvent::PublishStreamFinished { .. } => return Ok(None),
+        _ => Vec::new(),
+    }))
+}
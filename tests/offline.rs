@@ -0,0 +1,115 @@
+//! Blurring a file on disk with [`offline::process_file`]. The input is made by
+//! the `ffmpeg` command line tool, which has to be on the `PATH`. Faces come
+//! from a scripted detector, so the models are never loaded.
+
+use std::{
+    path::Path,
+    process::Command,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+};
+
+use ffmpeg::{format, media, Rational, Rescale};
+use ffmpeg_next as ffmpeg;
+
+use anonynews_rs::{
+    image_processing::{Detection, FaceDetector},
+    offline,
+    policy::StreamPolicy,
+};
+
+const FRAMES: usize = 20;
+
+/// Two seconds of `testsrc` at 10 fps, with a sine wave in AAC
+fn make_clip(path: &Path) {
+    let status = Command::new("ffmpeg")
+        .args(["-hide_banner", "-loglevel", "error", "-y"])
+        .args([
+            "-f",
+            "lavfi",
+            "-i",
+            "testsrc=size=160x120:rate=10:duration=2",
+        ])
+        .args([
+            "-f",
+            "lavfi",
+            "-i",
+            "sine=frequency=440:sample_rate=44100:duration=2",
+        ])
+        .args(["-c:v", "mpeg4", "-c:a", "aac", "-f", "mp4"])
+        .arg(path)
+        .status()
+        .expect("could not run ffmpeg, is it on the PATH?");
+    assert!(status.success());
+}
+
+/// What is in a file, as far as this test cares
+struct Packets {
+    /// In milliseconds, in presentation order
+    video_timestamps: Vec<i64>,
+    audio: Vec<Vec<u8>>,
+}
+
+fn read_packets(path: &Path) -> Packets {
+    let mut input = format::input(&path).unwrap();
+    let video = input.streams().best(media::Type::Video).unwrap().index();
+    let audio = input.streams().best(media::Type::Audio).unwrap().index();
+
+    let mut packets = Packets {
+        video_timestamps: Vec::new(),
+        audio: Vec::new(),
+    };
+    for (stream, packet) in input.packets() {
+        if stream.index() == video {
+            let pts = packet.pts().expect("video packet without a timestamp");
+            let pts = pts.rescale(stream.time_base(), Rational(1, 1000));
+            packets.video_timestamps.push(pts);
+        } else if stream.index() == audio {
+            packets.audio.push(packet.data().unwrap_or_default().to_vec());
+        }
+    }
+    packets.video_timestamps.sort_unstable();
+    packets
+}
+
+#[test]
+fn blurs_the_video_and_copies_the_audio() {
+    let directory = tempfile::tempdir().unwrap();
+    let input_path = directory.path().join("in.mp4");
+    let output_path = directory.path().join("out.mp4");
+    make_clip(&input_path);
+
+    let frames_seen = Arc::new(AtomicUsize::new(0));
+    let counter = frames_seen.clone();
+    let detector = FaceDetector::Scripted(Arc::new(move |_| {
+        counter.fetch_add(1, Ordering::SeqCst);
+        vec![Detection {
+            x: 40,
+            y: 30,
+            width: 40,
+            height: 40,
+            confidence: 1.0,
+            blurred: true,
+            identity: String::new(),
+            distance: -1.0,
+        }]
+    }));
+    offline::process_file(
+        &input_path,
+        &output_path,
+        None,
+        &StreamPolicy::default(),
+        detector,
+    )
+    .unwrap();
+
+    let input = read_packets(&input_path);
+    let output = read_packets(&output_path);
+    assert_eq!(frames_seen.load(Ordering::SeqCst), FRAMES);
+    assert_eq!(input.video_timestamps.len(), FRAMES);
+    assert_eq!(output.video_timestamps, input.video_timestamps);
+    assert!(!input.audio.is_empty());
+    assert_eq!(output.audio, input.audio);
+}